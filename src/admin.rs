@@ -0,0 +1,2205 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppState, Channel, LineEvent, PresetKind, admin_permission,
+    admins::{Grant, Permission, Removal, Revocation},
+    audit, backups, find_preset, format_bytes, image_message,
+    line::{self, Quota, QuotaConsumption, ReplyTarget},
+    mask, media, preset_image, preset_url, presets, write_preview,
+};
+
+/// The first words of admin commands, which a preset's trigger message
+/// may not start with.
+const COMMAND_WORDS: &[&str] = &[
+    "announce",
+    "broadcast",
+    "quota",
+    "richmenu",
+    "undo",
+    "info",
+    "presets",
+    "一覧",
+    "audit",
+    "reload",
+    "preset",
+    "stats",
+    "export",
+    "import",
+    "admin",
+    "status",
+    "grant",
+    "revoke",
+];
+
+/// Commands an admin limited to some presets may still run, since they
+/// change nothing.
+const READ_ONLY_COMMANDS: &[&str] = &[
+    "quota", "info", "presets", "一覧", "audit", "stats", "status",
+];
+
+/// Runs `text` as an admin command if it is one. Returns false when the
+/// text isn't a recognized command so the caller can fall through to the
+/// normal preset lookup.
+pub async fn handle_command(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    sender: Option<&str>,
+    permission: &Permission,
+    text: &str,
+) -> anyhow::Result<bool> {
+    let (command, args) = match text.split_once(char::is_whitespace) {
+        Some((command, args)) => (command, args.trim()),
+        None => (text, ""),
+    };
+    if *permission != Permission::All
+        && COMMAND_WORDS.contains(&command)
+        && !READ_ONLY_COMMANDS.contains(&command)
+    {
+        channel
+            .line
+            .reply_text(target, FULL_ADMINS_ONLY_REPLY)
+            .await?;
+        return Ok(true);
+    }
+    match command {
+        "announce" => announce(state, channel, target, args).await?,
+        "broadcast" => confirm_broadcast(state, channel, target, args).await?,
+        "quota" => quota(channel, target).await?,
+        "richmenu" if args == "sync" => sync_rich_menu(state, channel, target).await?,
+        "undo" => undo(state, channel, target, args).await?,
+        "info" => info(state, channel, target, args).await?,
+        "presets" | "一覧" => list_presets(state, channel, target).await?,
+        "audit" => audit_log(state, channel, target, args).await?,
+        "reload" => reload_presets(state, channel, target).await?,
+        "preset" => edit_preset(state, channel, target, args).await?,
+        "stats" => preset_stats(state, channel, target).await?,
+        "export" => export_presets(state, channel, target).await?,
+        "import" => import_presets(state, channel, target, args).await?,
+        "admin" => manage_admins(state, channel, target, sender, args).await?,
+        "status" => status(state, channel, target).await?,
+        "grant" => grant(state, channel, target, sender, args).await?,
+        "revoke" => revoke(state, channel, target, sender, args).await?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// `announce <text>`: multicasts the text to ANNOUNCE_USER_IDS.
+async fn announce(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    text: &str,
+) -> anyhow::Result<()> {
+    if text.is_empty() {
+        return channel
+            .line
+            .reply_text(target, "使い方: announce <本文>")
+            .await;
+    }
+    if state.announce_user_ids.is_empty() {
+        return channel
+            .line
+            .reply_text(
+                target,
+                "送信先が設定されていません。ANNOUNCE_USER_IDS を設定してください。",
+            )
+            .await;
+    }
+
+    let chunks = channel
+        .line
+        .multicast(&state.announce_user_ids, line::text_messages(text), None)
+        .await;
+
+    let total = state.announce_user_ids.len();
+    let mut failed = 0;
+    let mut errors = Vec::new();
+    for chunk in &chunks {
+        if let Err(e) = &chunk.result {
+            failed += chunk.recipients;
+            errors.push(e.to_string());
+        }
+    }
+
+    let reply = if errors.is_empty() {
+        format!("{}人にお知らせを送信しました。", total)
+    } else {
+        format!(
+            "{}人中{}人への送信に失敗しました。\n{}",
+            total,
+            failed,
+            errors.join("\n")
+        )
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+/// `quota`: this month's message usage against the plan's limit.
+async fn quota(channel: &Channel, target: ReplyTarget<'_>) -> anyhow::Result<()> {
+    let (quota, consumption) = tokio::try_join!(
+        channel.line.get_quota(),
+        channel.line.get_quota_consumption()
+    )?;
+    channel
+        .line
+        .reply_text(target, &format_quota(&quota, &consumption))
+        .await
+}
+
+fn format_quota(quota: &Quota, consumption: &QuotaConsumption) -> String {
+    let used = consumption.total_usage;
+    match (quota.r#type.as_str(), quota.value) {
+        ("limited", Some(limit)) => format!(
+            "今月の送信数: {} / {} 通\n残り: {} 通",
+            used,
+            limit,
+            limit.saturating_sub(used)
+        ),
+        _ => format!("今月の送信数: {} 通\n上限: なし", used),
+    }
+}
+
+/// Size LINE expects for a full-height rich menu image.
+const RICH_MENU_WIDTH: u32 = 2500;
+const RICH_MENU_HEIGHT: u32 = 1686;
+const RICH_MENU_COLUMNS: usize = 3;
+/// LINE allows at most this many tap areas per rich menu.
+const RICH_MENU_MAX_AREAS: usize = 20;
+/// LINE refuses rich menu images larger than this.
+const RICH_MENU_MAX_BYTES: usize = 1024 * 1024;
+/// JPEG qualities tried in turn until the menu image fits.
+const RICH_MENU_QUALITIES: [u8; 4] = [85, 70, 55, 40];
+/// Id of the rich menu created by the last sync, so the next one can
+/// delete it.
+const RICH_MENU_ID_OBJECT: &str = "richmenu/current.txt";
+
+/// `richmenu sync`: replaces the default rich menu with one tap area per
+/// preset, each sending the preset's trigger text.
+async fn sync_rich_menu(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let mut names: Vec<&String> = presets.keys().collect();
+    names.sort();
+    names.truncate(RICH_MENU_MAX_AREAS);
+    if names.is_empty() {
+        return channel
+            .line
+            .reply_text(target, "プリセットがありません。")
+            .await;
+    }
+
+    let image = rich_menu_image(state, channel, &presets, &names).await?;
+    let rich_menu_id = channel.line.create_rich_menu(&rich_menu(&names)).await?;
+    channel
+        .line
+        .upload_rich_menu_image(&rich_menu_id, image, "image/jpeg")
+        .await?;
+    channel.line.set_default_rich_menu(&rich_menu_id).await?;
+    info!(rich_menu_id = %rich_menu_id, "default rich menu updated");
+
+    let id_object = channel.object_path(RICH_MENU_ID_OBJECT);
+    if let Ok(previous) = state.storage.download(&id_object).await {
+        let previous = String::from_utf8_lossy(&previous).trim().to_string();
+        if !previous.is_empty()
+            && previous != rich_menu_id
+            && let Err(e) = channel.line.delete_rich_menu(&previous).await
+        {
+            warn!(rich_menu_id = %previous, "failed to delete previous rich menu: {:#}", e);
+        }
+    }
+    state
+        .storage
+        .upload(&id_object, rich_menu_id.clone().into_bytes(), "text/plain")
+        .await?;
+
+    let reply = format!("リッチメニューを更新しました（{}件）。", names.len());
+    channel.line.reply_text(target, &reply).await
+}
+
+/// The menu artwork: each preset's current image filling its cell of the
+/// grid built by `rich_menu`, as a JPEG small enough for LINE. Presets
+/// without an image, or whose image can't be read, leave their cell blank.
+async fn rich_menu_image(
+    state: &AppState,
+    channel: &Channel,
+    presets: &presets::Presets,
+    names: &[&String],
+) -> anyhow::Result<Vec<u8>> {
+    let mut tiles = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let Some(object) = presets[*name].image_object() else {
+            continue;
+        };
+        let object = state.versions.resolve(&channel.object_path(object));
+        match state.storage.download(&object).await {
+            Ok(data) => tiles.push((rich_menu_cell(i, names.len()), data)),
+            Err(e) => warn!(object = %object, "preset image left out of the rich menu: {:#}", e),
+        }
+    }
+    tokio::task::spawn_blocking(move || {
+        let tiles: Vec<_> = tiles
+            .into_iter()
+            .filter_map(|(cell, data)| match media::decode(&data) {
+                Ok(decoded) => Some((cell, decoded.image)),
+                Err(e) => {
+                    warn!("preset image left out of the rich menu: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+        let menu = media::composite(RICH_MENU_WIDTH, RICH_MENU_HEIGHT, &tiles);
+        for quality in RICH_MENU_QUALITIES {
+            let jpeg = media::encode_jpeg(&menu, quality)?;
+            if jpeg.len() <= RICH_MENU_MAX_BYTES {
+                return Ok(jpeg);
+            }
+        }
+        anyhow::bail!("rich menu image is over {} bytes", RICH_MENU_MAX_BYTES)
+    })
+    .await?
+}
+
+/// Where the `i`th of `count` presets goes in the menu's grid, left to
+/// right and top to bottom.
+fn rich_menu_cell(i: usize, count: usize) -> media::Cell {
+    let columns = count.min(RICH_MENU_COLUMNS) as u32;
+    let rows = count.div_ceil(RICH_MENU_COLUMNS) as u32;
+    let (width, height) = (RICH_MENU_WIDTH / columns, RICH_MENU_HEIGHT / rows);
+    let (row, column) = (i as u32 / columns, i as u32 % columns);
+    (column * width, row * height, width, height)
+}
+
+/// A rich menu with the presets laid out in a grid, left to right and top
+/// to bottom.
+fn rich_menu(names: &[&String]) -> serde_json::Value {
+    let areas: Vec<serde_json::Value> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let (x, y, width, height) = rich_menu_cell(i, names.len());
+            serde_json::json!({
+                "bounds": {
+                    "x": x,
+                    "y": y,
+                    "width": width,
+                    "height": height,
+                },
+                "action": {
+                    "type": "message",
+                    "label": line::action_label(name),
+                    "text": name,
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "size": { "width": RICH_MENU_WIDTH, "height": RICH_MENU_HEIGHT },
+        "selected": true,
+        "name": "presets",
+        "chatBarText": "メニュー",
+        "areas": areas,
+    })
+}
+
+/// `broadcast <key>`: asks for confirmation before sending a preset's image
+/// to every follower, since a broadcast is billed per recipient.
+async fn confirm_broadcast(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    key: &str,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let Some((name, preset)) =
+        find_preset(&presets, key).filter(|(_, preset)| preset.image_object().is_some())
+    else {
+        return channel
+            .line
+            .reply_text(target, "使い方: broadcast <プリセット名>")
+            .await;
+    };
+
+    let text = broadcast_question(name);
+    let template = serde_json::json!({
+        "type": "buttons",
+        "text": text,
+        "actions": [
+            {
+                "type": "postback",
+                "label": "送信",
+                // The id doubles as the retry key, so tapping 送信 twice
+                // still broadcasts once.
+                "data": format!(
+                    "action=broadcast&preset={}&id={}",
+                    preset.key,
+                    Uuid::new_v4()
+                ),
+            },
+            {
+                "type": "postback",
+                "label": "キャンセル",
+                "data": "action=cancel",
+            },
+        ],
+    });
+    channel.line.reply_template(target, &text, template).await
+}
+
+/// Asks whether to broadcast `name`, shortened so the question fits in a
+/// buttons template.
+fn broadcast_question(name: &str) -> String {
+    let question = "」の画像を全友だちに送信しますか？";
+    let room = line::MAX_BUTTONS_TEXT_CHARS - 1 - question.chars().count();
+    format!("「{}{}", line::ellipsize(name, room), question)
+}
+
+/// Handles postbacks from admin confirmation prompts. Returns false for
+/// postbacks that belong to other flows.
+pub async fn handle_postback(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    event: &LineEvent,
+    params: &HashMap<String, String>,
+) -> anyhow::Result<bool> {
+    let Some(action) = params.get("action") else {
+        return Ok(false);
+    };
+    // A cancel carrying a pending id is for an upload, not a broadcast.
+    if !matches!(action.as_str(), "broadcast" | "cancel") || params.contains_key("pending") {
+        return Ok(false);
+    }
+
+    // Postback data is client-supplied, so the sender is checked again here.
+    if admin_permission(state, event.source.as_ref()) != Some(Permission::All) {
+        channel
+            .line
+            .reply_text(target, FULL_ADMINS_ONLY_REPLY)
+            .await?;
+        return Ok(true);
+    }
+
+    match action.as_str() {
+        "broadcast" => {
+            let key = params.get("preset").map(String::as_str).unwrap_or("");
+            let id = params
+                .get("id")
+                .map(String::as_str)
+                .filter(|id| Uuid::parse_str(id).is_ok());
+            broadcast(state, channel, target, key, id).await?;
+        }
+        _ => {
+            channel
+                .line
+                .reply_text(target, "キャンセルしました。")
+                .await?;
+        }
+    }
+    Ok(true)
+}
+
+/// `info <key>`: who last changed a preset's image (or video), and when.
+async fn info(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    key: &str,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let Some((name, preset)) = find_preset(&presets, key) else {
+        return channel
+            .line
+            .reply_text(target, "使い方: info <プリセット名>")
+            .await;
+    };
+    let object = match &preset.kind {
+        PresetKind::Image { object } | PresetKind::Video { object, .. } => object,
+        PresetKind::Sticker { .. } => {
+            let reply = format!("「{}」はスタンプのプリセットです。", name);
+            return channel.line.reply_text(target, &reply).await;
+        }
+        PresetKind::Text { body } => {
+            let reply = format!(
+                "「{}」は文章のプリセットです（{}文字）。",
+                name,
+                body.chars().count()
+            );
+            return channel.line.reply_text(target, &reply).await;
+        }
+        PresetKind::Messages { parts } => {
+            let reply = format!(
+                "「{}」は{}件のメッセージをまとめて送るプリセットです。",
+                name,
+                parts.len()
+            );
+            return channel.line.reply_text(target, &reply).await;
+        }
+    };
+
+    let object = state.versions.resolve(&channel.object_path(object));
+    if !state.storage.exists(&object).await? {
+        let reply = format!("「{}」の画像はまだありません。", name);
+        return channel.line.reply_text(target, &reply).await;
+    }
+    let metadata = state.storage.metadata(&object).await?;
+
+    let mut lines = vec![format!("「{}」", name)];
+    for (label, by, at) in [
+        ("アップロード", "uploaded-by", "uploaded-at"),
+        ("紐づけ", "bound-by", "bound-at"),
+    ] {
+        if let Some(at) = metadata.get(at) {
+            let who = match metadata.get(by) {
+                Some(user_id) => display_name(state, channel, user_id).await,
+                None => "不明".to_string(),
+            };
+            lines.push(format!("{}: {} {}", label, format_timestamp(at), who));
+        }
+    }
+    if lines.len() == 1 {
+        lines.push("更新履歴は記録されていません。".to_string());
+    }
+    channel.line.reply_text(target, &lines.join("\n")).await
+}
+
+/// `presets` (or `一覧`): every preset by key, with the size and update
+/// time of its current object, flagging those still without one.
+async fn list_presets(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let snapshot = state.presets.snapshot();
+    let mut presets: Vec<_> = snapshot.iter().collect();
+    presets.sort_by(|(_, a), (_, b)| a.key.cmp(&b.key));
+
+    let mut lines = Vec::with_capacity(presets.len());
+    for (name, preset) in presets {
+        let object = match &preset.kind {
+            PresetKind::Image { object } | PresetKind::Video { object, .. } => object,
+            PresetKind::Sticker { .. } => {
+                lines.push(format!("{}（{}）: スタンプ", preset.key, name));
+                continue;
+            }
+            PresetKind::Text { .. } => {
+                lines.push(format!("{}（{}）: 文章", preset.key, name));
+                continue;
+            }
+            PresetKind::Messages { parts } => {
+                lines.push(format!(
+                    "{}（{}）: メッセージ{}件",
+                    preset.key,
+                    name,
+                    parts.len()
+                ));
+                continue;
+            }
+        };
+        let object = state.versions.resolve(&channel.object_path(object));
+        let status = match state.storage.stat(&object).await? {
+            Some(stat) => format!(
+                "{} {}",
+                format_bytes(stat.size),
+                format_time(stat.updated.into())
+            ),
+            None => "⚠ 未登録".to_string(),
+        };
+        lines.push(format!("{}（{}）: {}", preset.key, name, status));
+    }
+    if lines.is_empty() {
+        lines.push("プリセットがありません。".to_string());
+    }
+    channel
+        .line
+        .reply_messages(target, line::text_messages(&lines.join("\n")))
+        .await
+}
+
+/// `reload`: re-reads presets.json and lists the presets it added,
+/// removed or changed. A broken file leaves the current presets in place.
+async fn reload_presets(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let diff = match state.presets.reload().await {
+        Ok(diff) => diff,
+        Err(e) => {
+            warn!("failed to reload presets: {:#}", e);
+            let reply = format!(
+                "{} を読み込めませんでした。現在のプリセットを使い続けます。\n{:#}",
+                presets::PRESETS_OBJECT,
+                e
+            );
+            return channel.line.reply_text(target, &reply).await;
+        }
+    };
+    let count = state.presets.snapshot().len();
+    if diff.is_empty() {
+        let reply = format!(
+            "プリセットを再読み込みしました（{}件）。変更はありません。",
+            count
+        );
+        return channel.line.reply_text(target, &reply).await;
+    }
+
+    let mut lines = vec![format!("プリセットを再読み込みしました（{}件）", count)];
+    lines.extend(diff_lines(&diff));
+    channel.line.reply_text(target, &lines.join("\n")).await
+}
+
+/// One line per kind of change in `diff`, naming the presets.
+fn diff_lines(diff: &presets::Diff) -> Vec<String> {
+    [
+        ("追加", &diff.added),
+        ("削除", &diff.removed),
+        ("変更", &diff.changed),
+    ]
+    .into_iter()
+    .filter(|(_, names)| !names.is_empty())
+    .map(|(label, names)| format!("{}: {}", label, names.join("、")))
+    .collect()
+}
+
+/// Largest file `import` reads.
+const MAX_IMPORT_BYTES: usize = 1024 * 1024;
+
+/// `export`: saves the preset configuration under exports/ and replies
+/// with where to get it.
+async fn export_presets(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let object = state.presets.export().await?;
+    let url = state.storage.url(&object).await?;
+    let reply = format!(
+        "プリセットを書き出しました。\n{}\n{}\n戻すときは import {} を送ってください。",
+        object, url, object
+    );
+    channel.line.reply_text(target, &reply).await
+}
+
+/// `import <url-or-object>`: replaces the preset configuration with an
+/// exported file, which must pass the same checks as presets.json.
+async fn import_presets(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    source: &str,
+) -> anyhow::Result<()> {
+    if source.is_empty() {
+        return channel
+            .line
+            .reply_text(target, "使い方: import <URL またはオブジェクトパス>")
+            .await;
+    }
+    let data = match fetch_import(state, source).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(source, "failed to read preset import: {:#}", e);
+            let reply = format!("「{}」を読み込めませんでした。\n{:#}", source, e);
+            return channel.line.reply_text(target, &reply).await;
+        }
+    };
+    let diff = match state.presets.import(&data, source).await {
+        Ok(diff) => diff,
+        Err(e) => {
+            warn!(source, "rejected preset import: {:#}", e);
+            let reply = format!(
+                "「{}」は取り込めませんでした。現在のプリセットを使い続けます。\n{:#}",
+                source, e
+            );
+            return channel.line.reply_text(target, &reply).await;
+        }
+    };
+    let count = state.presets.snapshot().len();
+    let mut lines = vec![format!("プリセットを取り込みました（{}件）", count)];
+    if diff.is_empty() {
+        lines.push("変更はありません。".to_string());
+    }
+    lines.extend(diff_lines(&diff));
+    channel.line.reply_text(target, &lines.join("\n")).await
+}
+
+/// Downloads an import from a URL, or reads it from the bucket.
+async fn fetch_import(state: &AppState, source: &str) -> anyhow::Result<Vec<u8>> {
+    let data = if source.starts_with("https://") || source.starts_with("http://") {
+        let response = reqwest::get(source).await?.error_for_status()?;
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_IMPORT_BYTES as u64)
+        {
+            anyhow::bail!("larger than {} bytes", MAX_IMPORT_BYTES);
+        }
+        response.bytes().await?.to_vec()
+    } else {
+        let object = source.trim_start_matches('/');
+        if object.split('/').any(|part| part == "..") {
+            anyhow::bail!("object paths can't contain ..");
+        }
+        state.storage.download(object).await?
+    };
+    if data.len() > MAX_IMPORT_BYTES {
+        anyhow::bail!("larger than {} bytes", MAX_IMPORT_BYTES);
+    }
+    Ok(data)
+}
+
+const PRESET_USAGE: &str = "使い方:\npreset add <固定メッセージ> <画像のオブジェクトパス> [| キャプション]\npreset remove <固定メッセージ>\n空白を含む固定メッセージは \"...\" で囲んでください。";
+
+/// `preset add <message> <object> [| caption]` and `preset remove
+/// <message>`: edits the image presets and saves them to presets.json.
+async fn edit_preset(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    args: &str,
+) -> anyhow::Result<()> {
+    // Everything after the first '|' is the caption, taken as typed.
+    let (args, caption) = match args.split_once('|') {
+        Some((args, caption)) => (args, Some(caption.trim())),
+        None => (args, None),
+    };
+    let Some(args) = split_args(args) else {
+        return channel
+            .line
+            .reply_text(target, "引用符が閉じられていません。")
+            .await;
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let reply = match args.as_slice() {
+        ["add", name, object] => add_preset(state, name, object, caption).await?,
+        ["remove", name] if caption.is_none() => remove_preset(state, name).await?,
+        _ => PRESET_USAGE.to_string(),
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+async fn add_preset(
+    state: &AppState,
+    name: &str,
+    object: &str,
+    caption: Option<&str>,
+) -> anyhow::Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(PRESET_USAGE.to_string());
+    }
+    if name.contains(['&', '=']) {
+        return Ok("固定メッセージに「&」と「=」は使えません。".to_string());
+    }
+    if name.chars().count() > presets::MAX_NAME_CHARS {
+        return Ok(format!(
+            "固定メッセージは{}文字までです。",
+            presets::MAX_NAME_CHARS
+        ));
+    }
+    let first_word = name.split_whitespace().next().unwrap_or(name);
+    if COMMAND_WORDS.contains(&first_word) || name == state.menu_list_command {
+        return Ok(format!(
+            "「{}」は管理者コマンドと重なるため、固定メッセージに使えません。",
+            name
+        ));
+    }
+    let object = object.trim_start_matches('/');
+    if object.is_empty() || object.split('/').any(|part| part == "..") {
+        return Ok(format!("オブジェクトパス「{}」は使えません。", object));
+    }
+    if let Some(caption) = caption
+        && caption.chars().count() > line::MAX_TEXT_CHARS
+    {
+        return Ok(format!(
+            "キャプションは{}文字までです。",
+            line::MAX_TEXT_CHARS
+        ));
+    }
+    let presets = state.presets.snapshot();
+    if let Some(owner) = presets.owner(name)
+        && owner != name
+    {
+        return Ok(format!(
+            "「{}」は「{}」と同じメッセージとして扱われるため、固定メッセージに使えません。",
+            name, owner
+        ));
+    }
+    if let Some(preset) = presets.get(name)
+        && preset.image_object().is_none()
+    {
+        return Ok(format!(
+            "「{}」は画像のプリセットではないため、画像に置き換えられません。",
+            name
+        ));
+    }
+
+    let existed = state.presets.set_image(name, object, caption).await?;
+    let count = state.presets.snapshot().len();
+    info!(preset = %name, object, "image preset saved");
+    Ok(if existed {
+        format!(
+            "「{}」の画像を {} に変更しました（全{}件）。",
+            name, object, count
+        )
+    } else {
+        format!("「{}」を追加しました（全{}件）。", name, count)
+    })
+}
+
+async fn remove_preset(state: &AppState, name_or_key: &str) -> anyhow::Result<String> {
+    let presets = state.presets.snapshot();
+    let Some((name, preset)) = find_preset(&presets, name_or_key) else {
+        return Ok(format!(
+            "「{}」というプリセットはありません。presets で一覧を確認できます。",
+            name_or_key
+        ));
+    };
+    // Anything presets.json doesn't hold came from STICKER_PRESETS or
+    // VIDEO_PRESETS
+    if !state.presets.remove(name).await? {
+        let kind = match preset.kind {
+            PresetKind::Video { .. } => "VIDEO_PRESETS",
+            _ => "STICKER_PRESETS",
+        };
+        return Ok(format!(
+            "「{}」は環境変数 {} で設定されているため、削除できません。",
+            name, kind
+        ));
+    }
+    let count = state.presets.snapshot().len();
+    info!(preset = %name, "preset removed");
+    Ok(format!("「{}」を削除しました（全{}件）。", name, count))
+}
+
+/// Splits command arguments on whitespace, keeping text in double quotes
+/// (straight or curly, as phone keyboards type them) together. None when a
+/// quote is left open.
+fn split_args(args: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' | '“' | '”' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+/// Changes `audit <key>` lists.
+const AUDIT_REPLY_ENTRIES: usize = 5;
+
+/// `audit <key>`: the latest changes to a preset from the audit log.
+async fn audit_log(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    key: &str,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let Some((name, preset)) = find_preset(&presets, key) else {
+        return channel
+            .line
+            .reply_text(target, "使い方: audit <プリセット名>")
+            .await;
+    };
+    let entries = audit::recent(&*state.storage, channel, &preset.key, AUDIT_REPLY_ENTRIES).await?;
+    if entries.is_empty() {
+        let reply = format!("「{}」の変更履歴はありません。", name);
+        return channel.line.reply_text(target, &reply).await;
+    }
+
+    let mut lines = vec![format!("「{}」の変更履歴（新しい順）", name)];
+    for entry in &entries {
+        let who = match &entry.user_id {
+            Some(user_id) => display_name(state, channel, user_id).await,
+            None => "不明".to_string(),
+        };
+        lines.push(format!("{} {}", format_timestamp(&entry.at), who));
+    }
+    channel.line.reply_text(target, &lines.join("\n")).await
+}
+
+const ADMIN_USAGE: &str =
+    "使い方:\nadmin list\nadmin add <ユーザー ID または me>\nadmin remove <ユーザー ID または me>";
+
+/// `admin list`, `admin add <userId>` and `admin remove <userId>`, where
+/// `me` stands for the sender.
+async fn manage_admins(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    sender: Option<&str>,
+    args: &str,
+) -> anyhow::Result<()> {
+    let (action, user_id) = match args.split_once(char::is_whitespace) {
+        Some((action, user_id)) => (action, user_id.trim()),
+        None => (args, ""),
+    };
+    let user_id = match user_id {
+        "me" => sender.unwrap_or_default(),
+        user_id => user_id,
+    };
+    let valid_id = is_user_id(user_id);
+    let reply = match action {
+        "list" if user_id.is_empty() => {
+            let admins = state.admins.entries();
+            let mut lines = vec![format!("管理者（{}人）", admins.len())];
+            for admin in &admins {
+                let scope = match &admin.permission {
+                    Permission::All => "*".to_string(),
+                    Permission::Presets(keys) if keys.is_empty() => "なし".to_string(),
+                    Permission::Presets(keys) => keys.join(", "),
+                };
+                lines.push(format!(
+                    "{} ({}) {}",
+                    display_name(state, channel, &admin.user_id).await,
+                    admin.user_id,
+                    scope
+                ));
+            }
+            lines.join("\n")
+        }
+        "add" if valid_id => {
+            if state.admins.add(user_id).await? {
+                info!(admin = %mask(user_id), by = ?sender.map(mask), "admin added");
+                format!("{} を管理者に追加しました。", user_id)
+            } else {
+                format!("{} は既に管理者です。", user_id)
+            }
+        }
+        "remove" if valid_id => match state.admins.remove(user_id).await? {
+            Removal::Removed => {
+                info!(admin = %mask(user_id), by = ?sender.map(mask), "admin removed");
+                format!("{} を管理者から外しました。", user_id)
+            }
+            Removal::NotAdmin => format!("{} は管理者ではありません。", user_id),
+            Removal::LastAdmin => {
+                "最後の管理者は外せません。先に別の管理者を追加してください。".to_string()
+            }
+        },
+        _ => ADMIN_USAGE.to_string(),
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+const FULL_ADMINS_ONLY_REPLY: &str = "この操作は全権限（*）の管理者のみ可能です。";
+
+fn is_user_id(text: &str) -> bool {
+    text.starts_with('U') && text.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+const GRANT_USAGE: &str =
+    "使い方:\ngrant <ユーザー ID> <プリセット>\nrevoke <ユーザー ID> <プリセット>";
+
+/// Splits `<userId> <preset>` arguments, resolving the preset to its key.
+fn grant_args<'a>(state: &AppState, args: &'a str) -> Result<(&'a str, String), String> {
+    let Some((user_id, name)) = args.split_once(char::is_whitespace) else {
+        return Err(GRANT_USAGE.to_string());
+    };
+    let name = name.trim();
+    if !is_user_id(user_id) || name.is_empty() {
+        return Err(GRANT_USAGE.to_string());
+    }
+    let presets = state.presets.snapshot();
+    match find_preset(&presets, name) {
+        Some((_, preset)) => Ok((user_id, preset.key.clone())),
+        None => Err(format!("プリセット「{}」が見つかりません。", name)),
+    }
+}
+
+/// `grant <userId> <preset>`: lets an admin change one more preset, making
+/// them an admin limited to it if they weren't one.
+async fn grant(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    sender: Option<&str>,
+    args: &str,
+) -> anyhow::Result<()> {
+    let reply = match grant_args(state, args) {
+        Err(reply) => reply,
+        Ok((user_id, key)) => match state.admins.grant(user_id, &key).await? {
+            Grant::Granted => {
+                info!(admin = %mask(user_id), key = %key, by = ?sender.map(mask), "preset granted");
+                format!("{} に「{}」の変更を許可しました。", user_id, key)
+            }
+            Grant::AlreadyGranted => format!("{} は既に「{}」を変更できます。", user_id, key),
+            Grant::AlreadyAll => format!("{} は全権限（*）の管理者です。", user_id),
+        },
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+/// `revoke <userId> <preset>`: takes back a `grant`.
+async fn revoke(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    sender: Option<&str>,
+    args: &str,
+) -> anyhow::Result<()> {
+    let reply = match grant_args(state, args) {
+        Err(reply) => reply,
+        Ok((user_id, key)) => match state.admins.revoke(user_id, &key).await? {
+            Revocation::Revoked => {
+                info!(admin = %mask(user_id), key = %key, by = ?sender.map(mask), "preset revoked");
+                format!("{} の「{}」の変更許可を取り消しました。", user_id, key)
+            }
+            Revocation::NotGranted => format!("{} は「{}」を変更できません。", user_id, key),
+            Revocation::NotAdmin => format!("{} は管理者ではありません。", user_id),
+            Revocation::HasAll => format!(
+                "{} は全権限（*）の管理者です。制限するには admin remove の後に grant してください。",
+                user_id
+            ),
+        },
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+/// Presets `stats` ranks.
+const STATS_REPLY_PRESETS: usize = 10;
+
+/// `stats`: today's most used presets and how many messages matched none.
+async fn preset_stats(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let (day, stats) = state.stats.today(channel).await?;
+    let mut lines = vec![format!("{} のプリセット利用", day)];
+    let top = stats.top(STATS_REPLY_PRESETS);
+    if top.is_empty() {
+        lines.push("まだ利用はありません。".to_string());
+    }
+    for (rank, (name, count)) in top.iter().enumerate() {
+        lines.push(format!("{}. {} {}回", rank + 1, name, count));
+    }
+    lines.push(format!("一致しなかったメッセージ: {}件", stats.misses));
+    channel.line.reply_text(target, &lines.join("\n")).await
+}
+
+/// What `status` reports.
+struct Status {
+    version: &'static str,
+    uptime: Duration,
+    presets: usize,
+    pending_uploads: usize,
+    events_processed: u64,
+    last_line_error: Option<(DateTime<Utc>, String)>,
+    storage_backend: &'static str,
+}
+
+/// `status`: how the running process is doing.
+async fn status(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+) -> anyhow::Result<()> {
+    let sets = channel.object_path("uploads/sets/");
+    let pending_uploads = state
+        .storage
+        .list(&channel.object_path("uploads/"))
+        .await?
+        .iter()
+        .filter(|object| !object.name.starts_with(&sets))
+        .count();
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.started_at.elapsed(),
+        presets: state.presets.snapshot().len(),
+        pending_uploads,
+        events_processed: state.metrics.events_processed.get(),
+        last_line_error: state
+            .metrics
+            .last_line_error
+            .get()
+            .map(|(at, error)| (at.into(), error)),
+        storage_backend: state.storage_backend,
+    };
+    channel
+        .line
+        .reply_text(target, &format_status(&status))
+        .await
+}
+
+fn format_status(status: &Status) -> String {
+    let mut lines = vec![
+        format!("バージョン: {}", status.version),
+        format!("稼働時間: {}", format_uptime(status.uptime)),
+        format!("プリセット: {}件", status.presets),
+        format!("紐づけ待ちのアップロード: {}件", status.pending_uploads),
+        format!("処理したイベント: {}件", status.events_processed),
+    ];
+    lines.push(match &status.last_line_error {
+        Some((at, error)) => format!("直近の LINE API エラー: {} {}", format_time(*at), error),
+        None => "直近の LINE API エラー: なし".to_string(),
+    });
+    lines.push(format!("ストレージ: {}", status.storage_backend));
+    lines.join("\n")
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}日{}時間{}分", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}時間{}分", hours, minutes)
+    } else {
+        format!("{}分", minutes)
+    }
+}
+
+/// A user's display name when LINE will tell us, otherwise their user id.
+async fn display_name(state: &AppState, channel: &Channel, user_id: &str) -> String {
+    match state.profiles.get(channel, user_id).await {
+        Ok(Some(profile)) => profile.display_name,
+        Ok(None) => user_id.to_string(),
+        Err(e) => {
+            warn!("failed to look up profile: {:#}", e);
+            user_id.to_string()
+        }
+    }
+}
+
+/// Renders an RFC 3339 timestamp in Japan time.
+pub fn format_timestamp(rfc3339: &str) -> String {
+    match DateTime::parse_from_rfc3339(rfc3339) {
+        Ok(at) => format_time(at.with_timezone(&Utc)),
+        Err(_) => rfc3339.to_string(),
+    }
+}
+
+pub fn format_time(at: DateTime<Utc>) -> String {
+    let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+    at.with_timezone(&jst).format("%Y-%m-%d %H:%M").to_string()
+}
+
+/// `undo <key>`: puts back the image (or video) a preset had before its
+/// latest update, from the newest backup. The backup is used up, so a
+/// second `undo` goes back one more update.
+async fn undo(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    key: &str,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let Some((name, preset)) = find_preset(&presets, key) else {
+        return channel
+            .line
+            .reply_text(target, "使い方: undo <プリセット名>")
+            .await;
+    };
+    let object = match &preset.kind {
+        PresetKind::Image { object } | PresetKind::Video { object, .. } => object,
+        PresetKind::Sticker { .. } | PresetKind::Text { .. } | PresetKind::Messages { .. } => {
+            return channel
+                .line
+                .reply_text(target, "このプリセットは元に戻せません。")
+                .await;
+        }
+    };
+
+    let object = channel.object_path(object);
+    let Some(backup) = state
+        .backups
+        .latest(&channel.object_path(&backups::prefix(&preset.key)))
+        .await?
+    else {
+        let reply = format!("「{}」には元に戻せる以前の画像がありません。", name);
+        return channel.line.reply_text(target, &reply).await;
+    };
+    // Restoring makes a new version, so the URL changes like any update
+    let restored = state.versions.bind(&object, &backup).await?;
+    state.presence.invalidate(&object);
+    if let Err(e) = state.storage.delete(&backup).await {
+        warn!(backup = %backup, "failed to delete the restored backup: {:#}", e);
+    }
+
+    let message = match &preset.kind {
+        PresetKind::Video { preview, .. } => {
+            let url = state.storage.url(&restored).await?;
+            let preview_url = preset_url(state, channel, preview).await?;
+            line::video_message(&url, &preview_url, Some(&preset.key))
+        }
+        _ => {
+            if let Err(e) = write_preview(state, &restored).await {
+                warn!(object = %restored, "failed to make a preview: {:#}", e);
+            }
+            image_message(state, &restored).await?
+        }
+    };
+    channel
+        .line
+        .reply_messages(
+            target,
+            vec![
+                line::text_message(&format!("「{}」を元に戻しました。", name)),
+                message,
+            ],
+        )
+        .await
+}
+
+async fn broadcast(
+    state: &AppState,
+    channel: &Channel,
+    target: ReplyTarget<'_>,
+    key: &str,
+    retry_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let presets = state.presets.snapshot();
+    let Some((name, object)) =
+        find_preset(&presets, key).and_then(|(name, preset)| Some((name, preset.image_object()?)))
+    else {
+        return channel
+            .line
+            .reply_text(target, "指定されたメッセージが見つかりません。")
+            .await;
+    };
+
+    let image = preset_image(state, channel, object).await?;
+    let reply = match channel.line.broadcast(vec![image], retry_key).await {
+        Ok(()) => {
+            info!(preset = %name, "broadcast sent");
+            format!("「{}」の画像を全友だちに送信しました。", name)
+        }
+        Err(e) => format!("送信に失敗しました: {}", e),
+    };
+    channel.line.reply_text(target, &reply).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::test_support::{
+        ADMIN, Scripted, TestApp, USER, postback_event, text_event, user_source,
+    };
+
+    /// The postback data of the reply's first button.
+    fn button_data(app: &TestApp) -> String {
+        let reply = app.line.to("/v2/bot/message/reply").pop().unwrap().json();
+        reply["messages"][0]["template"]["actions"][0]["data"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn last_reply_text(app: &TestApp) -> String {
+        let reply = app.line.to("/v2/bot/message/reply").pop().unwrap().json();
+        reply["messages"][0]["text"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn broadcast_is_sent_once_confirmed() {
+        let app = TestApp::new().await;
+        app.handle(text_event(user_source(ADMIN), "broadcast 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert!(app.line.to("/v2/bot/message/broadcast").is_empty());
+
+        let data = button_data(&app);
+        app.handle(postback_event(user_source(ADMIN), &data))
+            .await
+            .unwrap();
+        let sent = app.line.to("/v2/bot/message/broadcast");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].json()["messages"][0]["type"], "image");
+        let id = data.rsplit_once("id=").unwrap().1;
+        assert_eq!(sent[0].header("x-line-retry-key"), Some(id));
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」の画像を全友だちに送信しました。"
+        );
+    }
+
+    #[test]
+    fn long_names_are_shortened_in_the_broadcast_question() {
+        let text = broadcast_question(&"長".repeat(200));
+        assert_eq!(text.chars().count(), line::MAX_BUTTONS_TEXT_CHARS);
+        assert!(
+            text.ends_with("長…」の画像を全友だちに送信しますか？"),
+            "{}",
+            text
+        );
+        assert_eq!(
+            broadcast_question("ランチ"),
+            "「ランチ」の画像を全友だちに送信しますか？"
+        );
+    }
+
+    #[tokio::test]
+    async fn broadcast_errors_are_relayed_to_the_admin() {
+        let app = TestApp::new().await;
+        for _ in 0..3 {
+            app.line.respond(
+                "/v2/bot/message/broadcast",
+                Scripted::new(429, r#"{"message":"You have reached your monthly limit."}"#),
+            );
+        }
+        let data = format!("action=broadcast&preset=food1&id={}", Uuid::new_v4());
+        app.handle(postback_event(user_source(ADMIN), &data))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        assert!(reply.starts_with("送信に失敗しました"));
+        assert!(reply.contains("You have reached your monthly limit."));
+    }
+
+    #[tokio::test]
+    async fn non_admins_cannot_broadcast_through_a_postback() {
+        let app = TestApp::new().await;
+        let data = format!("action=broadcast&preset=food1&id={}", Uuid::new_v4());
+        app.handle(postback_event(user_source(USER), &data))
+            .await
+            .unwrap();
+        assert!(app.line.to("/v2/bot/message/broadcast").is_empty());
+        assert_eq!(last_reply_text(&app), FULL_ADMINS_ONLY_REPLY);
+    }
+
+    #[test]
+    fn quota_is_formatted_from_the_api_fixtures() {
+        let quota: Quota = serde_json::from_str(r#"{"type":"limited","value":1000}"#).unwrap();
+        let consumption: QuotaConsumption = serde_json::from_str(r#"{"totalUsage":500}"#).unwrap();
+        assert_eq!(
+            format_quota(&quota, &consumption),
+            "今月の送信数: 500 / 1000 通\n残り: 500 通"
+        );
+
+        let unlimited: Quota = serde_json::from_str(r#"{"type":"none"}"#).unwrap();
+        assert_eq!(
+            format_quota(&unlimited, &consumption),
+            "今月の送信数: 500 通\n上限: なし"
+        );
+    }
+
+    #[test]
+    fn remaining_quota_never_goes_negative() {
+        let quota: Quota = serde_json::from_str(r#"{"type":"limited","value":200}"#).unwrap();
+        let consumption: QuotaConsumption = serde_json::from_str(r#"{"totalUsage":250}"#).unwrap();
+        assert!(format_quota(&quota, &consumption).ends_with("残り: 0 通"));
+    }
+
+    #[tokio::test]
+    async fn quota_command_replies_with_usage() {
+        let app = TestApp::new().await;
+        app.line.respond(
+            "/v2/bot/message/quota",
+            Scripted::new(200, r#"{"type":"limited","value":1000}"#),
+        );
+        app.line.respond(
+            "/v2/bot/message/quota/consumption",
+            Scripted::new(200, r#"{"totalUsage":12}"#),
+        );
+        app.handle(text_event(user_source(ADMIN), "quota"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "今月の送信数: 12 / 1000 通\n残り: 988 通"
+        );
+    }
+
+    #[tokio::test]
+    async fn quota_from_a_non_admin_is_treated_as_a_preset_name() {
+        let app = TestApp::new().await;
+        app.handle(text_event(user_source(USER), "quota"))
+            .await
+            .unwrap();
+        assert!(app.line.to("/v2/bot/message/quota").is_empty());
+        assert_eq!(last_reply_text(&app), crate::DEFAULT_FALLBACK_TEXT);
+    }
+
+    #[test]
+    fn rich_menu_lays_presets_out_in_rows_of_three() {
+        let names: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let names: Vec<&String> = names.iter().collect();
+        let menu = rich_menu(&names);
+        let areas = menu["areas"].as_array().unwrap();
+        assert_eq!(areas.len(), 4);
+        let bounds: Vec<(u64, u64, u64, u64)> = areas
+            .iter()
+            .map(|a| {
+                let b = &a["bounds"];
+                (
+                    b["x"].as_u64().unwrap(),
+                    b["y"].as_u64().unwrap(),
+                    b["width"].as_u64().unwrap(),
+                    b["height"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            bounds,
+            [
+                (0, 0, 833, 843),
+                (833, 0, 833, 843),
+                (1666, 0, 833, 843),
+                (0, 843, 833, 843),
+            ]
+        );
+        assert_eq!(areas[3]["action"]["text"], "d");
+    }
+
+    #[test]
+    fn a_single_preset_fills_the_menu() {
+        let name = "とても長いメニューの名前がここに入りますよ本当に長い".to_string();
+        let menu = rich_menu(&[&name]);
+        let area = &menu["areas"][0];
+        assert_eq!(area["bounds"]["width"], RICH_MENU_WIDTH);
+        assert_eq!(area["bounds"]["height"], RICH_MENU_HEIGHT);
+        assert_eq!(area["action"]["text"], name.as_str());
+        let label = area["action"]["label"].as_str().unwrap();
+        assert_eq!(label.chars().count(), 20);
+    }
+
+    fn solid_jpeg(color: [u8; 3]) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(16, 16, image::Rgb(color));
+        media::encode_jpeg(&image::DynamicImage::ImageRgb8(image), 90).unwrap()
+    }
+
+    fn near(pixel: [u8; 4], color: [u8; 3]) -> bool {
+        pixel[..3]
+            .iter()
+            .zip(color)
+            .all(|(&a, b)| a.abs_diff(b) < 16)
+    }
+
+    #[test]
+    fn cells_follow_the_menu_grid() {
+        assert_eq!(rich_menu_cell(0, 1), (0, 0, 2500, 1686));
+        assert_eq!(rich_menu_cell(1, 2), (1250, 0, 1250, 1686));
+        assert_eq!(rich_menu_cell(4, 5), (833, 843, 833, 843));
+    }
+
+    #[tokio::test]
+    async fn richmenu_sync_uploads_the_image_and_replaces_the_old_menu() {
+        let app = TestApp::new().await;
+        // Sorted, the default presets are ナイトランチ, 食べ物, 飲み物1 and
+        // 飲み物2, in two rows of three.
+        app.storage.put("images/food2.jpg", solid_jpeg([255, 0, 0]));
+        app.storage
+            .put("images/drink2.jpg", solid_jpeg([0, 0, 255]));
+        app.storage.put(RICH_MENU_ID_OBJECT, "rm-old");
+        app.line.respond(
+            "/v2/bot/richmenu",
+            Scripted::new(200, r#"{"richMenuId":"rm-new"}"#),
+        );
+        app.handle(text_event(user_source(ADMIN), "richmenu sync"))
+            .await
+            .unwrap();
+
+        let created = app.line.to("/v2/bot/richmenu")[0].json();
+        assert_eq!(created["areas"].as_array().unwrap().len(), 4);
+        let upload = &app.line.to("/v2/bot/richmenu/rm-new/content")[0];
+        assert_eq!(upload.header("content-type"), Some("image/jpeg"));
+        let menu = media::decode(&upload.body).unwrap().image.to_rgba8();
+        assert_eq!(menu.dimensions(), (RICH_MENU_WIDTH, RICH_MENU_HEIGHT));
+        let at = |i: usize| {
+            let (x, y, width, height) = rich_menu_cell(i, 4);
+            menu.get_pixel(x + width / 2, y + height / 2).0
+        };
+        assert!(near(at(0), [255, 0, 0]), "{:?}", at(0));
+        assert!(near(at(1), [255, 255, 255]), "{:?}", at(1));
+        assert!(near(at(3), [0, 0, 255]), "{:?}", at(3));
+        assert_eq!(app.line.to("/v2/bot/user/all/richmenu/rm-new").len(), 1);
+        assert_eq!(app.line.to("/v2/bot/richmenu/rm-old").len(), 1);
+        assert_eq!(app.storage.get(RICH_MENU_ID_OBJECT).unwrap(), b"rm-new");
+        assert_eq!(
+            last_reply_text(&app),
+            "リッチメニューを更新しました（4件）。"
+        );
+    }
+
+    #[tokio::test]
+    async fn richmenu_sync_composites_the_current_version_and_skips_broken_images() {
+        let app = TestApp::new().await;
+        app.storage.put("images/food2.jpg", solid_jpeg([255, 0, 0]));
+        app.storage
+            .put("uploads/green.jpg", solid_jpeg([0, 255, 0]));
+        app.state
+            .versions
+            .bind("images/food2.jpg", "uploads/green.jpg")
+            .await
+            .unwrap();
+        app.storage.put("images/food1.jpg", b"GIF89a....".to_vec());
+        app.line.respond(
+            "/v2/bot/richmenu",
+            Scripted::new(200, r#"{"richMenuId":"rm-new"}"#),
+        );
+        app.handle(text_event(user_source(ADMIN), "richmenu sync"))
+            .await
+            .unwrap();
+
+        let upload = &app.line.to("/v2/bot/richmenu/rm-new/content")[0];
+        let menu = media::decode(&upload.body).unwrap().image.to_rgba8();
+        let at = |i: usize| {
+            let (x, y, width, height) = rich_menu_cell(i, 4);
+            menu.get_pixel(x + width / 2, y + height / 2).0
+        };
+        assert!(near(at(0), [0, 255, 0]), "{:?}", at(0));
+        assert!(near(at(1), [255, 255, 255]), "{:?}", at(1));
+        assert_eq!(
+            last_reply_text(&app),
+            "リッチメニューを更新しました（4件）。"
+        );
+    }
+
+    #[tokio::test]
+    async fn undo_puts_back_the_image_a_bind_replaced() {
+        let app = TestApp::new().await;
+        app.storage.put("images/food1.jpg", b"original".to_vec());
+        let pending = Uuid::new_v4().to_string();
+        let upload = format!("uploads/{}.jpg", pending);
+        app.storage.put(&upload, b"wrong".to_vec());
+        app.storage
+            .set_metadata(&upload, &[("uploaded-by", ADMIN.to_string())])
+            .await
+            .unwrap();
+        let bind = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("action", "bind")
+            .append_pair("confirm", "yes")
+            .append_pair("pending", &pending)
+            .append_pair("target", "食べ物メニュー")
+            .append_pair("media", "image")
+            .finish();
+        app.handle(postback_event(user_source(ADMIN), &bind))
+            .await
+            .unwrap();
+        let current = |app: &TestApp| {
+            let object = app.state.versions.resolve("images/food1.jpg");
+            app.storage.get(&object).unwrap()
+        };
+        assert_eq!(current(&app), b"wrong");
+        let backups: Vec<_> = app
+            .storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("backups/"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(app.storage.get(&backups[0]).unwrap(), b"original");
+
+        app.handle(text_event(user_source(ADMIN), "undo 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(current(&app), b"original");
+        assert!(app.storage.get(&backups[0]).is_none());
+        let reply = app.line.to("/v2/bot/message/reply").pop().unwrap().json();
+        assert_eq!(
+            reply["messages"][0]["text"],
+            "「食べ物メニュー」を元に戻しました。"
+        );
+        assert_eq!(reply["messages"][1]["type"], "image");
+
+        app.handle(text_event(user_source(ADMIN), "undo 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」には元に戻せる以前の画像がありません。"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_shown_in_japan_time() {
+        assert_eq!(format_timestamp("2024-01-02T18:04:05Z"), "2024-01-03 03:04");
+        assert_eq!(
+            format_timestamp("2024-01-02T18:04:05+09:00"),
+            "2024-01-02 18:04"
+        );
+        assert_eq!(format_timestamp("yesterday"), "yesterday");
+    }
+
+    #[tokio::test]
+    async fn info_reports_who_uploaded_and_bound_the_image() {
+        let app = TestApp::new().await;
+        app.line.respond(
+            &format!("/v2/bot/profile/{}", ADMIN),
+            Scripted::new(200, r#"{"userId": "U", "displayName": "店長"}"#),
+        );
+        app.storage.put("images/food1.jpg", b"jpeg".to_vec());
+        app.storage
+            .set_metadata(
+                "images/food1.jpg",
+                &[
+                    ("uploaded-by", ADMIN.to_string()),
+                    ("uploaded-at", "2024-01-02T01:00:00Z".to_string()),
+                    ("bound-by", "Uformer".to_string()),
+                    ("bound-at", "2024-01-02T01:05:00Z".to_string()),
+                ],
+            )
+            .await
+            .unwrap();
+        app.handle(text_event(user_source(ADMIN), "info 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」\n\
+             アップロード: 2024-01-02 10:00 店長\n\
+             紐づけ: 2024-01-02 10:05 Uformer"
+        );
+    }
+
+    #[tokio::test]
+    async fn info_copes_with_legacy_and_missing_objects() {
+        let app = TestApp::new().await;
+        app.handle(text_event(user_source(ADMIN), "info 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」の画像はまだありません。"
+        );
+
+        app.storage.put("images/food1.jpg", b"jpeg".to_vec());
+        app.handle(text_event(user_source(ADMIN), "info 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」\n更新履歴は記録されていません。"
+        );
+    }
+
+    #[tokio::test]
+    async fn presets_are_listed_by_key_with_missing_images_flagged() {
+        let app = TestApp::new().await;
+        app.storage
+            .put("images/food1.jpg", vec![0; 3 * 1024 * 1024]);
+        let updated = app
+            .storage
+            .stat("images/food1.jpg")
+            .await
+            .unwrap()
+            .unwrap()
+            .updated;
+        app.handle(text_event(user_source(ADMIN), "一覧"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!(
+                "drink1（飲み物1メニュー）: ⚠ 未登録\n\
+                 drink2（飲み物2メニュー）: ⚠ 未登録\n\
+                 food1（食べ物メニュー）: 3.0MB {}\n\
+                 food2（ナイトランチメニュー）: ⚠ 未登録",
+                format_time(updated.into())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_replies_with_the_latest_changes_by_name() {
+        let app = TestApp::new().await;
+        app.line.respond(
+            &format!("/v2/bot/profile/{}", ADMIN),
+            Scripted::new(200, r#"{"userId": "U", "displayName": "店長"}"#),
+        );
+        for (at, user_id) in [
+            ("2024-01-02T01:00:00Z", Some(ADMIN)),
+            ("2024-01-02T03:30:00Z", None),
+        ] {
+            let entry = audit::Entry {
+                at: at.to_string(),
+                user_id: user_id.map(str::to_string),
+                key: "food1".to_string(),
+                pending_id: "p".to_string(),
+                source: "uploads/p.jpg".to_string(),
+                object: "images/food1/1.jpg".to_string(),
+                generation: None,
+            };
+            audit::append(&*app.state.storage, &app.channel(), &entry)
+                .await
+                .unwrap();
+        }
+        app.handle(text_event(user_source(ADMIN), "audit 食べ物メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「食べ物メニュー」の変更履歴（新しい順）\n\
+             2024-01-02 12:30 不明\n\
+             2024-01-02 10:00 店長"
+        );
+
+        app.handle(text_event(user_source(ADMIN), "audit 飲み物1メニュー"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「飲み物1メニュー」の変更履歴はありません。"
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_replies_with_what_changed_and_serves_the_new_presets() {
+        let app = TestApp::new().await;
+        app.storage.put(
+            presets::PRESETS_OBJECT,
+            r#"{"食べ物メニュー": "images/food1.jpg", "ランチ": "images/lunch.jpg"}"#
+                .as_bytes()
+                .to_vec(),
+        );
+        app.handle(text_event(user_source(ADMIN), "reload"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "プリセットを再読み込みしました（2件）\n\
+             追加: ランチ\n\
+             削除: ナイトランチメニュー、飲み物1メニュー、飲み物2メニュー"
+        );
+
+        app.handle(text_event(user_source(USER), "ランチ"))
+            .await
+            .unwrap();
+        assert_eq!(last_reply_text(&app), crate::DEFAULT_MISSING_IMAGE_TEXT);
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_reload_keeps_the_presets_and_tells_the_admin() {
+        let app = TestApp::new().await;
+        app.storage
+            .put(presets::PRESETS_OBJECT, b"{not json".to_vec());
+        app.handle(text_event(user_source(ADMIN), "reload"))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        assert!(
+            reply.starts_with(
+                "presets.json を読み込めませんでした。現在のプリセットを使い続けます。\n"
+            ),
+            "{}",
+            reply
+        );
+        assert_eq!(app.state.presets.snapshot().len(), 4);
+    }
+
+    #[test]
+    fn arguments_split_on_spaces_outside_quotes() {
+        assert_eq!(
+            split_args(r#"add "夜の ランチ" images/lunch.jpg"#).unwrap(),
+            ["add", "夜の ランチ", "images/lunch.jpg"]
+        );
+        assert_eq!(
+            split_args("remove “夜の ランチ”").unwrap(),
+            ["remove", "夜の ランチ"]
+        );
+        assert_eq!(split_args(r#"add "" x"#).unwrap(), ["add", "", "x"]);
+        assert_eq!(split_args(r#"add "夜の ランチ images/lunch.jpg"#), None);
+    }
+
+    #[tokio::test]
+    async fn added_presets_survive_a_restart() {
+        let app = TestApp::new().await;
+        app.handle(text_event(
+            user_source(ADMIN),
+            r#"preset add "夜の ランチ" images/lunch.jpg"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「夜の ランチ」を追加しました（全5件）。"
+        );
+
+        let restarted = presets::PresetStore::load(app.state.storage.clone())
+            .await
+            .unwrap();
+        let presets = restarted.snapshot();
+        assert_eq!(presets.len(), 5);
+        assert_eq!(
+            presets["夜の ランチ"].image_object(),
+            Some("images/lunch.jpg")
+        );
+
+        app.handle(text_event(
+            user_source(ADMIN),
+            r#"preset remove "夜の ランチ""#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "「夜の ランチ」を削除しました（全4件）。"
+        );
+        let restarted = presets::PresetStore::load(app.state.storage.clone())
+            .await
+            .unwrap();
+        assert!(!restarted.snapshot().contains_key("夜の ランチ"));
+    }
+
+    #[tokio::test]
+    async fn command_words_and_postback_characters_are_not_preset_names() {
+        let app = TestApp::new().await;
+        for (text, reply) in [
+            (
+                "preset add reload images/a.jpg",
+                "「reload」は管理者コマンドと重なるため、固定メッセージに使えません。",
+            ),
+            (
+                r#"preset add "undo all" images/a.jpg"#,
+                "「undo all」は管理者コマンドと重なるため、固定メッセージに使えません。",
+            ),
+            (
+                "preset add a&b=c images/a.jpg",
+                "固定メッセージに「&」と「=」は使えません。",
+            ),
+            (
+                "preset remove ランチ",
+                "「ランチ」というプリセットはありません。presets で一覧を確認できます。",
+            ),
+        ] {
+            app.handle(text_event(user_source(ADMIN), text))
+                .await
+                .unwrap();
+            assert_eq!(last_reply_text(&app), reply);
+        }
+        assert!(app.storage.get(presets::PRESETS_OBJECT).is_none());
+    }
+
+    #[tokio::test]
+    async fn long_preset_names_are_refused_with_the_limit() {
+        let app = TestApp::new().await;
+        let name = "ラ".repeat(presets::MAX_NAME_CHARS + 1);
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("preset add {} images/a.jpg", name),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(last_reply_text(&app), "固定メッセージは100文字までです。");
+        assert!(app.storage.get(presets::PRESETS_OBJECT).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_caption_is_taken_from_after_the_separator() {
+        let app = TestApp::new().await;
+        let caption = |app: &TestApp| {
+            let presets = app.state.presets.snapshot();
+            presets["ランチ"].caption.clone()
+        };
+        app.handle(text_event(
+            user_source(ADMIN),
+            "preset add ランチ images/lunch.jpg | 本日の ランチ | 数量限定",
+        ))
+        .await
+        .unwrap();
+        assert_eq!(caption(&app).as_deref(), Some("本日の ランチ | 数量限定"));
+
+        // Without a separator the caption stays; an empty one clears it
+        app.handle(text_event(
+            user_source(ADMIN),
+            "preset add ランチ images/lunch2.jpg",
+        ))
+        .await
+        .unwrap();
+        assert_eq!(caption(&app).as_deref(), Some("本日の ランチ | 数量限定"));
+        app.handle(text_event(
+            user_source(ADMIN),
+            "preset add ランチ images/lunch2.jpg |",
+        ))
+        .await
+        .unwrap();
+        assert_eq!(caption(&app), None);
+
+        app.handle(text_event(user_source(ADMIN), "preset remove ランチ | x"))
+            .await
+            .unwrap();
+        assert_eq!(last_reply_text(&app), PRESET_USAGE);
+    }
+
+    #[tokio::test]
+    async fn stats_ranks_todays_presets_and_counts_misses() {
+        let app = TestApp::new().await;
+        let channel = app.channel();
+        let day = Utc::now()
+            .with_timezone(&chrono::FixedOffset::east_opt(9 * 3600).unwrap())
+            .format("%Y-%m-%d");
+
+        app.handle(text_event(user_source(ADMIN), "stats"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!(
+                "{} のプリセット利用\nまだ利用はありません。\n一致しなかったメッセージ: 0件",
+                day
+            )
+        );
+
+        for name in ["飲み物1メニュー", "食べ物メニュー", "食べ物メニュー"] {
+            app.state.stats.hit(&channel, name);
+        }
+        app.state.stats.miss(&channel);
+        app.handle(text_event(user_source(ADMIN), "stats"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!(
+                "{} のプリセット利用\n\
+                 1. 食べ物メニュー 2回\n\
+                 2. 飲み物1メニュー 1回\n\
+                 一致しなかったメッセージ: 1件",
+                day
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn export_then_import_restores_the_presets() {
+        let app = TestApp::new().await;
+        app.handle(text_event(user_source(ADMIN), "export"))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "プリセットを書き出しました。");
+        let object = lines[1];
+        assert!(object.starts_with("exports/presets-"), "{}", object);
+        assert_eq!(lines[2], format!("https://storage.test/{}", object));
+        assert_eq!(
+            lines[3],
+            format!("戻すときは import {} を送ってください。", object)
+        );
+
+        app.handle(text_event(
+            user_source(ADMIN),
+            "preset remove 飲み物2メニュー",
+        ))
+        .await
+        .unwrap();
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("import {}", object),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "プリセットを取り込みました（4件）\n追加: 飲み物2メニュー"
+        );
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("import {}", object),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "プリセットを取り込みました（4件）\n変更はありません。"
+        );
+    }
+
+    #[tokio::test]
+    async fn import_refuses_a_file_with_a_duplicate_alias() {
+        let app = TestApp::new().await;
+        app.storage.put(
+            "exports/bad.json",
+            r#"{
+                "ランチ": {"object": "images/lunch.jpg", "aliases": ["menu"]},
+                "ディナー": {"object": "images/dinner.jpg", "aliases": ["menu"]}
+            }"#
+            .as_bytes()
+            .to_vec(),
+        );
+        app.handle(text_event(user_source(ADMIN), "import exports/bad.json"))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        assert!(
+            reply.starts_with(
+                "「exports/bad.json」は取り込めませんでした。現在のプリセットを使い続けます。\n"
+            ),
+            "{}",
+            reply
+        );
+        assert!(
+            reply.contains("alias \"menu\" is used by both"),
+            "{}",
+            reply
+        );
+        assert_eq!(app.state.presets.snapshot().len(), 4);
+
+        app.handle(text_event(user_source(ADMIN), "import ../presets.json"))
+            .await
+            .unwrap();
+        assert!(last_reply_text(&app).starts_with("「../presets.json」を読み込めませんでした。"),);
+    }
+
+    #[tokio::test]
+    async fn admin_add_takes_a_user_id_or_me() {
+        let app = TestApp::new().await;
+        let new_admin = "Unew00000000000000000000000000000";
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("admin add {}", new_admin),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!("{} を管理者に追加しました。", new_admin)
+        );
+        assert!(app.state.admins.permission(new_admin).is_some());
+
+        app.handle(text_event(user_source(ADMIN), "admin add me"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!("{} は既に管理者です。", ADMIN)
+        );
+
+        for args in [
+            "admin add",
+            "admin add not-a-user",
+            "admin add U1 U2",
+            "admin",
+            "admin promote me",
+        ] {
+            app.handle(text_event(user_source(ADMIN), args))
+                .await
+                .unwrap();
+            assert_eq!(last_reply_text(&app), ADMIN_USAGE, "{}", args);
+        }
+        assert_eq!(app.state.admins.list(), [ADMIN, new_admin]);
+        // The list is kept in storage
+        let stored: Vec<String> =
+            serde_json::from_slice(&app.storage.get(crate::admins::ADMINS_OBJECT).unwrap())
+                .unwrap();
+        assert_eq!(stored, [ADMIN, new_admin]);
+    }
+
+    #[tokio::test]
+    async fn admin_remove_refuses_the_last_admin() {
+        let app = TestApp::new().await;
+        app.handle(text_event(user_source(ADMIN), "admin remove me"))
+            .await
+            .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            "最後の管理者は外せません。先に別の管理者を追加してください。"
+        );
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("admin remove {}", USER),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!("{} は管理者ではありません。", USER)
+        );
+
+        app.state.admins.add(USER).await.unwrap();
+        app.handle(text_event(
+            user_source(ADMIN),
+            &format!("admin remove {}", USER),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            last_reply_text(&app),
+            format!("{} を管理者から外しました。", USER)
+        );
+        assert_eq!(app.state.admins.list(), [ADMIN]);
+    }
+
+    #[tokio::test]
+    async fn admin_list_names_each_admin_with_their_scope() {
+        let app = TestApp::new().await;
+        app.line.respond(
+            &format!("/v2/bot/profile/{}", ADMIN),
+            Scripted::new(200, r#"{"userId": "U", "displayName": "店長"}"#),
+        );
+        app.state.admins.grant(USER, "food1").await.unwrap();
+        app.handle(text_event(user_source(ADMIN), "admin list"))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "管理者（2人）");
+        assert_eq!(lines[1], format!("店長 ({}) *", ADMIN));
+        assert!(
+            lines[2].ends_with(&format!("({}) food1", USER)),
+            "{}",
+            lines[2]
+        );
+    }
+
+    #[test]
+    fn status_lists_every_field_in_one_message() {
+        let at = DateTime::parse_from_rfc3339("2024-05-01T03:04:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let status = Status {
+            version: "1.2.3",
+            uptime: Duration::from_secs(((2 * 24 + 5) * 60 + 7) * 60 + 59),
+            presets: 12,
+            pending_uploads: 3,
+            events_processed: 4567,
+            last_line_error: Some((at, "500 Internal Server Error".to_string())),
+            storage_backend: "gcs",
+        };
+        let text = format_status(&status);
+        assert_eq!(
+            text,
+            "バージョン: 1.2.3\n\
+             稼働時間: 2日5時間7分\n\
+             プリセット: 12件\n\
+             紐づけ待ちのアップロード: 3件\n\
+             処理したイベント: 4567件\n\
+             直近の LINE API エラー: 2024-05-01 12:04 500 Internal Server Error\n\
+             ストレージ: gcs"
+        );
+        assert!(text.chars().count() <= crate::line::MAX_TEXT_CHARS);
+
+        let quiet = Status {
+            last_line_error: None,
+            ..status
+        };
+        assert!(format_status(&quiet).contains("直近の LINE API エラー: なし"));
+    }
+
+    #[test]
+    fn uptime_drops_leading_zero_units() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0分");
+        assert_eq!(format_uptime(Duration::from_secs(61 * 60)), "1時間1分");
+        assert_eq!(format_uptime(Duration::from_secs(24 * 3600)), "1日0時間0分");
+    }
+
+    #[tokio::test]
+    async fn status_counts_uploads_but_not_set_manifests() {
+        let app = TestApp::new().await;
+        app.storage.put("uploads/a.jpg", b"jpeg".to_vec());
+        app.storage.put("uploads/b.mp4", b"mp4".to_vec());
+        app.storage.put("uploads/sets/s.json", b"[]".to_vec());
+        app.state.metrics.events_processed.inc();
+        app.handle(text_event(user_source(ADMIN), "status"))
+            .await
+            .unwrap();
+        let reply = last_reply_text(&app);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!("バージョン: {}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(lines[1], "稼働時間: 0分");
+        assert_eq!(lines[2], "プリセット: 4件");
+        assert_eq!(lines[3], "紐づけ待ちのアップロード: 2件");
+        assert_eq!(lines[4], "処理したイベント: 1件");
+        assert_eq!(lines[5], "直近の LINE API エラー: なし");
+        assert_eq!(lines[6], "ストレージ: memory");
+    }
+
+    #[tokio::test]
+    async fn grant_and_revoke_name_the_preset_and_persist() {
+        let app = TestApp::new().await;
+        let send = |text: String| {
+            let app = &app;
+            async move {
+                app.handle(text_event(user_source(ADMIN), &text))
+                    .await
+                    .unwrap();
+                last_reply_text(app)
+            }
+        };
+        assert_eq!(
+            send(format!("grant {} 飲み物1メニュー", USER)).await,
+            format!("{} に「drink1」の変更を許可しました。", USER)
+        );
+        assert_eq!(
+            send(format!("grant {} drink1", USER)).await,
+            format!("{} は既に「drink1」を変更できます。", USER)
+        );
+        assert_eq!(
+            send(format!("grant {} 存在しない", USER)).await,
+            "プリセット「存在しない」が見つかりません。"
+        );
+        assert_eq!(
+            send(format!("grant {} food1", ADMIN)).await,
+            format!("{} は全権限（*）の管理者です。", ADMIN)
+        );
+        assert_eq!(send("grant".to_string()).await, GRANT_USAGE);
+
+        let reloaded = crate::admins::AdminStore::load(app.state.storage.clone(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            reloaded.permission(USER),
+            Some(Permission::Presets(vec!["drink1".to_string()]))
+        );
+
+        assert_eq!(
+            send(format!("revoke {} 飲み物1メニュー", USER)).await,
+            format!("{} の「drink1」の変更許可を取り消しました。", USER)
+        );
+        assert_eq!(
+            send(format!("revoke {} drink1", USER)).await,
+            format!("{} は「drink1」を変更できません。", USER)
+        );
+        let reloaded = crate::admins::AdminStore::load(app.state.storage.clone(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            reloaded.permission(USER),
+            Some(Permission::Presets(Vec::new()))
+        );
+    }
+
+    #[tokio::test]
+    async fn only_full_admins_may_grant() {
+        let app = TestApp::new().await;
+        app.state.admins.grant(USER, "food1").await.unwrap();
+        let other = "Uother00000000000000000000000000";
+        for command in [
+            format!("grant {} food1", other),
+            format!("revoke {} food1", USER),
+        ] {
+            app.handle(text_event(user_source(USER), &command))
+                .await
+                .unwrap();
+            assert_eq!(last_reply_text(&app), FULL_ADMINS_ONLY_REPLY, "{}", command);
+        }
+        assert_eq!(app.state.admins.permission(other), None);
+        assert_eq!(
+            app.state.admins.permission(USER),
+            Some(Permission::Presets(vec!["food1".to_string()]))
+        );
+    }
+}
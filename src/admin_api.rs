@@ -0,0 +1,637 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{self, Query, Request, State, rejection::BytesRejection},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppState, Channel, PresetKind, UploadKind, admin, bind_upload, check_image, find_preset,
+    media::ImageFormat, now_rfc3339,
+};
+
+/// Recorded as the uploader and binder of images sent through the API, in
+/// place of a LINE user id.
+const API_USER: &str = "admin-api";
+
+/// Room for multipart headers and boundaries on top of MAX_UPLOAD_BYTES.
+pub const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+/// ADMIN_API_TOKEN, which callers send as a bearer token.
+#[derive(Clone)]
+pub struct AdminToken(pub Arc<str>);
+
+#[derive(Deserialize)]
+pub struct ChannelQuery {
+    /// Channel name; the first channel when absent.
+    channel: Option<String>,
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn authorized(headers: &HeaderMap, token: &AdminToken) -> bool {
+    let Some(given) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.trim().as_bytes().ct_eq(token.0.as_bytes()).into()
+}
+
+fn unauthorized() -> Response {
+    let mut resp = error_response(StatusCode::UNAUTHORIZED, "invalid token");
+    resp.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    resp
+}
+
+/// Turns away requests without the token before their handler runs, so
+/// an unauthenticated upload is refused without its body being read.
+pub async fn require_token(State(token): State<AdminToken>, req: Request, next: Next) -> Response {
+    if !authorized(req.headers(), &token) {
+        return unauthorized();
+    }
+    next.run(req).await
+}
+
+fn find_channel<'a>(state: &'a AppState, query: &ChannelQuery) -> Option<&'a Arc<Channel>> {
+    match &query.channel {
+        Some(name) => state.channels.iter().find(|channel| &channel.name == name),
+        None => state.channels.first(),
+    }
+}
+
+/// GET /admin/presets: every preset an upload can replace, with the
+/// object it currently resolves to.
+pub async fn list_presets(
+    State(state): State<AppState>,
+    Query(query): Query<ChannelQuery>,
+) -> Response {
+    let Some(channel) = find_channel(&state, &query) else {
+        return error_response(StatusCode::NOT_FOUND, "unknown channel");
+    };
+    let presets = state.presets.snapshot();
+    let mut bindings: Vec<_> = presets
+        .iter()
+        .filter_map(|(name, preset)| {
+            let (kind, object) = [UploadKind::Image, UploadKind::Video]
+                .into_iter()
+                .find_map(|kind| Some((kind, preset.upload_object(kind)?)))?;
+            Some((name, preset, kind, object))
+        })
+        .collect();
+    bindings.sort_by(|a, b| a.1.key.cmp(&b.1.key).then(a.0.cmp(b.0)));
+
+    let mut entries = Vec::with_capacity(bindings.len());
+    for (name, preset, kind, object) in bindings {
+        let version = state.versions.resolve(&channel.object_path(object));
+        let url = object_url(&state, &version).await;
+        entries.push(json!({
+            "key": preset.key,
+            "name": name,
+            "kind": kind.param(),
+            "object": object,
+            "version": version,
+            "url": url,
+        }));
+    }
+    Json(json!({ "channel": channel.name, "presets": entries })).into_response()
+}
+
+/// POST /admin/presets/{key}/image: replaces a preset's image with the
+/// request body, either the image itself or a multipart/form-data form
+/// carrying it, checked the same way as one sent over LINE.
+pub async fn upload_image(
+    State(state): State<AppState>,
+    extract::Path(key): extract::Path<String>,
+    Query(query): Query<ChannelQuery>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> Response {
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return too_large(&state);
+        }
+        Err(rejection) => return error_response(rejection.status(), &rejection.body_text()),
+    };
+    let Some(channel) = find_channel(&state, &query) else {
+        return error_response(StatusCode::NOT_FOUND, "unknown channel");
+    };
+    let presets = state.presets.snapshot();
+    let Some((_, preset)) = find_preset(&presets, &key) else {
+        return error_response(StatusCode::NOT_FOUND, "unknown preset");
+    };
+    if preset.upload_object(UploadKind::Image).is_none() {
+        return error_response(StatusCode::BAD_REQUEST, "preset does not take images");
+    }
+
+    let data = match multipart_boundary(&headers) {
+        Some(boundary) => match multipart_file(&body, &boundary) {
+            Some(data) => data,
+            None => {
+                return error_response(StatusCode::BAD_REQUEST, "no image field in form data");
+            }
+        },
+        None => body,
+    };
+    if data.len() as u64 > state.max_upload_bytes {
+        return too_large(&state);
+    }
+    // Taken before decoding, which is most of an upload's work
+    let Ok(_permit) = state.upload_permits.try_acquire() else {
+        return error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "another upload is in progress",
+        );
+    };
+    let image = match check_image(&state, data).await {
+        Ok(image) => image,
+        Err(reason) => {
+            info!(key = %preset.key, reason = %reason, "API upload rejected");
+            return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, &reason);
+        }
+    };
+
+    let pending_id = format!("{}.{}", Uuid::new_v4(), UploadKind::Image.extension());
+    let tmp_object = channel.object_path(&UploadKind::Image.tmp_object(&pending_id));
+    if let Err(e) = state
+        .storage
+        .upload(
+            &tmp_object,
+            image.data.to_vec(),
+            UploadKind::Image.content_type(),
+        )
+        .await
+    {
+        error!(object = %tmp_object, "failed to store API upload: {:#}", e);
+        return error_response(StatusCode::BAD_GATEWAY, "failed to store the image");
+    }
+    state.metrics.gcs_uploads.inc();
+    let metadata = [
+        ("uploaded-by", API_USER.to_string()),
+        ("uploaded-at", now_rfc3339()),
+        ("width", image.width.to_string()),
+        ("height", image.height.to_string()),
+    ];
+    if let Err(e) = state.storage.set_metadata(&tmp_object, &metadata).await {
+        warn!(object = %tmp_object, "failed to record upload metadata: {:#}", e);
+    }
+
+    let version = match bind_upload(
+        &state,
+        channel,
+        preset,
+        UploadKind::Image,
+        &pending_id,
+        &tmp_object,
+        Some(API_USER),
+    )
+    .await
+    {
+        Ok(version) => version,
+        Err(e) => {
+            error!(key = %preset.key, "failed to bind API upload: {:#}", e);
+            if let Err(e) = state.storage.delete(&tmp_object).await {
+                warn!(object = %tmp_object, "failed to delete temporary upload: {:#}", e);
+            }
+            return error_response(StatusCode::BAD_GATEWAY, "failed to bind the image");
+        }
+    };
+    info!(key = %preset.key, object = %version, "preset image replaced through the API");
+    let url = object_url(&state, &version).await;
+    Json(json!({
+        "key": preset.key,
+        "object": version,
+        "url": url,
+        "convertedFrom": image.converted_from.map(ImageFormat::name),
+    }))
+    .into_response()
+}
+
+async fn object_url(state: &AppState, object: &str) -> Option<String> {
+    match state.storage.url(object).await {
+        Ok(url) => Some(url),
+        Err(e) => {
+            warn!(object, "failed to build preset URL: {:#}", e);
+            None
+        }
+    }
+}
+
+/// GET /admin/dashboard: a page showing what each preset currently sends,
+/// for staff who'd rather not read JSON.
+pub async fn dashboard(
+    State(state): State<AppState>,
+    Query(query): Query<ChannelQuery>,
+) -> Response {
+    let Some(channel) = find_channel(&state, &query) else {
+        return error_response(StatusCode::NOT_FOUND, "unknown channel");
+    };
+    let stats = match state.stats.today(channel).await {
+        Ok(today) => Some(today),
+        Err(e) => {
+            warn!("failed to read today's stats: {:#}", e);
+            None
+        }
+    };
+    let presets = state.presets.snapshot();
+    let mut presets: Vec<_> = presets.iter().collect();
+    presets.sort_by(|(a_name, a), (b_name, b)| a.key.cmp(&b.key).then(a_name.cmp(b_name)));
+
+    let mut rows = String::new();
+    for (name, preset) in presets {
+        // The object whose update time is shown, and the picture to show
+        let (kind, object, picture) = match &preset.kind {
+            PresetKind::Image { object } => ("画像", Some(object), Some(object)),
+            PresetKind::Video { object, preview } => ("動画", Some(object), Some(preview)),
+            PresetKind::Sticker { .. } => ("スタンプ", None, None),
+            PresetKind::Text { .. } => ("文章", None, None),
+            PresetKind::Messages { .. } => ("複数メッセージ", None, None),
+        };
+        let picture = match picture {
+            Some(picture) => {
+                let picture = state.versions.resolve(&channel.object_path(picture));
+                match object_url(&state, &picture).await {
+                    Some(url) => format!(
+                        r#"<img src="{}" alt="{}" loading="lazy">"#,
+                        escape_html(&url),
+                        escape_html(&preset.key)
+                    ),
+                    None => String::new(),
+                }
+            }
+            None => String::new(),
+        };
+        let updated = match object {
+            Some(object) => {
+                let object = state.versions.resolve(&channel.object_path(object));
+                last_updated(&state, &object).await
+            }
+            None => "-".to_string(),
+        };
+        let hits = match &stats {
+            Some((_, stats)) => stats.hits.get(name).copied().unwrap_or(0).to_string(),
+            None => "-".to_string(),
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&preset.key),
+            escape_html(name),
+            kind,
+            picture,
+            escape_html(&updated),
+            hits
+        ));
+    }
+    let hits_heading = match &stats {
+        Some((day, _)) => format!("利用回数（{}）", escape_html(day)),
+        None => "利用回数".to_string(),
+    };
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>プリセット一覧</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #ccc; padding: 0.4em; text-align: left; vertical-align: top; }}
+img {{ max-width: 200px; max-height: 200px; }}
+</style>
+</head>
+<body>
+<h1>プリセット一覧（{}）</h1>
+<table>
+<tr><th>キー</th><th>メッセージ</th><th>種類</th><th>現在の内容</th><th>更新日時</th><th>{}</th></tr>
+{}</table>
+</body>
+</html>
+"#,
+        escape_html(&channel.name),
+        hits_heading,
+        rows
+    );
+    Html(page).into_response()
+}
+
+/// When the object was last bound, from its metadata, or its storage
+/// update time when it predates that metadata.
+async fn last_updated(state: &AppState, object: &str) -> String {
+    match state.storage.metadata(object).await {
+        Ok(metadata) if metadata.contains_key("bound-at") => {
+            return admin::format_timestamp(&metadata["bound-at"]);
+        }
+        Ok(_) => {}
+        Err(e) => warn!(object, "failed to read object metadata: {:#}", e),
+    }
+    match state.storage.stat(object).await {
+        Ok(Some(stat)) => admin::format_time(stat.updated.into()),
+        Ok(None) => "未登録".to_string(),
+        Err(e) => {
+            warn!(object, "failed to stat object: {:#}", e);
+            "-".to_string()
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn too_large(state: &AppState) -> Response {
+    error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        &format!("image exceeds {} bytes", state.max_upload_bytes),
+    )
+}
+
+fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+/// The first file in a multipart/form-data body: a part with a filename,
+/// or one named `image` or `file`.
+fn multipart_file(body: &Bytes, boundary: &str) -> Option<Bytes> {
+    let delimiter = format!("--{}", boundary);
+    let mut pos = find(body, delimiter.as_bytes(), 0)? + delimiter.len();
+    let delimiter = format!("\r\n{}", delimiter);
+    loop {
+        // `--` after a delimiter closes the body
+        if body[pos..].starts_with(b"--") {
+            return None;
+        }
+        let headers_start = find(body, b"\r\n", pos)? + 2;
+        let headers_end = find(body, b"\r\n\r\n", headers_start - 2)?;
+        let data_start = headers_end + 4;
+        let data_end = find(body, delimiter.as_bytes(), data_start)?;
+        let headers = body.get(headers_start..headers_end).unwrap_or_default();
+        let headers = String::from_utf8_lossy(headers);
+        if is_file_part(&headers) {
+            return Some(body.slice(data_start..data_end));
+        }
+        pos = data_end + delimiter.len();
+    }
+}
+
+fn is_file_part(headers: &str) -> bool {
+    headers.lines().any(|line| {
+        let Some((name, value)) = line.split_once(':') else {
+            return false;
+        };
+        name.trim().eq_ignore_ascii_case("content-disposition")
+            && value.split(';').any(|param| {
+                let param = param.trim();
+                param.starts_with("filename=")
+                    || param == "name=\"image\""
+                    || param == "name=\"file\""
+            })
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| i + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        router,
+        test_support::{PNG, TestApp, serve},
+    };
+
+    const TOKEN: &str = "test-admin-token";
+
+    async fn serve_api(state: &AppState) -> String {
+        serve(router(
+            state.clone(),
+            1024 * 1024,
+            None,
+            Some(TOKEN.to_string()),
+        ))
+        .await
+    }
+
+    async fn upload(base: &str, key: &str, token: &str, body: Vec<u8>) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/presets/{}/image", base, key))
+            .bearer_auth(token)
+            .header("content-type", "image/png")
+            .body(body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_token_get_401() {
+        let app = TestApp::new().await;
+        let base = serve_api(&app.state).await;
+        let client = reqwest::Client::new();
+        for path in ["/admin/presets", "/admin/dashboard"] {
+            let resp = client
+                .get(format!("{}{}", base, path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        }
+        let resp = upload(&base, "food1", "wrong-token", PNG.to_vec()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // Refused before the body is read, so its size doesn't matter
+        let huge = vec![0; 2 * 1024 * 1024];
+        let resp = upload(&base, "food1", "wrong-token", huge).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(
+            app.storage
+                .names()
+                .iter()
+                .all(|name| !name.starts_with("uploads/"))
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_presets_and_channels_get_404() {
+        let app = TestApp::new().await;
+        let base = serve_api(&app.state).await;
+        let resp = upload(&base, "no-such-preset", TOKEN, PNG.to_vec()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"], "unknown preset");
+
+        let resp = reqwest::Client::new()
+            .get(format!("{}/admin/presets?channel=elsewhere", base))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn oversized_images_get_413() {
+        let mut app = TestApp::new().await;
+        app.state.max_upload_bytes = 1024;
+        let base = serve_api(&app.state).await;
+        // Over MAX_UPLOAD_BYTES but within the room left for multipart
+        let resp = upload(&base, "food1", TOKEN, vec![0; 2048]).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"], "image exceeds 1024 bytes");
+        // Over the body limit itself
+        let resp = upload(
+            &base,
+            "food1",
+            TOKEN,
+            vec![0; 1024 + MULTIPART_OVERHEAD + 1],
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn uploads_beyond_the_permits_get_429_before_decoding() {
+        let app = TestApp::new().await;
+        let base = serve_api(&app.state).await;
+        let held = app.state.upload_permits.clone().try_acquire_many_owned(2);
+        // Not an image, but turned away before anyone looks
+        let resp = upload(&base, "food1", TOKEN, b"not an image".to_vec()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(held);
+        let resp = upload(&base, "food1", TOKEN, b"not an image".to_vec()).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn an_upload_is_bound_and_listed() {
+        let app = TestApp::new().await;
+        let base = serve_api(&app.state).await;
+        let resp = upload(&base, "food1", TOKEN, PNG.to_vec()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["key"], "food1");
+        let object = body["object"].as_str().unwrap();
+        assert!(app.storage.get(object).is_some());
+        assert_eq!(body["url"], format!("https://storage.test/{}", object));
+
+        let resp = reqwest::Client::new()
+            .get(format!("{}/admin/presets", base))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let food1 = body["presets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|preset| preset["key"] == "food1")
+            .unwrap();
+        assert_eq!(food1["name"], "食べ物メニュー");
+        assert_eq!(food1["version"], object);
+    }
+
+    async fn get_dashboard(base: &str, token: Option<&str>) -> reqwest::Response {
+        let mut req = reqwest::Client::new().get(format!("{}/admin/dashboard", base));
+        if let Some(token) = token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_dashboard_is_absent_without_a_configured_token() {
+        let app = TestApp::new().await;
+        let base = serve(router(app.state.clone(), 1024 * 1024, None, None)).await;
+        let resp = get_dashboard(&base, Some(TOKEN)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let base = serve_api(&app.state).await;
+        let resp = get_dashboard(&base, None).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = get_dashboard(&base, Some("wrong-token")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn the_dashboard_lists_every_preset_with_its_image_and_uses() {
+        let app = TestApp::new().await;
+        app.storage.put("images/food1.jpg", PNG.to_vec());
+        let channel = app.channel();
+        app.state.stats.hit(&channel, "食べ物メニュー");
+        app.state.stats.hit(&channel, "食べ物メニュー");
+        let base = serve_api(&app.state).await;
+
+        let resp = get_dashboard(&base, Some(TOKEN)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(
+            resp.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html")
+        );
+        let html = resp.text().await.unwrap();
+        for (name, preset) in app.state.presets.snapshot().iter() {
+            let row = format!("<tr><td>{}</td><td>{}</td>", preset.key, name);
+            assert!(html.contains(&row), "{} missing from\n{}", name, html);
+        }
+        let food1 = html
+            .lines()
+            .find(|line| line.starts_with("<tr><td>food1</td>"))
+            .unwrap();
+        assert!(
+            food1.contains(r#"<img src="https://storage.test/"#),
+            "{}",
+            food1
+        );
+        assert!(food1.ends_with("<td>2</td></tr>"), "{}", food1);
+        assert!(!food1.contains("未登録"), "{}", food1);
+    }
+}
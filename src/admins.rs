@@ -0,0 +1,465 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::storage::{self, PreconditionFailed, Storage};
+
+/// The admin list, as a JSON array of admins.
+pub const ADMINS_OBJECT: &str = "state/admins.json";
+
+/// Edits retried when another instance rewrote the list between our read
+/// and write.
+const MAX_EDIT_ATTEMPTS: u32 = 3;
+
+/// An admin and what they may change.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "RawAdmin", into = "RawAdmin")]
+pub struct Admin {
+    pub user_id: String,
+    pub permission: Permission,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Permission {
+    /// Every preset, every admin command and managing other admins.
+    All,
+    /// Only the presets with these keys.
+    Presets(Vec<String>),
+}
+
+impl Permission {
+    /// Whether the preset `key` may be changed.
+    pub fn allows(&self, key: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Presets(keys) => keys.iter().any(|k| k == key),
+        }
+    }
+}
+
+/// An admin as written: a bare user id for `*` (every preset), or
+/// `{"userId": "U…", "presets": ["menu3", "menu4"]}`.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RawAdmin {
+    All(String),
+    Limited {
+        #[serde(rename = "userId")]
+        user_id: String,
+        presets: RawPresets,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RawPresets {
+    /// Only `"*"`.
+    All(String),
+    Keys(Vec<String>),
+}
+
+impl TryFrom<RawAdmin> for Admin {
+    type Error = String;
+
+    fn try_from(raw: RawAdmin) -> Result<Self, String> {
+        let (user_id, permission) = match raw {
+            RawAdmin::All(user_id) => (user_id, Permission::All),
+            RawAdmin::Limited {
+                user_id,
+                presets: RawPresets::All(all),
+            } if all == "*" => (user_id, Permission::All),
+            RawAdmin::Limited {
+                presets: RawPresets::All(other),
+                ..
+            } => {
+                return Err(format!(
+                    "presets must be \"*\" or a list of keys, got {:?}",
+                    other
+                ));
+            }
+            RawAdmin::Limited {
+                user_id,
+                presets: RawPresets::Keys(keys),
+            } => (user_id, Permission::Presets(keys)),
+        };
+        Ok(Self {
+            user_id,
+            permission,
+        })
+    }
+}
+
+impl From<Admin> for RawAdmin {
+    fn from(admin: Admin) -> Self {
+        match admin.permission {
+            Permission::All => Self::All(admin.user_id),
+            Permission::Presets(keys) => Self::Limited {
+                user_id: admin.user_id,
+                presets: RawPresets::Keys(keys),
+            },
+        }
+    }
+}
+
+/// What `remove` did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Removal {
+    Removed,
+    NotAdmin,
+    /// Refused, since nobody could manage the bot afterwards.
+    LastAdmin,
+}
+
+/// What `grant` did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Grant {
+    Granted,
+    AlreadyGranted,
+    /// The admin may already change every preset.
+    AlreadyAll,
+}
+
+/// What `revoke` did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Revocation {
+    Revoked,
+    NotGranted,
+    NotAdmin,
+    /// Refused; an admin with `*` has no per-preset grants to take back.
+    HasAll,
+}
+
+/// The users allowed to run admin commands and upload media, and which
+/// presets each may change. Kept in storage so admins can be added and
+/// removed without a redeploy.
+pub struct AdminStore {
+    storage: Arc<dyn Storage>,
+    admins: RwLock<Vec<Admin>>,
+}
+
+impl AdminStore {
+    /// Reads the stored list, or, on first run, stores `seed` (from
+    /// ADMIN_USER_IDS) as the list.
+    pub async fn load(storage: Arc<dyn Storage>, seed: Vec<String>) -> anyhow::Result<Self> {
+        let seed: Vec<Admin> = seed
+            .into_iter()
+            .map(|user_id| Admin {
+                user_id,
+                permission: Permission::All,
+            })
+            .collect();
+        let admins = match read(&*storage).await? {
+            Some((admins, _)) => {
+                info!(
+                    admins = admins.len(),
+                    "loaded admins from {}", ADMINS_OBJECT
+                );
+                admins
+            }
+            None if seed.is_empty() => seed,
+            None => {
+                match write(&*storage, &seed, None).await {
+                    Ok(()) => info!(admins = seed.len(), "seeded {}", ADMINS_OBJECT),
+                    // Another instance seeded it first; use theirs.
+                    Err(e) if e.is::<PreconditionFailed>() => {
+                        if let Some((admins, _)) = read(&*storage).await? {
+                            return Ok(Self::new(storage, admins));
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+                seed
+            }
+        };
+        Ok(Self::new(storage, admins))
+    }
+
+    fn new(storage: Arc<dyn Storage>, admins: Vec<Admin>) -> Self {
+        Self {
+            storage,
+            admins: RwLock::new(admins),
+        }
+    }
+
+    /// Picks up changes other instances made to the stored list.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        if let Some((admins, _)) = read(&*self.storage).await? {
+            *self.admins.write().unwrap() = admins;
+        }
+        Ok(())
+    }
+
+    /// What `user_id` may change; None when they aren't an admin.
+    pub fn permission(&self, user_id: &str) -> Option<Permission> {
+        self.admins
+            .read()
+            .unwrap()
+            .iter()
+            .find(|admin| admin.user_id == user_id)
+            .map(|admin| admin.permission.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.admins.read().unwrap().is_empty()
+    }
+
+    /// Admin user ids, in the order they were added.
+    pub fn list(&self) -> Vec<String> {
+        self.entries()
+            .into_iter()
+            .map(|admin| admin.user_id)
+            .collect()
+    }
+
+    pub fn entries(&self) -> Vec<Admin> {
+        self.admins.read().unwrap().clone()
+    }
+
+    /// Adds `user_id` with `*`. Returns false when it was already an admin.
+    pub async fn add(&self, user_id: &str) -> anyhow::Result<bool> {
+        self.edit(|admins| {
+            if admins.iter().any(|admin| admin.user_id == user_id) {
+                return false;
+            }
+            admins.push(Admin {
+                user_id: user_id.to_string(),
+                permission: Permission::All,
+            });
+            true
+        })
+        .await
+    }
+
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<Removal> {
+        self.edit(|admins| {
+            let Some(position) = admins.iter().position(|admin| admin.user_id == user_id) else {
+                return Removal::NotAdmin;
+            };
+            let others_with_all = admins
+                .iter()
+                .filter(|admin| admin.user_id != user_id && admin.permission == Permission::All)
+                .count();
+            if admins[position].permission == Permission::All && others_with_all == 0 {
+                return Removal::LastAdmin;
+            }
+            admins.remove(position);
+            Removal::Removed
+        })
+        .await
+    }
+
+    /// Lets `user_id` change the preset `key`, making them an admin limited
+    /// to it if they weren't one.
+    pub async fn grant(&self, user_id: &str, key: &str) -> anyhow::Result<Grant> {
+        self.edit(|admins| {
+            let Some(admin) = admins.iter_mut().find(|admin| admin.user_id == user_id) else {
+                admins.push(Admin {
+                    user_id: user_id.to_string(),
+                    permission: Permission::Presets(vec![key.to_string()]),
+                });
+                return Grant::Granted;
+            };
+            match &mut admin.permission {
+                Permission::All => Grant::AlreadyAll,
+                Permission::Presets(keys) if keys.iter().any(|k| k == key) => Grant::AlreadyGranted,
+                Permission::Presets(keys) => {
+                    keys.push(key.to_string());
+                    Grant::Granted
+                }
+            }
+        })
+        .await
+    }
+
+    /// Takes back a `grant`. The admin stays an admin even with no presets
+    /// left; `remove` drops them.
+    pub async fn revoke(&self, user_id: &str, key: &str) -> anyhow::Result<Revocation> {
+        self.edit(|admins| {
+            let Some(admin) = admins.iter_mut().find(|admin| admin.user_id == user_id) else {
+                return Revocation::NotAdmin;
+            };
+            match &mut admin.permission {
+                Permission::All => Revocation::HasAll,
+                Permission::Presets(keys) if keys.iter().any(|k| k == key) => {
+                    keys.retain(|k| k != key);
+                    Revocation::Revoked
+                }
+                Permission::Presets(_) => Revocation::NotGranted,
+            }
+        })
+        .await
+    }
+
+    /// Applies `change` to the stored list and saves it, only writing if
+    /// the object is still the one read so concurrent edits aren't lost.
+    async fn edit<T>(&self, change: impl Fn(&mut Vec<Admin>) -> T) -> anyhow::Result<T> {
+        let mut edited = None;
+        storage::update_conditionally(
+            &*self.storage,
+            ADMINS_OBJECT,
+            "application/json",
+            MAX_EDIT_ATTEMPTS,
+            |data| {
+                let mut admins = match data {
+                    Some(data) => parse(&data)?,
+                    None => self.entries(),
+                };
+                let before = admins.clone();
+                let result = change(&mut admins);
+                let data = if admins == before {
+                    None
+                } else {
+                    Some(serde_json::to_vec_pretty(&admins)?)
+                };
+                edited = Some((result, admins));
+                Ok(data)
+            },
+        )
+        .await?;
+        let (result, admins) = edited.expect("an edit that was written ran its change");
+        *self.admins.write().unwrap() = admins;
+        Ok(result)
+    }
+}
+
+async fn read(storage: &dyn Storage) -> anyhow::Result<Option<(Vec<Admin>, String)>> {
+    let Some((data, revision)) = storage.download_revision(ADMINS_OBJECT).await? else {
+        return Ok(None);
+    };
+    Ok(Some((parse(&data)?, revision)))
+}
+
+fn parse(data: &[u8]) -> anyhow::Result<Vec<Admin>> {
+    serde_json::from_slice(data).with_context(|| {
+        format!(
+            "{} must be a JSON array of user ids and {{\"userId\", \"presets\"}} objects",
+            ADMINS_OBJECT
+        )
+    })
+}
+
+async fn write(
+    storage: &dyn Storage,
+    admins: &[Admin],
+    revision: Option<&str>,
+) -> anyhow::Result<()> {
+    let data = serde_json::to_vec_pretty(admins)?;
+    storage
+        .upload_if(ADMINS_OBJECT, data, "application/json", revision)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const FIRST: &str = "Ufirst";
+    const SECOND: &str = "Usecond";
+
+    fn stored(storage: &MemoryStorage) -> serde_json::Value {
+        serde_json::from_slice(&storage.get(ADMINS_OBJECT).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_first_run_stores_the_seed_and_later_runs_ignore_it() {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage.clone(), vec![FIRST.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(admins.list(), [FIRST]);
+        assert_eq!(stored(&storage), serde_json::json!([FIRST]));
+
+        let admins = AdminStore::load(storage.clone(), vec![SECOND.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(admins.list(), [FIRST]);
+        assert_eq!(admins.permission(SECOND), None);
+    }
+
+    #[tokio::test]
+    async fn no_seed_stores_nothing() {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage.clone(), Vec::new()).await.unwrap();
+        assert!(admins.is_empty());
+        assert!(storage.get(ADMINS_OBJECT).is_none());
+    }
+
+    #[tokio::test]
+    async fn adds_and_removals_survive_a_restart() {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage.clone(), vec![FIRST.to_string()])
+            .await
+            .unwrap();
+        assert!(admins.add(SECOND).await.unwrap());
+        assert!(!admins.add(SECOND).await.unwrap());
+        assert_eq!(
+            admins.grant("Ulimited", "food1").await.unwrap(),
+            Grant::Granted
+        );
+
+        let reloaded = AdminStore::load(storage.clone(), Vec::new()).await.unwrap();
+        assert_eq!(reloaded.list(), [FIRST, SECOND, "Ulimited"]);
+        assert_eq!(
+            reloaded.permission("Ulimited"),
+            Some(Permission::Presets(vec!["food1".to_string()]))
+        );
+        assert_eq!(
+            stored(&storage),
+            serde_json::json!([FIRST, SECOND, {"userId": "Ulimited", "presets": ["food1"]}])
+        );
+
+        assert_eq!(admins.remove(FIRST).await.unwrap(), Removal::Removed);
+        assert_eq!(admins.remove(FIRST).await.unwrap(), Removal::NotAdmin);
+        let reloaded = AdminStore::load(storage, Vec::new()).await.unwrap();
+        assert_eq!(reloaded.list(), [SECOND, "Ulimited"]);
+    }
+
+    #[tokio::test]
+    async fn the_last_admin_with_every_permission_stays() {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage.clone(), vec![FIRST.to_string()])
+            .await
+            .unwrap();
+        admins.grant(SECOND, "food1").await.unwrap();
+        // A limited admin can't manage others, so doesn't count
+        assert_eq!(admins.remove(FIRST).await.unwrap(), Removal::LastAdmin);
+        assert_eq!(admins.remove(SECOND).await.unwrap(), Removal::Removed);
+        assert_eq!(admins.list(), [FIRST]);
+        assert_eq!(stored(&storage), serde_json::json!([FIRST]));
+    }
+
+    #[tokio::test]
+    async fn an_edit_made_elsewhere_is_kept() {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage.clone(), vec![FIRST.to_string()])
+            .await
+            .unwrap();
+        // Another instance added SECOND after this one loaded
+        storage.put(ADMINS_OBJECT, serde_json::to_vec(&[FIRST, SECOND]).unwrap());
+        assert!(admins.add("Uthird").await.unwrap());
+        assert_eq!(admins.list(), [FIRST, SECOND, "Uthird"]);
+
+        storage.fail_next(
+            "upload_if",
+            ADMINS_OBJECT,
+            PreconditionFailed {
+                object: ADMINS_OBJECT.to_string(),
+            },
+        );
+        assert_eq!(admins.remove("Uthird").await.unwrap(), Removal::Removed);
+        assert_eq!(stored(&storage), serde_json::json!([FIRST, SECOND]));
+    }
+
+    #[tokio::test]
+    async fn a_malformed_admin_list_fails_the_load() {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.put(
+            ADMINS_OBJECT,
+            br#"[{"userId": "U1", "presets": "food1"}]"#.to_vec(),
+        );
+        assert!(AdminStore::load(storage, Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::Context;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    Channel,
+    storage::{self, Storage},
+};
+
+/// Daily audit objects live under here as `YYYY-MM-DD.jsonl` (UTC dates).
+const AUDIT_DIR: &str = "audit";
+
+/// Appends retried when another instance wrote the same day's object
+/// between our read and write.
+const MAX_APPEND_ATTEMPTS: u32 = 5;
+
+/// How many daily objects `recent` looks back through.
+const LOOKBACK_DAYS: usize = 31;
+
+/// One preset change, as a line of the audit log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// RFC 3339, UTC.
+    pub at: String,
+    pub user_id: Option<String>,
+    pub key: String,
+    pub pending_id: String,
+    /// The temporary upload the change was made from.
+    pub source: String,
+    /// The versioned object now serving the preset.
+    pub object: String,
+    /// `object`'s GCS generation (or the backend's equivalent).
+    pub generation: Option<String>,
+}
+
+/// Appends `entry` to today's audit object. The object is only written if
+/// it is still the one read, so concurrent appends from other instances
+/// are retried rather than overwritten.
+pub async fn append(storage: &dyn Storage, channel: &Channel, entry: &Entry) -> anyhow::Result<()> {
+    let object = channel.object_path(&format!(
+        "{}/{}.jsonl",
+        AUDIT_DIR,
+        Utc::now().format("%Y-%m-%d")
+    ));
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    storage::update_conditionally(
+        storage,
+        &object,
+        "application/x-ndjson",
+        MAX_APPEND_ATTEMPTS,
+        |data| {
+            let mut data = data.unwrap_or_default();
+            data.extend_from_slice(&line);
+            Ok(Some(data))
+        },
+    )
+    .await
+}
+
+/// Up to `limit` of the latest changes to `key`, newest first.
+pub async fn recent(
+    storage: &dyn Storage,
+    channel: &Channel,
+    key: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<Entry>> {
+    let mut days: Vec<String> = storage
+        .list(&channel.object_path(&format!("{}/", AUDIT_DIR)))
+        .await?
+        .into_iter()
+        .map(|object| object.name)
+        .filter(|name| name.ends_with(".jsonl"))
+        .collect();
+    days.sort();
+
+    let mut entries = Vec::new();
+    for day in days.iter().rev().take(LOOKBACK_DAYS) {
+        let data = storage.download(day).await?;
+        let text = String::from_utf8(data).with_context(|| format!("{} is not UTF-8", day))?;
+        let mut matching: Vec<Entry> = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!(object = %day, "skipping malformed audit line: {}", e);
+                    None
+                }
+            })
+            .filter(|entry| entry.key == key)
+            .collect();
+        matching.reverse();
+        entries.extend(matching);
+        if entries.len() >= limit {
+            entries.truncate(limit);
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::PreconditionFailed, test_support::TestApp};
+
+    fn entry(key: &str, at: &str) -> Entry {
+        Entry {
+            at: at.to_string(),
+            user_id: Some("Uadmin".to_string()),
+            key: key.to_string(),
+            pending_id: "p".to_string(),
+            source: "uploads/p.jpg".to_string(),
+            object: format!("images/{}/1.jpg", key),
+            generation: Some("7".to_string()),
+        }
+    }
+
+    fn today() -> String {
+        format!("audit/{}.jsonl", Utc::now().format("%Y-%m-%d"))
+    }
+
+    #[tokio::test]
+    async fn a_conflicting_write_is_retried_on_the_new_contents() {
+        let app = TestApp::new().await;
+        let channel = app.channel();
+        append(
+            &*app.state.storage,
+            &channel,
+            &entry("food1", "2024-01-01T00:00:00Z"),
+        )
+        .await
+        .unwrap();
+        app.storage.fail_next(
+            "upload_if",
+            &today(),
+            PreconditionFailed { object: today() },
+        );
+        append(
+            &*app.state.storage,
+            &channel,
+            &entry("food2", "2024-01-01T00:01:00Z"),
+        )
+        .await
+        .unwrap();
+
+        let log = String::from_utf8(app.storage.get(&today()).unwrap()).unwrap();
+        let keys: Vec<String> = log
+            .lines()
+            .map(|line| serde_json::from_str::<Entry>(line).unwrap().key)
+            .collect();
+        assert_eq!(keys, ["food1", "food2"]);
+    }
+
+    #[tokio::test]
+    async fn appends_give_up_after_repeated_conflicts() {
+        let app = TestApp::new().await;
+        for _ in 0..MAX_APPEND_ATTEMPTS {
+            app.storage.fail_next(
+                "upload_if",
+                &today(),
+                PreconditionFailed { object: today() },
+            );
+        }
+        let result = append(
+            &*app.state.storage,
+            &app.channel(),
+            &entry("food1", "2024-01-01T00:00:00Z"),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(app.storage.get(&today()).is_none());
+    }
+
+    #[tokio::test]
+    async fn recent_lists_one_presets_changes_newest_first_across_days() {
+        let app = TestApp::new().await;
+        let lines = |entries: &[Entry]| {
+            entries
+                .iter()
+                .map(|e| serde_json::to_string(e).unwrap() + "\n")
+                .collect::<String>()
+                .into_bytes()
+        };
+        app.storage.put(
+            "audit/2024-01-01.jsonl",
+            lines(&[
+                entry("food1", "2024-01-01T00:00:00Z"),
+                entry("food2", "2024-01-01T00:01:00Z"),
+            ]),
+        );
+        let mut day_two = lines(&[
+            entry("food1", "2024-01-02T00:00:00Z"),
+            entry("food1", "2024-01-02T00:02:00Z"),
+        ]);
+        day_two.extend_from_slice(b"not json\n");
+        app.storage.put("audit/2024-01-02.jsonl", day_two);
+
+        let latest = recent(&*app.state.storage, &app.channel(), "food1", 2)
+            .await
+            .unwrap();
+        let at: Vec<&str> = latest.iter().map(|e| e.at.as_str()).collect();
+        assert_eq!(at, ["2024-01-02T00:02:00Z", "2024-01-02T00:00:00Z"]);
+
+        let all = recent(&*app.state.storage, &app.channel(), "food1", 10)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 3);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{info, warn};
+
+use crate::storage::Storage;
+
+/// Copies of what a preset showed before each update, kept under
+/// `backups/{key}/{timestamp}` so `undo` can put a wrong image right.
+pub struct PresetBackups {
+    storage: Arc<dyn Storage>,
+    /// How many backups of each preset to keep.
+    keep: usize,
+}
+
+impl PresetBackups {
+    pub fn new(storage: Arc<dyn Storage>, keep: usize) -> Self {
+        Self {
+            storage,
+            keep: keep.max(1),
+        }
+    }
+
+    /// Copies `current`, the object a preset is about to stop showing, into
+    /// `prefix` (the preset's `backups/{key}/`), then drops the oldest
+    /// backups beyond the limit. A preset that shows nothing yet has
+    /// nothing to back up.
+    pub async fn save(&self, prefix: &str, current: &str) -> anyhow::Result<()> {
+        if !self.storage.exists(current).await? {
+            return Ok(());
+        }
+        let backup = backup_path(prefix, current, SystemTime::now());
+        self.storage.copy(current, &backup).await?;
+        info!(object = %current, backup = %backup, "preset object backed up");
+
+        let mut backups = self.storage.list(prefix).await?;
+        // Backup names are millisecond timestamps of equal width, so name
+        // order is age order.
+        backups.sort_by(|a, b| b.name.cmp(&a.name));
+        for old in backups.iter().skip(self.keep) {
+            if let Err(e) = self.storage.delete(&old.name).await {
+                warn!(backup = %old.name, "failed to delete old backup: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The most recent backup in `prefix`, if there is one.
+    pub async fn latest(&self, prefix: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .storage
+            .list(prefix)
+            .await?
+            .into_iter()
+            .map(|backup| backup.name)
+            .max())
+    }
+}
+
+/// Where the backups of the preset `key` are kept.
+pub fn prefix(key: &str) -> String {
+    format!("backups/{}/", key)
+}
+
+/// `backups/food1/` + `images/food1/1700000000000.png` ->
+/// `backups/food1/1700000000123.png`
+fn backup_path(prefix: &str, current: &str, now: SystemTime) -> String {
+    let millis = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let extension = current
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.contains('/'))
+        .unwrap_or("jpg");
+    format!("{}{:013}.{}", prefix, millis, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const PREFIX: &str = "backups/food1/";
+
+    #[tokio::test]
+    async fn only_the_newest_backups_are_kept() {
+        let storage = Arc::new(MemoryStorage::default());
+        let backups = PresetBackups::new(storage.clone(), 2);
+        for content in ["one", "two", "three"] {
+            storage.put("images/food1.jpg", content.as_bytes().to_vec());
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            backups.save(PREFIX, "images/food1.jpg").await.unwrap();
+        }
+        let kept: Vec<_> = storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with(PREFIX))
+            .collect();
+        assert_eq!(kept.len(), 2);
+        let latest = backups.latest(PREFIX).await.unwrap().unwrap();
+        assert_eq!(latest, kept[1]);
+        assert_eq!(storage.get(&latest).unwrap(), b"three");
+        assert_eq!(storage.get(&kept[0]).unwrap(), b"two");
+    }
+
+    #[tokio::test]
+    async fn a_preset_without_an_image_has_nothing_to_back_up() {
+        let storage = Arc::new(MemoryStorage::default());
+        let backups = PresetBackups::new(storage.clone(), 2);
+        backups.save(PREFIX, "images/food1.jpg").await.unwrap();
+        assert_eq!(backups.latest(PREFIX).await.unwrap(), None);
+    }
+
+    #[test]
+    fn backups_keep_the_current_objects_format() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            backup_path(PREFIX, "images/food1/1700000000000.png", at),
+            "backups/food1/1700000000123.png"
+        );
+    }
+}
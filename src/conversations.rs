@@ -0,0 +1,389 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    UploadKind,
+    storage::{self, Storage},
+};
+
+/// Where conversations in progress are kept so a restart doesn't strand
+/// an admin halfway through binding an upload.
+pub const CONVERSATIONS_OBJECT: &str = "state/conversations.json";
+
+/// Merges retried when another instance wrote the stored conversations
+/// between our read and write.
+const MAX_MERGE_ATTEMPTS: u32 = 5;
+
+/// An upload an admin is being asked about.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveUpload {
+    pub pending_id: String,
+    pub kind: UploadKind,
+    /// Its image set and index there, when the set is bound one image at
+    /// a time.
+    pub step: Option<(String, usize)>,
+}
+
+/// Where an admin is in binding an upload.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum Conversation {
+    #[default]
+    Idle,
+    /// A mapping prompt is out; a tap or a typed preset name picks the
+    /// preset.
+    AwaitingTarget { upload: ActiveUpload },
+    /// The preset has been picked; a tap or a typed はい / いいえ answers
+    /// the overwrite confirmation.
+    #[serde(rename_all = "camelCase")]
+    AwaitingConfirm {
+        upload: ActiveUpload,
+        /// The preset's name as in the presets map.
+        target: String,
+    },
+}
+
+impl Conversation {
+    /// The upload the conversation is about, unless idle.
+    pub fn upload(&self) -> Option<&ActiveUpload> {
+        match self {
+            Self::Idle => None,
+            Self::AwaitingTarget { upload } | Self::AwaitingConfirm { upload, .. } => Some(upload),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    /// When the conversation last moved, in seconds since the epoch.
+    at: u64,
+    conversation: Conversation,
+}
+
+pub fn key(channel: &str, user_id: &str) -> String {
+    format!("{}/{}", channel, user_id)
+}
+
+/// Each admin's conversation, by channel and user id. Anything not idle
+/// lasts as long as the upload it is about would, and every move is
+/// merged into the stored copy, so instances running side by side keep
+/// each other's conversations; only the admin who moved is written.
+pub struct Conversations {
+    storage: Arc<dyn Storage>,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Keeps writes in the order of the moves they record.
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl Conversations {
+    /// Picks up the conversations stored before a restart, dropping those
+    /// that expired meanwhile. A missing or unreadable object starts
+    /// everyone idle.
+    pub async fn load(storage: Arc<dyn Storage>, ttl: Duration) -> Self {
+        let mut entries: HashMap<String, Entry> =
+            match storage.download_revision(CONVERSATIONS_OBJECT).await {
+                Ok(Some((data, _))) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                    warn!("ignoring unreadable {}: {}", CONVERSATIONS_OBJECT, e);
+                    HashMap::new()
+                }),
+                Ok(None) => HashMap::new(),
+                Err(e) => {
+                    warn!("failed to read {}: {:#}", CONVERSATIONS_OBJECT, e);
+                    HashMap::new()
+                }
+            };
+        let now = now_secs();
+        entries.retain(|_, entry| !expired(entry, ttl, now));
+        if !entries.is_empty() {
+            info!(
+                conversations = entries.len(),
+                "resumed conversations from {}", CONVERSATIONS_OBJECT
+            );
+        }
+        Self {
+            storage,
+            ttl,
+            entries: Mutex::new(entries),
+            write_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Conversation {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if !expired(entry, self.ttl, now_secs()) => entry.conversation.clone(),
+            Some(_) => {
+                entries.remove(key);
+                Conversation::Idle
+            }
+            None => Conversation::Idle,
+        }
+    }
+
+    /// Moves `key`'s conversation to `conversation`, replacing wherever it
+    /// was.
+    pub async fn set(&self, key: &str, conversation: Conversation) {
+        self.update(key, |entries| {
+            if conversation == Conversation::Idle {
+                entries.remove(key).is_some()
+            } else {
+                let entry = Entry {
+                    at: now_secs(),
+                    conversation,
+                };
+                entries.insert(key.to_string(), entry);
+                true
+            }
+        })
+        .await;
+    }
+
+    /// Ends `key`'s conversation if it is still about `pending_id`; one
+    /// about a newer upload carries on.
+    pub async fn finish(&self, key: &str, pending_id: &str) {
+        self.update(key, |entries| {
+            let current = entries
+                .get(key)
+                .and_then(|entry| entry.conversation.upload())
+                .is_some_and(|upload| upload.pending_id == pending_id);
+            current && entries.remove(key).is_some()
+        })
+        .await;
+    }
+
+    /// Drops expired conversations so admins who never answer don't pile
+    /// up. They're dropped on load too, so the stored copy can lag.
+    pub fn prune(&self) {
+        let now = now_secs();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| !expired(entry, self.ttl, now));
+    }
+
+    /// Applies `change` to `key`'s conversation, which says whether it
+    /// changed anything, and stores the result. A failed write is only
+    /// logged; the move still holds in memory.
+    async fn update(&self, key: &str, change: impl FnOnce(&mut HashMap<String, Entry>) -> bool) {
+        let _write = self.write_lock.lock().await;
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            if !change(&mut entries) {
+                return;
+            }
+            entries.get(key).cloned()
+        };
+        if let Err(e) = self.merge_into_stored(key, entry).await {
+            warn!("failed to store {}: {:#}", CONVERSATIONS_OBJECT, e);
+        }
+    }
+
+    /// Writes `key`'s conversation into the stored ones, only writing if
+    /// they're still the version read so another instance's moves aren't
+    /// lost. Expired conversations are dropped on the way.
+    async fn merge_into_stored(&self, key: &str, entry: Option<Entry>) -> anyhow::Result<()> {
+        storage::update_conditionally(
+            &*self.storage,
+            CONVERSATIONS_OBJECT,
+            "application/json",
+            MAX_MERGE_ATTEMPTS,
+            |data| {
+                let mut stored = match data {
+                    Some(data) => serde_json::from_slice::<HashMap<String, Entry>>(&data)
+                        .unwrap_or_else(|e| {
+                            warn!("replacing unreadable {}: {}", CONVERSATIONS_OBJECT, e);
+                            HashMap::new()
+                        }),
+                    None => HashMap::new(),
+                };
+                let now = now_secs();
+                stored.retain(|_, entry| !expired(entry, self.ttl, now));
+                match &entry {
+                    Some(entry) => stored.insert(key.to_string(), entry.clone()),
+                    None => stored.remove(key),
+                };
+                Ok(Some(serde_json::to_vec(&stored)?))
+            },
+        )
+        .await
+    }
+}
+
+fn expired(entry: &Entry, ttl: Duration, now: u64) -> bool {
+    now.saturating_sub(entry.at) >= ttl.as_secs()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, PreconditionFailed};
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    fn upload(pending_id: &str) -> ActiveUpload {
+        ActiveUpload {
+            pending_id: pending_id.to_string(),
+            kind: UploadKind::Image,
+            step: None,
+        }
+    }
+
+    fn awaiting_target(pending_id: &str) -> Conversation {
+        Conversation::AwaitingTarget {
+            upload: upload(pending_id),
+        }
+    }
+
+    async fn load(storage: &Arc<MemoryStorage>) -> Conversations {
+        Conversations::load(storage.clone(), TTL).await
+    }
+
+    fn stored(storage: &MemoryStorage) -> HashMap<String, Entry> {
+        serde_json::from_slice(&storage.get(CONVERSATIONS_OBJECT).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn conversations_move_through_each_state() {
+        let storage = Arc::new(MemoryStorage::default());
+        let conversations = load(&storage).await;
+        let key = key("shop", "Uadmin");
+        assert_eq!(conversations.get(&key), Conversation::Idle);
+
+        conversations.set(&key, awaiting_target("p1")).await;
+        assert_eq!(conversations.get(&key), awaiting_target("p1"));
+        let confirm = Conversation::AwaitingConfirm {
+            upload: upload("p1"),
+            target: "食べ物メニュー".to_string(),
+        };
+        conversations.set(&key, confirm.clone()).await;
+        assert_eq!(conversations.get(&key), confirm);
+        assert_eq!(stored(&storage)[&key].conversation, confirm);
+
+        // Finishing another upload leaves this one be
+        conversations.finish(&key, "p0").await;
+        assert_eq!(conversations.get(&key), confirm);
+        conversations.finish(&key, "p1").await;
+        assert_eq!(conversations.get(&key), Conversation::Idle);
+        assert!(stored(&storage).is_empty());
+
+        // A newer upload takes over, and setting idle ends it
+        conversations.set(&key, awaiting_target("p2")).await;
+        conversations.set(&key, awaiting_target("p3")).await;
+        conversations.finish(&key, "p2").await;
+        assert_eq!(conversations.get(&key), awaiting_target("p3"));
+        conversations.set(&key, Conversation::Idle).await;
+        assert_eq!(conversations.get(&key), Conversation::Idle);
+        assert!(stored(&storage).is_empty());
+    }
+
+    #[tokio::test]
+    async fn conversations_expire_after_the_ttl() {
+        let storage = Arc::new(MemoryStorage::default());
+        let conversations = load(&storage).await;
+        for user in ["Ua", "Ub"] {
+            conversations
+                .set(&key("shop", user), awaiting_target(user))
+                .await;
+        }
+        let backdate = |user: &str| {
+            conversations
+                .entries
+                .lock()
+                .unwrap()
+                .get_mut(&key("shop", user))
+                .unwrap()
+                .at -= TTL.as_secs();
+        };
+        backdate("Ua");
+        assert_eq!(conversations.get(&key("shop", "Ua")), Conversation::Idle);
+        backdate("Ub");
+        conversations.prune();
+        assert!(conversations.entries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_restart_picks_up_unexpired_conversations() {
+        let storage = Arc::new(MemoryStorage::default());
+        let before = load(&storage).await;
+        before.set(&key("shop", "Ua"), awaiting_target("p1")).await;
+
+        let after = load(&storage).await;
+        assert_eq!(after.get(&key("shop", "Ua")), awaiting_target("p1"));
+
+        let mut entries = stored(&storage);
+        entries.get_mut(&key("shop", "Ua")).unwrap().at -= TTL.as_secs();
+        storage.put(CONVERSATIONS_OBJECT, serde_json::to_vec(&entries).unwrap());
+        let after = load(&storage).await;
+        assert_eq!(after.get(&key("shop", "Ua")), Conversation::Idle);
+
+        // An unreadable copy starts everyone idle and is replaced on the
+        // next move
+        storage.put(CONVERSATIONS_OBJECT, b"not json".to_vec());
+        let after = load(&storage).await;
+        assert!(after.entries.lock().unwrap().is_empty());
+        after.set(&key("shop", "Ub"), awaiting_target("p2")).await;
+        assert_eq!(stored(&storage).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn instances_keep_each_others_conversations() {
+        let storage = Arc::new(MemoryStorage::default());
+        let (a, b) = (load(&storage).await, load(&storage).await);
+        a.set(&key("shop", "Ua"), awaiting_target("p1")).await;
+        b.set(&key("shop", "Ub"), awaiting_target("p2")).await;
+        b.finish(&key("shop", "Ub"), "p2").await;
+        b.set(&key("shop", "Uc"), awaiting_target("p3")).await;
+
+        let restarted = load(&storage).await;
+        assert_eq!(restarted.get(&key("shop", "Ua")), awaiting_target("p1"));
+        assert_eq!(restarted.get(&key("shop", "Ub")), Conversation::Idle);
+        assert_eq!(restarted.get(&key("shop", "Uc")), awaiting_target("p3"));
+    }
+
+    #[tokio::test]
+    async fn a_conflicting_write_is_merged_again() {
+        let storage = Arc::new(MemoryStorage::default());
+        let conversations = load(&storage).await;
+        storage.fail_next(
+            "upload_if",
+            CONVERSATIONS_OBJECT,
+            PreconditionFailed {
+                object: CONVERSATIONS_OBJECT.to_string(),
+            },
+        );
+        conversations
+            .set(&key("shop", "Ua"), awaiting_target("p1"))
+            .await;
+        assert_eq!(
+            stored(&storage)[&key("shop", "Ua")].conversation,
+            awaiting_target("p1")
+        );
+
+        // A write that fails outright still holds in memory
+        storage.fail_next(
+            "upload_if",
+            CONVERSATIONS_OBJECT,
+            anyhow::anyhow!("storage is down"),
+        );
+        conversations
+            .set(&key("shop", "Ub"), awaiting_target("p2"))
+            .await;
+        assert_eq!(conversations.get(&key("shop", "Ub")), awaiting_target("p2"));
+        assert_eq!(stored(&storage).len(), 1);
+    }
+}
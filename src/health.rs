@@ -0,0 +1,130 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::{AppState, versions};
+
+/// How long a readiness result is reused, so frequent probes don't turn
+/// into a stream of LINE and storage calls.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Longest a single dependency check may take before it counts as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of one round of dependency checks.
+#[derive(Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Dependency name -> `{"ok": bool}`. Error details only go to the log,
+    /// since the probe is unauthenticated.
+    pub checks: Map<String, Value>,
+}
+
+#[derive(Default)]
+pub struct ReadinessCache(Mutex<Option<(Instant, Readiness)>>);
+
+impl ReadinessCache {
+    /// The latest readiness, checking again once the cached one is stale.
+    /// Concurrent probes during a check may each run their own; that's
+    /// cheaper than making them wait on a lock across the network calls.
+    pub async fn get(&self, state: &AppState) -> Readiness {
+        if let Some((checked_at, readiness)) = &*self.0.lock().unwrap()
+            && checked_at.elapsed() < CACHE_TTL
+        {
+            return readiness.clone();
+        }
+        let readiness = check(state).await;
+        *self.0.lock().unwrap() = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
+
+async fn check(state: &AppState) -> Readiness {
+    let mut checks = Map::new();
+    // Present or not, a stat proves the credentials and bucket work.
+    let storage =
+        with_timeout(async { state.storage.stat(versions::STATE_OBJECT).await.map(|_| ()) }).await;
+    record(&mut checks, "storage".to_string(), storage);
+    for channel in state.channels.iter() {
+        let line = with_timeout(async { channel.line.get_bot_info().await.map(|_| ()) }).await;
+        record(&mut checks, format!("line:{}", channel.name), line);
+    }
+    Readiness {
+        ready: checks.values().all(|check| check["ok"] == true),
+        checks,
+    }
+}
+
+async fn with_timeout(check: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", CHECK_TIMEOUT)))
+}
+
+fn record(checks: &mut Map<String, Value>, name: String, result: anyhow::Result<()>) {
+    if let Err(e) = &result {
+        warn!(dependency = %name, "readiness check failed: {:#}", e);
+    }
+    checks.insert(name, serde_json::json!({ "ok": result.is_ok() }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{Scripted, TestApp};
+
+    const BOT_INFO: &str = r#"{"userId":"Ubot","basicId":"@nanaharu","displayName":"ななはる"}"#;
+
+    #[tokio::test]
+    async fn ready_when_storage_and_line_answer() {
+        let app = TestApp::new().await;
+        app.line
+            .respond("/v2/bot/info", Scripted::new(200, BOT_INFO));
+        let readiness = app.state.readiness.get(&app.state).await;
+        assert!(readiness.ready);
+        assert_eq!(readiness.checks["storage"]["ok"], true);
+        assert_eq!(readiness.checks["line:default"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn storage_failures_are_named() {
+        let app = TestApp::new().await;
+        app.line
+            .respond("/v2/bot/info", Scripted::new(200, BOT_INFO));
+        app.storage
+            .fail_next("stat", versions::STATE_OBJECT, anyhow::anyhow!("403"));
+        let readiness = app.state.readiness.get(&app.state).await;
+        assert!(!readiness.ready);
+        assert_eq!(readiness.checks["storage"]["ok"], false);
+        assert_eq!(readiness.checks["line:default"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn line_failures_are_named() {
+        let app = TestApp::new().await;
+        app.line.respond(
+            "/v2/bot/info",
+            Scripted::new(401, r#"{"message":"Authentication failed"}"#),
+        );
+        let readiness = app.state.readiness.get(&app.state).await;
+        assert!(!readiness.ready);
+        assert_eq!(readiness.checks["storage"]["ok"], true);
+        assert_eq!(readiness.checks["line:default"]["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn results_are_reused_until_stale() {
+        let app = TestApp::new().await;
+        app.line
+            .respond("/v2/bot/info", Scripted::new(200, BOT_INFO));
+        assert!(app.state.readiness.get(&app.state).await.ready);
+        // LINE would now fail, but the cached result still stands.
+        assert!(app.state.readiness.get(&app.state).await.ready);
+        assert_eq!(app.line.to("/v2/bot/info").len(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+
+/// An uploaded image that arrived as part of a set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetImage {
+    pub pending_id: String,
+    /// When the upload arrived, as shown to the admin.
+    pub uploaded_at: String,
+    /// Only needed while the set is buffered; it's looked up again when a
+    /// stored set is walked through.
+    #[serde(skip)]
+    pub preview_url: Option<String>,
+    /// The format the image was converted to JPEG from, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub converted_from: Option<String>,
+}
+
+/// What `ImageSets::add` did with an image.
+pub enum Added {
+    /// The set is complete; its images in index order.
+    Complete(Vec<SetImage>),
+    /// The first image of a new set.
+    Started,
+    /// More of a set already being buffered.
+    Waiting,
+}
+
+/// Images sent together with others, held until the whole set has been
+/// uploaded so it can be offered as one prompt.
+#[derive(Default)]
+pub struct ImageSets {
+    sets: Mutex<HashMap<String, Partial>>,
+}
+
+struct Partial {
+    total: u32,
+    images: BTreeMap<u32, SetImage>,
+}
+
+impl ImageSets {
+    /// Buffers image `index` of the `total` in set `key`.
+    pub fn add(&self, key: &str, index: u32, total: u32, image: SetImage) -> Added {
+        let mut sets = self.sets.lock().unwrap();
+        let started = !sets.contains_key(key);
+        let partial = sets.entry(key.to_string()).or_insert_with(|| Partial {
+            total,
+            images: BTreeMap::new(),
+        });
+        // A redelivered event replaces its own earlier copy.
+        partial.images.insert(index, image);
+        if partial.images.len() as u32 >= partial.total {
+            let partial = sets.remove(key).unwrap();
+            return Added::Complete(partial.images.into_values().collect());
+        }
+        if started {
+            Added::Started
+        } else {
+            Added::Waiting
+        }
+    }
+
+    /// Gives up waiting for set `key`, returning whatever of it arrived, in
+    /// index order. None when it was completed in the meantime.
+    pub fn take(&self, key: &str) -> Option<Vec<SetImage>> {
+        let partial = self.sets.lock().unwrap().remove(key)?;
+        Some(partial.images.into_values().collect())
+    }
+}
+
+/// Where a completed set's images are listed, in the order they're offered.
+/// Under `uploads/` so the upload cleanup expires it with its images.
+pub fn manifest_object(set_id: &str) -> String {
+    format!("uploads/sets/{}.json", set_id)
+}
+
+pub async fn save(storage: &dyn Storage, object: &str, images: &[SetImage]) -> anyhow::Result<()> {
+    let data = serde_json::to_vec(images)?;
+    storage.upload(object, data, "application/json").await
+}
+
+pub async fn load(storage: &dyn Storage, object: &str) -> anyhow::Result<Option<Vec<SetImage>>> {
+    let Some((data, _)) = storage.download_revision(object).await? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn image(pending_id: &str) -> SetImage {
+        SetImage {
+            pending_id: pending_id.to_string(),
+            uploaded_at: "12:00".to_string(),
+            preview_url: None,
+            converted_from: None,
+        }
+    }
+
+    fn pending_ids(images: &[SetImage]) -> Vec<&str> {
+        images
+            .iter()
+            .map(|image| image.pending_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn a_set_completes_in_index_order_whatever_order_it_arrives_in() {
+        let sets = ImageSets::default();
+        assert!(matches!(sets.add("a", 3, 3, image("p3")), Added::Started));
+        assert!(matches!(sets.add("a", 1, 3, image("p1")), Added::Waiting));
+        let Added::Complete(images) = sets.add("a", 2, 3, image("p2")) else {
+            panic!("set should be complete");
+        };
+        assert_eq!(pending_ids(&images), ["p1", "p2", "p3"]);
+        // Completed sets are forgotten
+        assert!(sets.take("a").is_none());
+    }
+
+    #[test]
+    fn a_redelivered_image_does_not_count_twice() {
+        let sets = ImageSets::default();
+        sets.add("a", 1, 2, image("p1"));
+        assert!(matches!(
+            sets.add("a", 1, 2, image("p1-again")),
+            Added::Waiting
+        ));
+        let Added::Complete(images) = sets.add("a", 2, 2, image("p2")) else {
+            panic!("set should be complete");
+        };
+        assert_eq!(pending_ids(&images), ["p1-again", "p2"]);
+    }
+
+    #[test]
+    fn sets_are_kept_apart_and_a_partial_one_can_be_taken() {
+        let sets = ImageSets::default();
+        sets.add("a", 2, 3, image("a2"));
+        sets.add("b", 1, 2, image("b1"));
+        sets.add("a", 1, 3, image("a1"));
+        assert_eq!(pending_ids(&sets.take("a").unwrap()), ["a1", "a2"]);
+        assert!(sets.take("a").is_none());
+        assert!(matches!(
+            sets.add("b", 2, 2, image("b2")),
+            Added::Complete(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn manifests_round_trip_without_preview_urls() {
+        let storage = MemoryStorage::default();
+        let object = manifest_object("set");
+        assert!(load(&storage, &object).await.unwrap().is_none());
+        let mut first = image("p1");
+        first.preview_url = Some("https://storage.test/uploads/p1.jpg".to_string());
+        save(&storage, &object, &[first, image("p2")])
+            .await
+            .unwrap();
+        let loaded = load(&storage, &object).await.unwrap().unwrap();
+        assert_eq!(pending_ids(&loaded), ["p1", "p2"]);
+        assert!(loaded[0].preview_url.is_none());
+        assert_eq!(object, "uploads/sets/set.json");
+    }
+}
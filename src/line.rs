@@ -0,0 +1,1469 @@
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::Rng;
+use reqwest::{
+    RequestBuilder, Response, StatusCode,
+    header::{CONTENT_TYPE, RETRY_AFTER},
+};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::metrics::Metrics;
+
+pub const DEFAULT_API_BASE: &str = "https://api.line.me";
+pub const DEFAULT_DATA_API_BASE: &str = "https://api-data.line.me";
+
+/// How failed LINE API calls are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    /// Upper bound on the time spent across all attempts. Reply tokens
+    /// expire roughly 30 seconds after the event, so this stays well below.
+    max_total: Duration,
+    /// Longest Retry-After we are willing to honor on a 429.
+    max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_total: Duration::from_secs(20),
+            max_retry_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    /// Retries without waiting in between, so tests don't sleep.
+    #[cfg(test)]
+    pub fn immediate() -> Self {
+        Self {
+            base_delay: Duration::ZERO,
+            ..Self::default()
+        }
+    }
+
+    /// Exponential backoff for the given (1-based) attempt, plus up to 50% jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay * 2u32.saturating_pow(attempt - 1);
+        let jitter = rand::rng().random_range(0..=base.as_millis() as u64 / 2);
+        base + Duration::from_millis(jitter)
+    }
+}
+
+/// Consecutive 401s after which we assume the access token itself is bad.
+const UNAUTHORIZED_ALERT_THRESHOLD: u32 = 3;
+
+/// A non-success response from the Messaging API, decoded from LINE's
+/// JSON error body where possible.
+#[derive(Debug)]
+pub struct LineApiError {
+    pub status: StatusCode,
+    pub code: Option<String>,
+    pub message: String,
+    pub details: Vec<LineApiErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LineApiErrorDetail {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub property: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LineApiErrorBody {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    details: Vec<LineApiErrorDetail>,
+}
+
+impl LineApiError {
+    async fn from_response(resp: Response) -> Self {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        match serde_json::from_str::<LineApiErrorBody>(&text) {
+            Ok(body) => Self {
+                status,
+                code: body.code,
+                message: body.message,
+                details: body.details,
+            },
+            Err(_) => Self {
+                status,
+                code: None,
+                message: text,
+                details: Vec::new(),
+            },
+        }
+    }
+
+    /// LINE's response when a reply token has expired or was already used.
+    pub fn is_invalid_reply_token(&self) -> bool {
+        self.status == StatusCode::BAD_REQUEST && self.message == "Invalid reply token"
+    }
+
+    /// Whether sending the same request again could plausibly succeed.
+    pub fn is_retryable(&self) -> bool {
+        is_retryable_status(self.status)
+    }
+}
+
+impl fmt::Display for LineApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LINE API error {}", self.status)?;
+        if let Some(code) = &self.code {
+            write!(f, " ({})", code)?;
+        }
+        write!(f, ": {}", self.message)?;
+        for detail in &self.details {
+            match &detail.property {
+                Some(property) => write!(f, "; {}: {}", property, detail.message)?,
+                None => write!(f, "; {}", detail.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LineApiError {}
+
+/// Reply tokens are only valid for a short while after the event occurred.
+pub const REPLY_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// LINE accepts at most this many messages in one reply or push.
+pub const MAX_MESSAGES_PER_REQUEST: usize = 5;
+
+/// LINE rejects text messages longer than this many characters.
+pub const MAX_TEXT_CHARS: usize = 5000;
+
+/// Appended to text cut short by `text_messages`.
+const TRUNCATED_NOTE: &str = "\n…（以下省略）";
+
+/// LINE accepts at most this many recipients per multicast request.
+pub const MULTICAST_MAX_RECIPIENTS: usize = 500;
+
+/// How often to ask whether a video or audio message is ready to download.
+const TRANSCODING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct TranscodingStatus {
+    status: String,
+}
+
+/// A user's public profile as returned by GET /v2/bot/profile/{userId}.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub display_name: String,
+    #[serde(default)]
+    pub picture_url: Option<String>,
+    #[serde(default)]
+    pub status_message: Option<String>,
+}
+
+/// The bot's own account, from GET /v2/bot/info.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BotInfo {
+    pub user_id: String,
+    pub basic_id: String,
+    pub display_name: String,
+}
+
+/// The monthly message limit, from GET /v2/bot/message/quota.
+#[derive(Debug, Deserialize)]
+pub struct Quota {
+    /// "none" when the plan has no limit, "limited" otherwise.
+    #[serde(rename = "type")]
+    pub r#type: String,
+    /// The limit, present only for "limited".
+    #[serde(default)]
+    pub value: Option<u64>,
+}
+
+/// Messages sent this month, from GET /v2/bot/message/quota/consumption.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaConsumption {
+    pub total_usage: u64,
+}
+
+/// LINE rejects sender names longer than this many characters.
+const MAX_SENDER_NAME_CHARS: usize = 20;
+
+/// The name and icon a message appears to come from, in place of the
+/// bot's own profile.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sender {
+    pub name: String,
+    #[serde(default)]
+    pub icon_url: Option<String>,
+}
+
+impl Sender {
+    /// Truncates a name LINE would reject rather than failing every reply.
+    pub fn validated(mut self) -> Self {
+        if self.name.chars().count() > MAX_SENDER_NAME_CHARS {
+            warn!(
+                name = %self.name,
+                "sender name is longer than {} characters; truncating",
+                MAX_SENDER_NAME_CHARS
+            );
+            self.name = self.name.chars().take(MAX_SENDER_NAME_CHARS).collect();
+        }
+        self
+    }
+}
+
+/// The retry key for chunk `index` of a multicast sent under `key`: the
+/// key itself for the first chunk, and for later ones another UUID that
+/// depends only on the key and the index.
+fn chunk_retry_key(key: Uuid, index: usize) -> Uuid {
+    if index == 0 {
+        return key;
+    }
+    let mut bytes = *key.as_bytes();
+    let tail = u32::from_be_bytes(bytes[12..].try_into().unwrap());
+    bytes[12..].copy_from_slice(&tail.wrapping_add(index as u32).to_be_bytes());
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// Outcome of one request within a chunked multicast.
+pub struct MulticastChunk {
+    pub recipients: usize,
+    pub result: anyhow::Result<()>,
+}
+
+/// Where a reply goes: the event's reply token, plus the chat to push to
+/// instead when the token can't be used in time.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplyTarget<'a> {
+    pub reply_token: &'a str,
+    pub push_to: Option<&'a str>,
+    /// When the reply token stops being accepted, if the event's age is known.
+    pub expires_at: Option<Instant>,
+    /// Deliver without a push notification, for messages the recipient is
+    /// already expecting.
+    pub notification_disabled: bool,
+}
+
+impl ReplyTarget<'_> {
+    pub fn silent(self, notification_disabled: bool) -> Self {
+        Self {
+            notification_disabled,
+            ..self
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+/// Messaging API client for a single channel.
+#[derive(Clone)]
+pub struct LineClient {
+    http: reqwest::Client,
+    token: String,
+    api_base: String,
+    data_api_base: String,
+    content_timeout: Duration,
+    retry: RetryPolicy,
+    metrics: Arc<Metrics>,
+    unauthorized_streak: Arc<AtomicU32>,
+}
+
+impl LineClient {
+    pub fn new(
+        http: reqwest::Client,
+        token: String,
+        content_timeout: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            http,
+            token,
+            api_base: DEFAULT_API_BASE.to_string(),
+            data_api_base: DEFAULT_DATA_API_BASE.to_string(),
+            content_timeout,
+            retry: RetryPolicy::default(),
+            metrics,
+            unauthorized_streak: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Points the client at different API hosts, e.g. a mock server.
+    pub fn with_base_urls(mut self, api_base: &str, data_api_base: &str) -> Self {
+        self.api_base = api_base.trim_end_matches('/').to_string();
+        self.data_api_base = data_api_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Replies with `text`, split across several messages when it is too
+    /// long for one. Empty text is not sent at all, since LINE rejects it.
+    pub async fn reply_text(&self, target: ReplyTarget<'_>, text: &str) -> anyhow::Result<()> {
+        let messages = text_messages(text);
+        if messages.is_empty() {
+            warn!("not sending an empty text reply");
+            return Ok(());
+        }
+        self.reply_messages(target, messages).await
+    }
+
+    pub async fn reply_sticker(
+        &self,
+        target: ReplyTarget<'_>,
+        package_id: &str,
+        sticker_id: &str,
+        sender: Option<&Sender>,
+    ) -> anyhow::Result<()> {
+        let message = sticker_message(package_id, sticker_id);
+        self.reply_messages(target, vec![with_sender(message, sender)])
+            .await
+    }
+
+    pub async fn reply_template(
+        &self,
+        target: ReplyTarget<'_>,
+        alt_text: &str,
+        template: Value,
+    ) -> anyhow::Result<()> {
+        let message = serde_json::json!({
+            "type": "template",
+            "altText": alt_text,
+            "template": template,
+        });
+        self.reply_messages(target, vec![message]).await
+    }
+
+    /// Sends up to MAX_MESSAGES_PER_REQUEST messages in a single reply.
+    /// LINE honors only one reply per token, so everything meant for an
+    /// event has to go out together.
+    pub async fn reply_messages(
+        &self,
+        target: ReplyTarget<'_>,
+        messages: Vec<Value>,
+    ) -> anyhow::Result<()> {
+        if messages.is_empty() || messages.len() > MAX_MESSAGES_PER_REQUEST {
+            anyhow::bail!(
+                "a reply must contain 1 to {} messages, got {}",
+                MAX_MESSAGES_PER_REQUEST,
+                messages.len()
+            );
+        }
+        let kind = message_kind(&messages);
+
+        // Slow flows (content download, GCS upload) can outlive the token;
+        // don't spend a request on a reply LINE is certain to reject.
+        if target.is_expired()
+            && let Some(to) = target.push_to
+        {
+            info!(kind, "reply token expired; sending as push");
+            return self
+                .push_messages(to, messages, target.notification_disabled, None)
+                .await;
+        }
+
+        let mut body = serde_json::json!({
+            "replyToken": target.reply_token,
+            "messages": messages,
+        });
+        if target.notification_disabled {
+            body["notificationDisabled"] = true.into();
+        }
+
+        let url = format!("{}/v2/bot/message/reply", self.api_base);
+        let started = Instant::now();
+        let resp = self
+            .send_with_retry("LINE reply", || {
+                self.http.post(&url).bearer_auth(&self.token).json(&body)
+            })
+            .await
+            .inspect_err(|_| {
+                self.metrics.replies_failed.inc(kind);
+            })?;
+        self.metrics.reply_latency.observe(started.elapsed());
+
+        // Still rate limited once the reply token's budget is spent: wait
+        // out Retry-After and push instead, since pushes don't expire.
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS
+            && let Some(to) = target.push_to
+        {
+            let wait = retry_after(&resp)
+                .unwrap_or(self.retry.base_delay)
+                .min(self.retry.max_retry_after);
+            warn!(kind, ?wait, "reply rate limited; falling back to push");
+            tokio::time::sleep(wait).await;
+            return self
+                .push_messages(to, messages, target.notification_disabled, None)
+                .await;
+        }
+
+        match self.check(resp).await {
+            Ok(_) => {
+                self.metrics.replies_sent.inc(kind);
+                info!(kind, "sent reply to LINE");
+                Ok(())
+            }
+            Err(e) if e.is_invalid_reply_token() && target.push_to.is_some() => {
+                warn!(kind, "reply token rejected; falling back to push");
+                self.push_messages(
+                    target.push_to.unwrap_or_default(),
+                    messages,
+                    target.notification_disabled,
+                    None,
+                )
+                .await
+            }
+            Err(e) => {
+                self.metrics.replies_failed.inc(kind);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Pushes messages to one chat. Passing the same `retry_key` for a
+    /// repeated send makes LINE deliver it only once; None picks a new key.
+    pub async fn push_messages(
+        &self,
+        to: &str,
+        messages: Vec<Value>,
+        notification_disabled: bool,
+        retry_key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut body = serde_json::json!({
+            "to": to,
+            "messages": messages,
+        });
+        if notification_disabled {
+            body["notificationDisabled"] = true.into();
+        }
+        self.post_messages("push", body, retry_key).await
+    }
+
+    /// Sends messages to every follower. `retry_key` works as for
+    /// `push_messages`.
+    pub async fn broadcast(
+        &self,
+        messages: Vec<Value>,
+        retry_key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "messages": messages,
+        });
+        self.post_messages("broadcast", body, retry_key).await
+    }
+
+    /// Sends the same messages to many users, split into requests of at
+    /// most MULTICAST_MAX_RECIPIENTS. Every chunk is attempted even if an
+    /// earlier one fails. `retry_key` stands for the whole send; each chunk
+    /// goes out under a key derived from it, so repeating the send skips
+    /// the chunks LINE already accepted.
+    pub async fn multicast(
+        &self,
+        to: &[String],
+        messages: Vec<Value>,
+        retry_key: Option<&str>,
+    ) -> Vec<MulticastChunk> {
+        let retry_key = retry_key.and_then(|key| {
+            Uuid::parse_str(key)
+                .inspect_err(|_| warn!("multicast retry key is not a UUID; ignoring it"))
+                .ok()
+        });
+        let mut chunks = Vec::new();
+        for (i, recipients) in to.chunks(MULTICAST_MAX_RECIPIENTS).enumerate() {
+            let body = serde_json::json!({
+                "to": recipients,
+                "messages": messages,
+            });
+            let chunk_key = retry_key.map(|key| chunk_retry_key(key, i).to_string());
+            chunks.push(MulticastChunk {
+                recipients: recipients.len(),
+                result: self
+                    .post_messages("multicast", body, chunk_key.as_deref())
+                    .await,
+            });
+        }
+        chunks
+    }
+
+    /// Posts to one of the /v2/bot/message/{kind} endpoints that take a
+    /// full message body rather than a reply token.
+    async fn post_messages(
+        &self,
+        kind: &str,
+        body: Value,
+        retry_key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        // One key per logical send, reused across retries, so LINE delivers
+        // the message at most once even if a response is lost.
+        let retry_key = retry_key
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let url = format!("{}/v2/bot/message/{}", self.api_base, kind);
+        let resp = self
+            .send_with_retry("LINE send", || {
+                self.http
+                    .post(&url)
+                    .bearer_auth(&self.token)
+                    .header("X-Line-Retry-Key", &retry_key)
+                    .json(&body)
+            })
+            .await
+            .inspect_err(|_| {
+                self.metrics.replies_failed.inc(kind);
+            })?;
+
+        // 409 means LINE already accepted a request with this retry key.
+        if resp.status() == StatusCode::CONFLICT {
+            info!(kind, "LINE already accepted this send; not sending again");
+            return Ok(());
+        }
+        self.check(resp).await.inspect_err(|_| {
+            self.metrics.replies_failed.inc(kind);
+        })?;
+        self.metrics.replies_sent.inc(kind);
+        info!(kind, "sent messages to LINE");
+        Ok(())
+    }
+
+    /// Shows the typing indicator in a one-on-one chat for up to
+    /// `seconds` (a multiple of 5, at most 60), or until we next send.
+    pub async fn start_loading(&self, chat_id: &str, seconds: u32) -> anyhow::Result<()> {
+        let url = format!("{}/v2/bot/chat/loading/start", self.api_base);
+        let body = serde_json::json!({
+            "chatId": chat_id,
+            "loadingSeconds": seconds,
+        });
+        let resp = self
+            .send_with_retry("LINE loading animation", || {
+                self.http.post(&url).bearer_auth(&self.token).json(&body)
+            })
+            .await?;
+        self.check(resp).await?;
+        Ok(())
+    }
+
+    /// Fetches a user's profile. Returns None for users LINE won't describe,
+    /// typically because they have blocked the bot.
+    pub async fn get_profile(&self, user_id: &str) -> anyhow::Result<Option<Profile>> {
+        let url = format!("{}/v2/bot/profile/{}", self.api_base, user_id);
+        let resp = self
+            .send_with_retry("LINE profile", || {
+                self.http.get(&url).bearer_auth(&self.token)
+            })
+            .await?;
+        match self.check(resp).await {
+            Ok(resp) => Ok(Some(
+                resp.json()
+                    .await
+                    .map_err(|e| http_error("LINE profile", e))?,
+            )),
+            Err(e) if e.status == StatusCode::NOT_FOUND => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn get_bot_info(&self) -> anyhow::Result<BotInfo> {
+        let url = format!("{}/v2/bot/info", self.api_base);
+        self.get_json("LINE bot info", &url).await
+    }
+
+    pub async fn get_quota(&self) -> anyhow::Result<Quota> {
+        let url = format!("{}/v2/bot/message/quota", self.api_base);
+        self.get_json("LINE quota", &url).await
+    }
+
+    pub async fn get_quota_consumption(&self) -> anyhow::Result<QuotaConsumption> {
+        let url = format!("{}/v2/bot/message/quota/consumption", self.api_base);
+        self.get_json("LINE quota consumption", &url).await
+    }
+
+    /// Creates a rich menu and returns its id.
+    pub async fn create_rich_menu(&self, menu: &Value) -> anyhow::Result<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Created {
+            rich_menu_id: String,
+        }
+
+        let url = format!("{}/v2/bot/richmenu", self.api_base);
+        let resp = self
+            .send_with_retry("LINE rich menu create", || {
+                self.http.post(&url).bearer_auth(&self.token).json(menu)
+            })
+            .await?;
+        let created: Created = self
+            .check(resp)
+            .await?
+            .json()
+            .await
+            .map_err(|e| http_error("LINE rich menu create", e))?;
+        Ok(created.rich_menu_id)
+    }
+
+    pub async fn upload_rich_menu_image(
+        &self,
+        rich_menu_id: &str,
+        image: Vec<u8>,
+        content_type: &str,
+    ) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/v2/bot/richmenu/{}/content",
+            self.data_api_base, rich_menu_id
+        );
+        let resp = self
+            .send_with_retry("LINE rich menu image upload", || {
+                self.http
+                    .post(&url)
+                    .bearer_auth(&self.token)
+                    .header(CONTENT_TYPE, content_type)
+                    .timeout(self.content_timeout)
+                    .body(image.clone())
+            })
+            .await?;
+        self.check(resp).await?;
+        Ok(())
+    }
+
+    /// Makes the rich menu the one shown to every user without their own.
+    pub async fn set_default_rich_menu(&self, rich_menu_id: &str) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/v2/bot/user/all/richmenu/{}",
+            self.api_base, rich_menu_id
+        );
+        let resp = self
+            .send_with_retry("LINE default rich menu", || {
+                self.http.post(&url).bearer_auth(&self.token)
+            })
+            .await?;
+        self.check(resp).await?;
+        Ok(())
+    }
+
+    pub async fn delete_rich_menu(&self, rich_menu_id: &str) -> anyhow::Result<()> {
+        let url = format!("{}/v2/bot/richmenu/{}", self.api_base, rich_menu_id);
+        let resp = self
+            .send_with_retry("LINE rich menu delete", || {
+                self.http.delete(&url).bearer_auth(&self.token)
+            })
+            .await?;
+        self.check(resp).await?;
+        Ok(())
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, what: &str, url: &str) -> anyhow::Result<T> {
+        let resp = self
+            .send_with_retry(what, || self.http.get(url).bearer_auth(&self.token))
+            .await?;
+        let resp = self.check(resp).await?;
+        resp.json().await.map_err(|e| http_error(what, e))
+    }
+
+    /// Polls until LINE has finished preparing a video or audio message for
+    /// download, giving up after the content timeout.
+    pub async fn wait_for_transcoding(&self, message_id: &str) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/v2/bot/message/{}/content/transcoding",
+            self.data_api_base, message_id
+        );
+        let deadline = Instant::now() + self.content_timeout;
+        loop {
+            let resp = self
+                .send_with_retry("LINE transcoding status", || {
+                    self.http.get(&url).bearer_auth(&self.token)
+                })
+                .await?;
+            let status: TranscodingStatus = self
+                .check(resp)
+                .await?
+                .json()
+                .await
+                .map_err(|e| http_error("LINE transcoding status", e))?;
+            match status.status.as_str() {
+                "succeeded" => return Ok(()),
+                "failed" => anyhow::bail!("LINE failed to transcode message {}", message_id),
+                _ if Instant::now() >= deadline => {
+                    anyhow::bail!("timed out waiting for message {} to transcode", message_id)
+                }
+                _ => tokio::time::sleep(TRANSCODING_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Starts downloading a message's content. The body is left unread so
+    /// the caller can stream it onwards instead of buffering it.
+    pub async fn open_content(&self, message_id: &str) -> anyhow::Result<Response> {
+        let url = format!(
+            "{}/v2/bot/message/{}/content",
+            self.data_api_base, message_id
+        );
+        let resp = self
+            .send_with_retry("LINE content fetch", || {
+                self.http
+                    .get(&url)
+                    .bearer_auth(&self.token)
+                    .timeout(self.content_timeout)
+            })
+            .await?;
+        Ok(self.check(resp).await?)
+    }
+
+    /// Turns a non-success response into a LineApiError, keeping track of
+    /// consecutive 401s so a revoked token is loudly reported.
+    async fn check(&self, resp: Response) -> Result<Response, LineApiError> {
+        if resp.status().is_success() {
+            self.unauthorized_streak.store(0, Ordering::Relaxed);
+            return Ok(resp);
+        }
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            let streak = self.unauthorized_streak.fetch_add(1, Ordering::Relaxed) + 1;
+            if streak >= UNAUTHORIZED_ALERT_THRESHOLD {
+                error!(
+                    streak,
+                    "LINE keeps rejecting the channel access token; it has probably expired or been revoked"
+                );
+            }
+        }
+        let error = LineApiError::from_response(resp).await;
+        self.metrics.last_line_error.record(error.to_string());
+        Err(error)
+    }
+
+    /// Sends the request built by `build`, retrying on 5xx, 429, and
+    /// connection failures. Other responses are returned as-is for the
+    /// caller to inspect.
+    async fn send_with_retry(
+        &self,
+        what: &str,
+        build: impl Fn() -> RequestBuilder,
+    ) -> anyhow::Result<Response> {
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let result = build().send().await;
+            if let Ok(resp) = &result
+                && resp.status() == StatusCode::TOO_MANY_REQUESTS
+            {
+                self.metrics.rate_limited.inc();
+            }
+            let retryable = match &result {
+                Ok(resp) => is_retryable_status(resp.status()),
+                Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            };
+            if !retryable || attempt >= self.retry.max_attempts {
+                return result.map_err(|e| http_error(what, e));
+            }
+
+            let mut delay = self.retry.delay(attempt);
+            if let Ok(resp) = &result
+                && resp.status() == StatusCode::TOO_MANY_REQUESTS
+                && let Some(wait) = retry_after(resp)
+            {
+                delay = wait.min(self.retry.max_retry_after);
+            }
+            if started.elapsed() + delay > self.retry.max_total {
+                warn!(what, attempt, "retry budget exhausted");
+                return result.map_err(|e| http_error(what, e));
+            }
+            match &result {
+                Ok(resp) => warn!(what, attempt, status = %resp.status(), ?delay, "retrying"),
+                Err(e) => warn!(what, attempt, error = %e, ?delay, "retrying"),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// `text` as the text messages of one reply; see `split_text`.
+pub fn text_messages(text: &str) -> Vec<Value> {
+    split_text(text, &[])
+}
+
+/// Like `text_messages`, but expands `{emoji:productId/emojiId}`
+/// placeholders into LINE emojis.
+pub fn template_messages(template: &str) -> Vec<Value> {
+    let (text, emojis) = parse_emojis(template);
+    split_text(&text, &emojis)
+}
+
+/// LINE accepts at most this many emojis in one text message.
+const MAX_EMOJIS_PER_MESSAGE: usize = 20;
+
+/// A LINE emoji standing at the `$` that is character `at` of the text.
+struct Emoji<'a> {
+    at: usize,
+    product_id: &'a str,
+    emoji_id: &'a str,
+}
+
+/// Splits `text` on character boundaries into messages LINE accepts: each
+/// at most MAX_TEXT_CHARS long with at most MAX_EMOJIS_PER_MESSAGE emojis.
+/// Past MAX_MESSAGES_PER_REQUEST the rest is cut off with a note. Empty
+/// text gives no messages, since LINE rejects them.
+fn split_text(text: &str, emojis: &[Emoji<'_>]) -> Vec<Value> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    let (mut start, mut first) = (0, 0);
+    while start < chars.len() {
+        let mut end = (start + MAX_TEXT_CHARS).min(chars.len());
+        let mut last = first + emojis[first..].iter().take_while(|e| e.at < end).count();
+        if last - first > MAX_EMOJIS_PER_MESSAGE {
+            last = first + MAX_EMOJIS_PER_MESSAGE;
+            end = emojis[last].at;
+        }
+        chunks.push((start, end, &emojis[first..last]));
+        (start, first) = (end, last);
+    }
+
+    let truncated = chunks.len() > MAX_MESSAGES_PER_REQUEST;
+    if truncated {
+        chunks.truncate(MAX_MESSAGES_PER_REQUEST);
+        let (start, end, emojis) = chunks.last_mut().unwrap();
+        *end = (*start + MAX_TEXT_CHARS - TRUNCATED_NOTE.chars().count()).min(*end);
+        *emojis = &emojis[..emojis.iter().take_while(|e| e.at < *end).count()];
+    }
+    let count = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, (start, end, emojis))| {
+            let mut text: String = chars[start..end].iter().collect();
+            if truncated && i + 1 == count {
+                text.push_str(TRUNCATED_NOTE);
+            }
+            let mut message = text_message(&text);
+            if !emojis.is_empty() {
+                // LINE counts the index in UTF-16 code units, so a
+                // character outside the BMP advances it by two.
+                let emojis: Vec<Value> = emojis
+                    .iter()
+                    .map(|emoji| {
+                        let index: usize =
+                            chars[start..emoji.at].iter().map(|c| c.len_utf16()).sum();
+                        serde_json::json!({
+                            "index": index,
+                            "productId": emoji.product_id,
+                            "emojiId": emoji.emoji_id,
+                        })
+                    })
+                    .collect();
+                message["emojis"] = emojis.into();
+            }
+            message
+        })
+        .collect()
+}
+
+/// Replaces each `{emoji:productId/emojiId}` with the `$` LINE expects and
+/// records where it went. Malformed placeholders, including ones cut short
+/// by another `{`, are left as they are.
+fn parse_emojis(template: &str) -> (String, Vec<Emoji<'_>>) {
+    const OPEN: &str = "{emoji:";
+    let mut text = String::with_capacity(template.len());
+    let mut emojis = Vec::new();
+    let mut chars = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find(OPEN) {
+        let (before, placeholder) = rest.split_at(start);
+        text.push_str(before);
+        chars += before.chars().count();
+
+        let body = &placeholder[OPEN.len()..];
+        let ids = body
+            .find(['{', '}'])
+            .filter(|&end| body[end..].starts_with('}'))
+            .and_then(|end| Some((body[..end].split_once('/')?, &body[end + 1..])));
+        match ids {
+            Some(((product_id, emoji_id), after))
+                if !product_id.is_empty() && !emoji_id.is_empty() =>
+            {
+                emojis.push(Emoji {
+                    at: chars,
+                    product_id,
+                    emoji_id,
+                });
+                text.push('$');
+                chars += 1;
+                rest = after;
+            }
+            _ => {
+                text.push_str(OPEN);
+                chars += OPEN.len();
+                rest = body;
+            }
+        }
+    }
+    text.push_str(rest);
+    (text, emojis)
+}
+
+pub fn text_message(text: &str) -> Value {
+    serde_json::json!({
+        "type": "text",
+        "text": text,
+    })
+}
+
+pub fn image_message(image_url: &str, preview_url: &str) -> Value {
+    serde_json::json!({
+        "type": "image",
+        "originalContentUrl": image_url,
+        "previewImageUrl": preview_url,
+    })
+}
+
+/// `tracking_id` names the video in LINE's viewing statistics; ids LINE
+/// would reject, such as Japanese preset keys, are replaced by a hash.
+pub fn video_message(video_url: &str, preview_url: &str, tracking_id: Option<&str>) -> Value {
+    let mut message = serde_json::json!({
+        "type": "video",
+        "originalContentUrl": video_url,
+        "previewImageUrl": preview_url,
+    });
+    if let Some(tracking_id) = tracking_id {
+        message["trackingId"] = safe_tracking_id(tracking_id).into();
+    }
+    message
+}
+
+/// Longest trackingId LINE accepts.
+const MAX_TRACKING_ID_CHARS: usize = 100;
+
+/// `id` if LINE accepts it as a trackingId, which allows only ASCII
+/// letters, digits and some punctuation; otherwise a stable hash of it.
+fn safe_tracking_id(id: &str) -> String {
+    const ALLOWED: &str = "-.=,+*()%$&;:@{}!?<>[]";
+    let acceptable = !id.is_empty()
+        && id.len() <= MAX_TRACKING_ID_CHARS
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ALLOWED.contains(c));
+    if acceptable {
+        return id.to_string();
+    }
+    use sha2::Digest as _;
+    let digest = sha2::Sha256::digest(id.as_bytes());
+    let hash: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("preset-{}", hash)
+}
+
+/// Longest label LINE accepts on an action, whether a button or a quick
+/// reply item.
+pub const MAX_ACTION_LABEL_CHARS: usize = 20;
+
+/// `text` cut to at most `max_chars` characters, ending in … when it had
+/// to be shortened.
+pub fn ellipsize(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut short: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    short.push('…');
+    short
+}
+
+/// `text` cut down to what fits on an action's label.
+pub fn action_label(text: &str) -> String {
+    text.chars().take(MAX_ACTION_LABEL_CHARS).collect()
+}
+
+/// LINE shows at most this many columns in one carousel template.
+pub const MAX_CAROUSEL_COLUMNS: usize = 10;
+
+/// LINE refuses a flex carousel with more bubbles than this.
+pub const MAX_FLEX_CAROUSEL_BUBBLES: usize = 12;
+
+/// Longest text LINE accepts in a carousel column that has a thumbnail.
+pub const MAX_CAROUSEL_TEXT_CHARS: usize = 60;
+
+/// Longest text LINE accepts in a buttons template without a title or
+/// thumbnail.
+pub const MAX_BUTTONS_TEXT_CHARS: usize = 160;
+
+/// Longest text LINE accepts in a confirm template.
+pub const MAX_CONFIRM_TEXT_CHARS: usize = 240;
+
+pub fn carousel_template(columns: Vec<Value>) -> Value {
+    serde_json::json!({
+        "type": "carousel",
+        "columns": columns,
+    })
+}
+
+/// A carousel column with a thumbnail, title, and a single action. LINE
+/// requires every column in a carousel to have the same shape.
+pub fn carousel_column(thumbnail_url: &str, title: &str, text: &str, action: Value) -> Value {
+    serde_json::json!({
+        "thumbnailImageUrl": thumbnail_url,
+        "title": title,
+        "text": text,
+        "actions": [action],
+    })
+}
+
+pub fn sticker_message(package_id: &str, sticker_id: &str) -> Value {
+    serde_json::json!({
+        "type": "sticker",
+        "packageId": package_id,
+        "stickerId": sticker_id,
+    })
+}
+
+pub fn flex_message(alt_text: &str, contents: Value) -> Value {
+    serde_json::json!({
+        "type": "flex",
+        "altText": alt_text,
+        "contents": contents,
+    })
+}
+
+/// Sets the message's `sender`, if one is given.
+pub fn with_sender(mut message: Value, sender: Option<&Sender>) -> Value {
+    if let Some(sender) = sender {
+        let mut value = serde_json::json!({ "name": sender.name });
+        if let Some(icon_url) = &sender.icon_url {
+            value["iconUrl"] = icon_url.as_str().into();
+        }
+        message["sender"] = value;
+    }
+    message
+}
+
+/// Metrics label for a batch of messages: their shared type, or "mixed".
+fn message_kind(messages: &[Value]) -> &str {
+    let mut types = messages.iter().map(|m| m["type"].as_str().unwrap_or(""));
+    let first = types.next().unwrap_or("");
+    if types.all(|t| t == first) {
+        first
+    } else {
+        "mixed"
+    }
+}
+
+/// Parses Retry-After in either its delta-seconds or HTTP-date form.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Labels a reqwest error so timeouts stand apart from other transport failures.
+fn http_error(what: &str, err: reqwest::Error) -> anyhow::Error {
+    if err.is_timeout() {
+        anyhow::Error::new(err).context(format!("{} timed out", what))
+    } else if err.is_connect() {
+        anyhow::Error::new(err).context(format!("{} could not connect", what))
+    } else {
+        anyhow::Error::new(err).context(format!("{} failed", what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockLine, Scripted};
+
+    fn target(reply_token: &str) -> ReplyTarget<'_> {
+        ReplyTarget {
+            reply_token,
+            push_to: None,
+            expires_at: None,
+            notification_disabled: false,
+        }
+    }
+
+    async fn client() -> (MockLine, LineClient) {
+        let line = MockLine::start().await;
+        let client = line.client("token", Arc::new(Metrics::default()));
+        (line, client)
+    }
+
+    #[tokio::test]
+    async fn replies_go_to_the_configured_api_base() {
+        let (line, client) = client().await;
+        client.reply_text(target("rt"), "こんにちは").await.unwrap();
+
+        let requests = line.to("/v2/bot/message/reply");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("authorization"), Some("Bearer token"));
+        assert_eq!(
+            requests[0].json(),
+            serde_json::json!({
+                "replyToken": "rt",
+                "messages": [{ "type": "text", "text": "こんにちは" }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn content_is_fetched_from_the_data_api() {
+        let (line, client) = client().await;
+        line.respond(
+            "/v2/bot/message/m1/content",
+            Scripted::new(200, &b"image bytes"[..]),
+        );
+        let resp = client.open_content("m1").await.unwrap();
+        assert_eq!(resp.bytes().await.unwrap(), &b"image bytes"[..]);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (line, client) = client().await;
+        line.respond("/v2/bot/message/reply", Scripted::new(500, "{}"));
+        line.respond("/v2/bot/message/reply", Scripted::new(500, "{}"));
+        client.reply_text(target("rt"), "hi").await.unwrap();
+        assert_eq!(line.to("/v2/bot/message/reply").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_last_attempt() {
+        let (line, client) = client().await;
+        for _ in 0..3 {
+            line.respond("/v2/bot/message/reply", Scripted::new(503, "{}"));
+        }
+        let e = client.reply_text(target("rt"), "hi").await.unwrap_err();
+        let api = e.downcast_ref::<LineApiError>().unwrap();
+        assert_eq!(api.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(line.to("/v2/bot/message/reply").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (line, client) = client().await;
+        line.respond(
+            "/v2/bot/message/reply",
+            Scripted::new(400, r#"{"message":"The request body has 1 error(s)"}"#),
+        );
+        let e = client.reply_text(target("rt"), "hi").await.unwrap_err();
+        let api = e.downcast_ref::<LineApiError>().unwrap();
+        assert_eq!(api.message, "The request body has 1 error(s)");
+        assert_eq!(line.to("/v2/bot/message/reply").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pushes_keep_their_retry_key_across_retries() {
+        let (line, client) = client().await;
+        line.respond("/v2/bot/message/push", Scripted::new(500, "{}"));
+        client
+            .push_messages("U1", vec![text_message("hi")], false, None)
+            .await
+            .unwrap();
+
+        let requests = line.to("/v2/bot/message/push");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].json(),
+            serde_json::json!({
+                "to": "U1",
+                "messages": [{ "type": "text", "text": "hi" }],
+            })
+        );
+        let key = requests[0].header("x-line-retry-key").unwrap();
+        assert!(Uuid::parse_str(key).is_ok());
+        assert_eq!(requests[1].header("x-line-retry-key"), Some(key));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_reply_token_falls_back_to_a_push() {
+        let (line, client) = client().await;
+        line.respond(
+            "/v2/bot/message/reply",
+            Scripted::new(400, r#"{"message":"Invalid reply token"}"#),
+        );
+        let target = ReplyTarget {
+            push_to: Some("U1"),
+            ..target("rt")
+        };
+        client.reply_text(target, "hi").await.unwrap();
+
+        let reply = &line.to("/v2/bot/message/reply")[0];
+        let push = &line.to("/v2/bot/message/push")[0];
+        assert_eq!(push.json()["to"], "U1");
+        assert_eq!(push.json()["messages"], reply.json()["messages"]);
+    }
+
+    #[tokio::test]
+    async fn an_expired_reply_token_is_not_tried() {
+        let (line, client) = client().await;
+        let target = ReplyTarget {
+            push_to: Some("U1"),
+            expires_at: Some(Instant::now()),
+            ..target("rt")
+        };
+        client.reply_text(target, "hi").await.unwrap();
+        assert!(line.to("/v2/bot/message/reply").is_empty());
+        assert_eq!(line.to("/v2/bot/message/push").len(), 1);
+    }
+
+    #[test]
+    fn video_messages_carry_an_ascii_tracking_id() {
+        let message = video_message("https://v", "https://p", Some("menu-video"));
+        assert_eq!(message["trackingId"], "menu-video");
+
+        let message = video_message("https://v", "https://p", Some("ランチ動画"));
+        let tracking_id = message["trackingId"].as_str().unwrap();
+        assert!(tracking_id.starts_with("preset-"));
+        assert!(tracking_id.is_ascii());
+        assert!(tracking_id.len() <= MAX_TRACKING_ID_CHARS);
+        let again = video_message("https://v", "https://p", Some("ランチ動画"));
+        assert_eq!(again["trackingId"], tracking_id);
+        assert_ne!(
+            video_message("https://v", "https://p", Some("夜動画"))["trackingId"],
+            tracking_id
+        );
+
+        let message = video_message("https://v", "https://p", None);
+        assert!(message.get("trackingId").is_none());
+    }
+
+    fn texts(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|m| m["text"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn text_over_the_limit_is_split() {
+        let text = "a".repeat(MAX_TEXT_CHARS + 1);
+        let texts = texts(&text_messages(&text));
+        assert_eq!(texts.len(), 2);
+        assert_eq!(texts[0].len(), MAX_TEXT_CHARS);
+        assert_eq!(texts[1], "a");
+    }
+
+    #[test]
+    fn multi_byte_text_is_split_on_characters() {
+        let text = format!("{}あい", "ア".repeat(MAX_TEXT_CHARS - 1));
+        let texts = texts(&text_messages(&text));
+        assert_eq!(texts.len(), 2);
+        assert_eq!(texts[0].chars().count(), MAX_TEXT_CHARS);
+        assert!(texts[0].ends_with("アあ"));
+        assert_eq!(texts[1], "い");
+    }
+
+    #[test]
+    fn empty_text_sends_nothing() {
+        assert!(text_messages("").is_empty());
+    }
+
+    #[test]
+    fn text_beyond_five_messages_is_truncated_with_a_note() {
+        let text = "b".repeat(MAX_TEXT_CHARS * MAX_MESSAGES_PER_REQUEST + 1);
+        let texts = texts(&text_messages(&text));
+        assert_eq!(texts.len(), MAX_MESSAGES_PER_REQUEST);
+        let last = texts.last().unwrap();
+        assert_eq!(last.chars().count(), MAX_TEXT_CHARS);
+        assert!(last.ends_with(TRUNCATED_NOTE));
+    }
+
+    #[test]
+    fn sender_is_added_only_when_given() {
+        let message = with_sender(text_message("hi"), None);
+        assert!(message.get("sender").is_none());
+
+        let sender = Sender {
+            name: "店長".to_string(),
+            icon_url: None,
+        };
+        let message = with_sender(text_message("hi"), Some(&sender));
+        assert_eq!(message["sender"], serde_json::json!({ "name": "店長" }));
+
+        let sender = Sender {
+            icon_url: Some("https://example.com/icon.png".to_string()),
+            ..sender
+        };
+        let message = with_sender(text_message("hi"), Some(&sender));
+        assert_eq!(
+            message["sender"],
+            serde_json::json!({ "name": "店長", "iconUrl": "https://example.com/icon.png" })
+        );
+    }
+
+    #[test]
+    fn long_sender_names_are_truncated() {
+        let sender = Sender {
+            name: "あ".repeat(MAX_SENDER_NAME_CHARS + 5),
+            icon_url: None,
+        }
+        .validated();
+        assert_eq!(sender.name.chars().count(), MAX_SENDER_NAME_CHARS);
+        let short = Sender {
+            name: "店長".to_string(),
+            icon_url: None,
+        }
+        .validated();
+        assert_eq!(short.name, "店長");
+    }
+
+    fn emojis(message: &Value) -> Vec<(u64, String)> {
+        message["emojis"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|e| {
+                (
+                    e["index"].as_u64().unwrap(),
+                    format!(
+                        "{}/{}",
+                        e["productId"].as_str().unwrap(),
+                        e["emojiId"].as_str().unwrap()
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn emoji_indexes_count_utf16_units_after_multi_byte_text() {
+        let messages = template_messages("こんにちは {emoji:5ac1bfd5040ab15980c9b435/001}！");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["text"], "こんにちは $！");
+        assert_eq!(
+            emojis(&messages[0]),
+            [(6, "5ac1bfd5040ab15980c9b435/001".to_string())]
+        );
+    }
+
+    #[test]
+    fn emoji_indexes_count_surrogate_pairs_twice() {
+        let messages = template_messages("😀{emoji:p/001}𠮷{emoji:p/002}");
+        assert_eq!(messages[0]["text"], "😀$𠮷$");
+        assert_eq!(
+            emojis(&messages[0]),
+            [(2, "p/001".to_string()), (5, "p/002".to_string())]
+        );
+    }
+
+    #[test]
+    fn malformed_placeholders_are_left_as_text() {
+        let messages = template_messages("{emoji:foo {emoji:p/e} {emoji:/x} {emoji:a/b");
+        assert_eq!(messages[0]["text"], "{emoji:foo $ {emoji:/x} {emoji:a/b");
+        assert_eq!(emojis(&messages[0]), [(11, "p/e".to_string())]);
+        assert!(template_messages("plain")[0].get("emojis").is_none());
+    }
+
+    #[test]
+    fn emojis_beyond_twenty_start_a_new_message() {
+        let template = "{emoji:p/e}".repeat(MAX_EMOJIS_PER_MESSAGE + 5);
+        let messages = template_messages(&template);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(emojis(&messages[0]).len(), MAX_EMOJIS_PER_MESSAGE);
+        assert_eq!(messages[1]["text"], "$$$$$");
+        assert_eq!(emojis(&messages[1])[0].0, 0);
+    }
+
+    #[test]
+    fn long_emoji_text_is_split_with_indexes_per_message() {
+        let template = format!("{}{{emoji:p/e}}", "😀".repeat(MAX_TEXT_CHARS));
+        let messages = template_messages(&template);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].get("emojis").is_none());
+        assert_eq!(messages[1]["text"], "$");
+        assert_eq!(emojis(&messages[1]), [(0, "p/e".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn an_already_accepted_send_counts_as_sent() {
+        let (line, client) = client().await;
+        line.respond(
+            "/v2/bot/message/push",
+            Scripted::new(409, r#"{"message":"The retry key is already accepted"}"#),
+        );
+        client
+            .push_messages(
+                "U1",
+                vec![text_message("hi")],
+                false,
+                Some(&Uuid::new_v4().to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(line.to("/v2/bot/message/push").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn multicast_chunks_get_stable_keys_of_their_own() {
+        let (line, client) = client().await;
+        let to: Vec<String> = (0..MULTICAST_MAX_RECIPIENTS + 1)
+            .map(|i| format!("U{}", i))
+            .collect();
+        let key = Uuid::new_v4().to_string();
+        for _ in 0..2 {
+            let chunks = client
+                .multicast(&to, vec![text_message("hi")], Some(&key))
+                .await;
+            assert!(chunks.iter().all(|chunk| chunk.result.is_ok()));
+            assert_eq!(
+                chunks.iter().map(|c| c.recipients).collect::<Vec<_>>(),
+                [MULTICAST_MAX_RECIPIENTS, 1]
+            );
+        }
+
+        let keys: Vec<String> = line
+            .to("/v2/bot/message/multicast")
+            .iter()
+            .map(|r| r.header("x-line-retry-key").unwrap().to_string())
+            .collect();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[0], key);
+        assert_ne!(keys[1], key);
+        assert!(Uuid::parse_str(&keys[1]).is_ok());
+        assert_eq!(keys[..2], keys[2..]);
+    }
+
+    #[tokio::test]
+    async fn multicast_without_a_key_uses_a_fresh_one_per_chunk() {
+        let (line, client) = client().await;
+        let to: Vec<String> = (0..MULTICAST_MAX_RECIPIENTS + 1)
+            .map(|i| format!("U{}", i))
+            .collect();
+        client.multicast(&to, vec![text_message("hi")], None).await;
+        let requests = line.to("/v2/bot/message/multicast");
+        assert_ne!(
+            requests[0].header("x-line-retry-key"),
+            requests[1].header("x-line-retry-key")
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::io::Cursor;
+
+use bytes::{Bytes, BytesMut};
+use image::{
+    DynamicImage, ImageDecoder, ImageReader, ImageResult, Rgb, RgbImage, Rgba, RgbaImage,
+    codecs::jpeg::JpegEncoder,
+    imageops::{self, FilterType},
+    metadata::Orientation,
+};
+
+/// Largest width or height accepted for an image message.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// Longest side of the preview LINE shows in the chat, as it recommends.
+pub const PREVIEW_DIMENSION: u32 = 240;
+
+const PREVIEW_QUALITY: u8 = 80;
+
+/// Image formats accepted from admins, recognised by their leading bytes
+/// rather than trusted from the sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Heic,
+}
+
+impl ImageFormat {
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Self::Png),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            [
+                b'R',
+                b'I',
+                b'F',
+                b'F',
+                _,
+                _,
+                _,
+                _,
+                b'W',
+                b'E',
+                b'B',
+                b'P',
+                ..,
+            ] => Some(Self::Webp),
+            [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..]
+                if brand.len() >= 4
+                    && matches!(&brand[..4], b"heic" | b"heix" | b"mif1" | b"msf1") =>
+            {
+                Some(Self::Heic)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether uploads in this format can be decoded and converted to
+    /// JPEG. HEIC needs the `heic` feature.
+    pub fn supported(self) -> bool {
+        match self {
+            Self::Jpeg | Self::Png | Self::Webp => true,
+            Self::Heic => cfg!(feature = "heic"),
+            Self::Gif => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Jpeg => "JPEG",
+            Self::Png => "PNG",
+            Self::Gif => "GIF",
+            Self::Webp => "WebP",
+            Self::Heic => "HEIC",
+        }
+    }
+}
+
+/// Why an upload couldn't be decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not an image the decoder knows, or one that is cut off or corrupt.
+    Unreadable,
+    /// Wider or taller than MAX_DIMENSION.
+    TooLarge { width: u32, height: u32 },
+}
+
+/// A decoded image and the orientation its metadata asks it to be shown in.
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub image: DynamicImage,
+    pub orientation: Orientation,
+}
+
+/// Decodes a whole image, so data that is cut off or corrupt is caught
+/// before it is stored. The dimensions are read from the header first, so
+/// an oversized image is turned away without decoding its pixels.
+pub fn decode(bytes: &[u8]) -> Result<Decoded, DecodeError> {
+    #[cfg(feature = "heic")]
+    if ImageFormat::sniff(bytes) == Some(ImageFormat::Heic) {
+        return decode_heic(bytes);
+    }
+    let reader = || {
+        ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|_| DecodeError::Unreadable)
+    };
+    let (width, height) = reader()?
+        .into_dimensions()
+        .map_err(|_| DecodeError::Unreadable)?;
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(DecodeError::TooLarge { width, height });
+    }
+    let reader = reader()?;
+    // The JPEG decoder fills in whatever a cut-off scan is missing, so a
+    // JPEG also has to reach its end-of-image marker
+    if reader.format() == Some(image::ImageFormat::Jpeg) && !bytes.ends_with(&[0xFF, 0xD9]) {
+        return Err(DecodeError::Unreadable);
+    }
+    let mut decoder = reader.into_decoder().map_err(|_| DecodeError::Unreadable)?;
+    // Unreadable EXIF only loses the rotation, not the image
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let image = DynamicImage::from_decoder(decoder).map_err(|_| DecodeError::Unreadable)?;
+    Ok(Decoded { image, orientation })
+}
+
+#[cfg(feature = "heic")]
+fn decode_heic(bytes: &[u8]) -> Result<Decoded, DecodeError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(bytes).map_err(|_| DecodeError::Unreadable)?;
+    let handle = context
+        .primary_image_handle()
+        .map_err(|_| DecodeError::Unreadable)?;
+    let (width, height) = (handle.width(), handle.height());
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(DecodeError::TooLarge { width, height });
+    }
+    // libheif applies the rotation and mirroring the file asks for
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(|_| DecodeError::Unreadable)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or(DecodeError::Unreadable)?;
+    let row = plane.width as usize * 3;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|line| &line[..row])
+        .copied()
+        .collect();
+    let image = image::RgbImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or(DecodeError::Unreadable)?;
+    Ok(Decoded {
+        image: DynamicImage::ImageRgb8(image),
+        orientation: Orientation::NoTransforms,
+    })
+}
+
+/// Encodes `image` as a JPEG. Nothing but the pixels is written, so no
+/// EXIF, XMP or IPTC metadata comes along. Transparent areas, which JPEG
+/// can't hold, are laid over white.
+pub fn encode_jpeg(image: &DynamicImage, quality: u8) -> ImageResult<Vec<u8>> {
+    let rgb = if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        RgbImage::from_fn(image.width(), image.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            let over_white =
+                |c: u8| ((u16::from(c) * u16::from(a) + 255 * (255 - u16::from(a))) / 255) as u8;
+            Rgb([over_white(r), over_white(g), over_white(b)])
+        })
+    } else {
+        image.to_rgb8()
+    };
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(&rgb)?;
+    Ok(jpeg)
+}
+
+/// A JPEG of the image in `bytes` scaled down so its longest side is
+/// PREVIEW_DIMENSION. Smaller images keep their size.
+pub fn preview(bytes: &[u8]) -> ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(bytes)?;
+    let image = if image.width().max(image.height()) > PREVIEW_DIMENSION {
+        image.resize(PREVIEW_DIMENSION, PREVIEW_DIMENSION, FilterType::Triangle)
+    } else {
+        image
+    };
+    encode_jpeg(&image, PREVIEW_QUALITY)
+}
+
+/// `images/food1/1700000000000.png` -> `images/food1/1700000000000_preview.jpg`
+pub fn preview_object(object: &str) -> String {
+    let stem = object
+        .rsplit_once('.')
+        .filter(|(_, extension)| !extension.contains('/'))
+        .map_or(object, |(stem, _)| stem);
+    format!("{}{}", stem, PREVIEW_SUFFIX)
+}
+
+pub fn is_preview(object: &str) -> bool {
+    object.ends_with(PREVIEW_SUFFIX)
+}
+
+const PREVIEW_SUFFIX: &str = "_preview.jpg";
+
+/// Where a tile goes on a canvas: `(x, y, width, height)`.
+pub type Cell = (u32, u32, u32, u32);
+
+/// A `width` x `height` white canvas with each image scaled and cropped to
+/// fill its cell.
+pub fn composite(width: u32, height: u32, tiles: &[(Cell, DynamicImage)]) -> DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+    for ((x, y, cell_width, cell_height), image) in tiles {
+        let tile = image.resize_to_fill(*cell_width, *cell_height, FilterType::Triangle);
+        imageops::overlay(&mut canvas, &tile.to_rgba8(), i64::from(*x), i64::from(*y));
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Reads from `body` until at least `len` bytes (or the whole body, if
+/// shorter) are buffered, leaving the rest to be streamed.
+pub async fn read_prefix(body: &mut reqwest::Response, len: usize) -> reqwest::Result<Bytes> {
+    let mut prefix = BytesMut::new();
+    while prefix.len() < len {
+        match body.chunk().await? {
+            Some(chunk) => prefix.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(prefix.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, PNG};
+
+    const GIF: &[u8] = b"GIF89a\x05\x00\x04\x00\x00\x00\x00;";
+
+    /// A lossless WebP header for a 7x9 image.
+    fn webp() -> Vec<u8> {
+        let mut webp = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00\x2f".to_vec();
+        let bits: u32 = 6 | (8 << 14);
+        webp.extend_from_slice(&bits.to_le_bytes());
+        webp
+    }
+
+    /// An ftyp box and an image spatial extents property for a 640x480 image.
+    fn heic() -> Vec<u8> {
+        let mut heic = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic".to_vec();
+        heic.extend_from_slice(b"\x00\x00\x00\x14ispe\x00\x00\x00\x00");
+        heic.extend_from_slice(&640u32.to_be_bytes());
+        heic.extend_from_slice(&480u32.to_be_bytes());
+        heic
+    }
+
+    #[test]
+    fn formats_are_recognised_by_their_first_bytes() {
+        let cases = [
+            (test_support::jpeg(3, 2), ImageFormat::Jpeg),
+            (PNG.to_vec(), ImageFormat::Png),
+            (GIF.to_vec(), ImageFormat::Gif),
+            (webp(), ImageFormat::Webp),
+            (heic(), ImageFormat::Heic),
+        ];
+        for (bytes, format) in cases {
+            assert_eq!(ImageFormat::sniff(&bytes), Some(format));
+        }
+    }
+
+    #[test]
+    fn gif_is_not_supported_and_heic_needs_its_feature() {
+        assert!(ImageFormat::Jpeg.supported());
+        assert!(ImageFormat::Png.supported());
+        assert!(ImageFormat::Webp.supported());
+        assert!(!ImageFormat::Gif.supported());
+        assert_eq!(ImageFormat::Heic.supported(), cfg!(feature = "heic"));
+    }
+
+    #[test]
+    fn unknown_and_short_signatures_are_not_recognised() {
+        assert_eq!(ImageFormat::sniff(b""), None);
+        assert_eq!(ImageFormat::sniff(b"\xFF\xD8"), None);
+        assert_eq!(ImageFormat::sniff(b"%PDF-1.7"), None);
+        assert_eq!(ImageFormat::sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(ImageFormat::sniff(b"\0\0\0\x18ftypmp42"), None);
+    }
+
+    #[test]
+    fn whole_images_decode() {
+        let image = decode(&test_support::jpeg(3, 2)).unwrap().image;
+        assert_eq!((image.width(), image.height()), (3, 2));
+        let image = decode(PNG).unwrap().image;
+        assert_eq!((image.width(), image.height()), (2, 2));
+    }
+
+    #[test]
+    fn truncated_images_are_unreadable() {
+        let bytes = test_support::jpeg(64, 48);
+        for cut in [bytes.len() - 1, bytes.len() - 2, bytes.len() / 2, 25] {
+            assert_eq!(
+                decode(&bytes[..cut]),
+                Err(DecodeError::Unreadable),
+                "{}",
+                cut
+            );
+        }
+        for cut in [PNG.len() - 20, 33] {
+            assert_eq!(decode(&PNG[..cut]), Err(DecodeError::Unreadable), "{}", cut);
+        }
+    }
+
+    #[test]
+    fn a_png_chunk_failing_its_crc_is_unreadable() {
+        let mut bytes = PNG.to_vec();
+        // A pixel byte inside IDAT
+        let idat = bytes.windows(4).position(|w| w == b"IDAT").unwrap();
+        bytes[idat + 6] ^= 0x01;
+        assert_eq!(decode(&bytes), Err(DecodeError::Unreadable));
+    }
+
+    #[test]
+    fn text_is_unreadable() {
+        assert_eq!(decode(b"just some notes\n"), Err(DecodeError::Unreadable));
+    }
+
+    #[test]
+    fn oversized_images_are_refused_with_their_dimensions() {
+        let wide = test_support::png(MAX_DIMENSION + 1, 1);
+        assert_eq!(
+            decode(&wide),
+            Err(DecodeError::TooLarge {
+                width: MAX_DIMENSION + 1,
+                height: 1
+            })
+        );
+        assert!(decode(&test_support::png(MAX_DIMENSION, 1)).is_ok());
+    }
+
+    #[test]
+    fn previews_are_scaled_down_jpegs() {
+        let original = test_support::jpeg(2000, 1500);
+        let preview = preview(&original).unwrap();
+        assert_eq!(ImageFormat::sniff(&preview), Some(ImageFormat::Jpeg));
+        let image = decode(&preview).unwrap().image;
+        assert_eq!((image.width(), image.height()), (240, 180));
+        assert!(preview.len() < original.len());
+    }
+
+    #[test]
+    fn small_images_keep_their_size_in_the_preview() {
+        let image = decode(&preview(PNG).unwrap()).unwrap().image;
+        assert_eq!((image.width(), image.height()), (2, 2));
+    }
+
+    #[test]
+    fn previews_sit_next_to_their_image() {
+        assert_eq!(
+            preview_object("images/food1/1700000000000.png"),
+            "images/food1/1700000000000_preview.jpg"
+        );
+        assert_eq!(
+            preview_object("images/food1.jpg"),
+            "images/food1_preview.jpg"
+        );
+        assert!(is_preview(&preview_object("images/food1.jpg")));
+        assert!(!is_preview("images/food1.jpg"));
+    }
+
+    #[test]
+    fn webp_decodes() {
+        let mut webp = Vec::new();
+        image::RgbaImage::new(5, 3)
+            .write_to(&mut Cursor::new(&mut webp), image::ImageFormat::WebP)
+            .unwrap();
+        let image = decode(&webp).unwrap().image;
+        assert_eq!((image.width(), image.height()), (5, 3));
+    }
+
+    #[test]
+    fn transparency_turns_white_in_a_jpeg() {
+        let clear = DynamicImage::ImageRgba8(image::RgbaImage::new(8, 8));
+        let jpeg = encode_jpeg(&clear, 90).unwrap();
+        let pixel = decode(&jpeg).unwrap().image.to_rgb8().get_pixel(4, 4).0;
+        assert!(pixel.iter().all(|&c| c > 250), "{:?}", pixel);
+    }
+
+    #[test]
+    fn the_orientation_is_read_from_exif() {
+        let decoded = decode(&test_support::sideways_jpeg()).unwrap();
+        assert_eq!(decoded.orientation, Orientation::Rotate90);
+        assert_eq!((decoded.image.width(), decoded.image.height()), (4, 2));
+        let decoded = decode(&test_support::jpeg(4, 2)).unwrap();
+        assert_eq!(decoded.orientation, Orientation::NoTransforms);
+    }
+
+    #[test]
+    fn upright_images_are_encoded_without_metadata() {
+        let Decoded {
+            mut image,
+            orientation,
+        } = decode(&test_support::sideways_jpeg()).unwrap();
+        image.apply_orientation(orientation);
+        let jpeg = encode_jpeg(&image, 90).unwrap();
+
+        assert!(!test_support::jpeg_segments(&jpeg).contains(&0xE1));
+        assert!(!jpeg.windows(3).any(|w| w == b"GPS"));
+        let decoded = decode(&jpeg).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (2, 4));
+        assert_eq!(decoded.orientation, Orientation::NoTransforms);
+    }
+}
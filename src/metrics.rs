@@ -0,0 +1,268 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+/// A monotonically increasing count.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increments the counter and returns the new total.
+    pub fn inc(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A family of counters distinguished by a single label value.
+#[derive(Default)]
+pub struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    pub fn inc(&self, label: &str) -> u64 {
+        let mut values = self.0.lock().unwrap();
+        let value = values.entry(label.to_string()).or_insert(0);
+        *value += 1;
+        *value
+    }
+
+    fn snapshot(&self) -> Vec<(String, u64)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+}
+
+/// The most recent error of some kind and when it happened. Not exported
+/// to Prometheus; the `status` admin command shows it.
+#[derive(Default)]
+pub struct LastError(Mutex<Option<(SystemTime, String)>>);
+
+impl LastError {
+    pub fn record(&self, error: String) {
+        *self.0.lock().unwrap() = Some((SystemTime::now(), error));
+    }
+
+    pub fn get(&self) -> Option<(SystemTime, String)> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Upper bounds, in seconds, shared by every latency histogram.
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// A cumulative latency histogram in the Prometheus sense.
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Process-wide counters and histograms, exposed on GET /metrics.
+#[derive(Default)]
+pub struct Metrics {
+    pub events_received: LabeledCounter,
+    pub events_processed: Counter,
+    pub signature_failures: Counter,
+    pub signature_primary: Counter,
+    pub signature_secondary: Counter,
+    pub wrong_destination: Counter,
+    pub stale_events: Counter,
+    pub replies_sent: LabeledCounter,
+    pub replies_failed: LabeledCounter,
+    pub rate_limited: Counter,
+    pub gcs_uploads: Counter,
+    pub pending_uploads_deleted: Counter,
+    pub reply_latency: Histogram,
+    /// Reading an image from LINE, before it's checked and stored.
+    pub content_fetch_latency: Histogram,
+    /// Writing an upload to storage. Videos and voice memos stream from
+    /// LINE as they're written, so their fetch is counted here too.
+    pub storage_upload_latency: Histogram,
+    pub last_line_error: LastError,
+}
+
+impl Metrics {
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        labeled(
+            &mut out,
+            "line_events_received_total",
+            "type",
+            &self.events_received,
+        );
+        counter(
+            &mut out,
+            "line_events_processed_total",
+            &self.events_processed,
+        );
+        counter(
+            &mut out,
+            "line_signature_failures_total",
+            &self.signature_failures,
+        );
+        let _ = writeln!(out, "# TYPE line_signature_verified_total counter");
+        let _ = writeln!(
+            out,
+            "line_signature_verified_total{{secret=\"primary\"}} {}",
+            self.signature_primary.get()
+        );
+        let _ = writeln!(
+            out,
+            "line_signature_verified_total{{secret=\"secondary\"}} {}",
+            self.signature_secondary.get()
+        );
+        counter(
+            &mut out,
+            "line_wrong_destination_total",
+            &self.wrong_destination,
+        );
+        counter(&mut out, "line_stale_events_total", &self.stale_events);
+        labeled(
+            &mut out,
+            "line_replies_sent_total",
+            "kind",
+            &self.replies_sent,
+        );
+        labeled(
+            &mut out,
+            "line_replies_failed_total",
+            "kind",
+            &self.replies_failed,
+        );
+        counter(&mut out, "line_rate_limited_total", &self.rate_limited);
+        counter(&mut out, "gcs_uploads_total", &self.gcs_uploads);
+        counter(
+            &mut out,
+            "gcs_pending_uploads_deleted_total",
+            &self.pending_uploads_deleted,
+        );
+        histogram(&mut out, "line_reply_duration_seconds", &self.reply_latency);
+        histogram(
+            &mut out,
+            "line_content_fetch_duration_seconds",
+            &self.content_fetch_latency,
+        );
+        histogram(
+            &mut out,
+            "gcs_upload_duration_seconds",
+            &self.storage_upload_latency,
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, counter: &Counter) {
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn labeled(out: &mut String, name: &str, label: &str, counter: &LabeledCounter) {
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (value, count) in counter.snapshot() {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(&value),
+            count
+        );
+    }
+}
+
+/// Escapes a label value as the text format requires: backslash, double
+/// quote and line feed.
+fn escape_label(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn histogram(out: &mut String, name: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.events_received.inc("a\\b\"c\nd");
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"line_events_received_total{type="a\\b\"c\nd"} 1"#));
+    }
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.reply_latency.observe(Duration::from_millis(80));
+        metrics.reply_latency.observe(Duration::from_secs(3));
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"line_reply_duration_seconds_bucket{le="0.05"} 0"#));
+        assert!(rendered.contains(r#"line_reply_duration_seconds_bucket{le="0.1"} 1"#));
+        assert!(rendered.contains(r#"line_reply_duration_seconds_bucket{le="5"} 2"#));
+        assert!(rendered.contains(r#"line_reply_duration_seconds_bucket{le="+Inf"} 2"#));
+        assert!(rendered.contains("line_reply_duration_seconds_count 2"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    admins::AdminStore,
+    line::{self, LineApiError, LineClient},
+    storage::StorageError,
+};
+
+/// At most one notification per category is pushed in this window.
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Longest error text included in a notification.
+const MAX_ERROR_CHARS: usize = 200;
+
+/// Kinds of failure reported to admins, each rate-limited on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Category {
+    Storage,
+    LineApi,
+    Other,
+}
+
+impl Category {
+    /// The category of `error`, or None when it isn't worth waking an admin
+    /// for: LINE refusing a single request, such as an expired reply token,
+    /// says nothing about the bot's health.
+    fn of(error: &anyhow::Error) -> Option<Self> {
+        if error.downcast_ref::<StorageError>().is_some() {
+            return Some(Self::Storage);
+        }
+        if let Some(api) = error.downcast_ref::<LineApiError>() {
+            let status = api.status.as_u16();
+            return (api.status.is_server_error() || status == 401 || status == 403)
+                .then_some(Self::LineApi);
+        }
+        Some(Self::Other)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Storage => "storage",
+            Self::LineApi => "line-api",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Pushes a short note to an admin when handling an event fails.
+pub struct ErrorNotifier {
+    admins: Arc<AdminStore>,
+    /// NOTIFY_USER_ID; the first admin when unset.
+    user_id: Option<String>,
+    last_sent: Mutex<HashMap<Category, Instant>>,
+}
+
+impl ErrorNotifier {
+    pub fn from_env(admins: Arc<AdminStore>) -> Self {
+        let user_id = env::var("NOTIFY_USER_ID")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        Self {
+            admins,
+            user_id,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reports `error` through `client`. A failed push is only logged, never
+    /// reported in turn, so a LINE outage can't feed on itself.
+    pub async fn notify(&self, client: &LineClient, request_id: &str, error: &anyhow::Error) {
+        let Some(category) = Category::of(error) else {
+            return;
+        };
+        let Some(to) = self
+            .user_id
+            .clone()
+            .or_else(|| self.admins.list().into_iter().next())
+        else {
+            return;
+        };
+        if !self.claim(category, Instant::now()) {
+            info!(category = category.label(), "error notification suppressed");
+            return;
+        }
+        let text = format!(
+            "エラーが発生しました（{}）\n{}\nrequest id: {}",
+            category.label(),
+            truncate(&format!("{:#}", error)),
+            request_id
+        );
+        let messages = vec![line::text_message(&text)];
+        if let Err(e) = client.push_messages(&to, messages, false, None).await {
+            warn!(
+                category = category.label(),
+                "failed to push error notification: {:#}", e
+            );
+        }
+    }
+
+    /// Takes the category's slot for this window, before the push is sent,
+    /// so a burst of failures yields a single notification.
+    fn claim(&self, category: Category, now: Instant) -> bool {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if let Some(at) = last_sent.get(&category)
+            && now.duration_since(*at) < NOTIFY_INTERVAL
+        {
+            return false;
+        }
+        last_sent.insert(category, now);
+        true
+    }
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_ERROR_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        storage::MemoryStorage,
+        test_support::{ADMIN, MockLine, Scripted},
+    };
+
+    async fn notifier(user_id: Option<&str>) -> ErrorNotifier {
+        let storage = Arc::new(MemoryStorage::default());
+        let admins = AdminStore::load(storage, vec![ADMIN.to_string()])
+            .await
+            .unwrap();
+        ErrorNotifier {
+            admins: Arc::new(admins),
+            user_id: user_id.map(str::to_string),
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn line_error(status: u16) -> anyhow::Error {
+        LineApiError {
+            status: StatusCode::from_u16(status).unwrap(),
+            code: None,
+            message: "failed".to_string(),
+            details: Vec::new(),
+        }
+        .into()
+    }
+
+    fn storage_error() -> anyhow::Error {
+        StorageError::new("upload", true, std::io::Error::other("503")).into()
+    }
+
+    #[test]
+    fn errors_are_categorized_and_single_request_refusals_dropped() {
+        assert_eq!(Category::of(&storage_error()), Some(Category::Storage));
+        assert_eq!(Category::of(&line_error(500)), Some(Category::LineApi));
+        assert_eq!(Category::of(&line_error(401)), Some(Category::LineApi));
+        assert_eq!(Category::of(&line_error(400)), None);
+        assert_eq!(Category::of(&line_error(429)), None);
+        assert_eq!(
+            Category::of(&anyhow::anyhow!("something else")),
+            Some(Category::Other)
+        );
+    }
+
+    #[tokio::test]
+    async fn each_category_is_claimed_once_per_interval() {
+        let notifier = notifier(None).await;
+        let start = Instant::now();
+        assert!(notifier.claim(Category::Storage, start));
+        assert!(!notifier.claim(Category::Storage, start + Duration::from_secs(60)));
+        assert!(notifier.claim(Category::LineApi, start + Duration::from_secs(60)));
+        assert!(!notifier.claim(
+            Category::Storage,
+            start + NOTIFY_INTERVAL - Duration::from_secs(1)
+        ));
+        assert!(notifier.claim(Category::Storage, start + NOTIFY_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_failures_pushes_one_notification_to_the_first_admin() {
+        let line = MockLine::start().await;
+        let client = line.client("token", Default::default());
+        let notifier = notifier(None).await;
+        for request_id in ["r1", "r2", "r3"] {
+            notifier.notify(&client, request_id, &storage_error()).await;
+        }
+        notifier.notify(&client, "r4", &line_error(400)).await;
+
+        let pushes = line.to("/v2/bot/message/push");
+        assert_eq!(pushes.len(), 1);
+        let body = pushes[0].json();
+        assert_eq!(body["to"], ADMIN);
+        let text = body["messages"][0]["text"].as_str().unwrap();
+        assert!(
+            text.starts_with("エラーが発生しました（storage）\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("\nrequest id: r1"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn a_failed_push_is_not_reported_in_turn() {
+        let line = MockLine::start().await;
+        line.respond(
+            "/v2/bot/message/push",
+            Scripted::new(500, r#"{"message": "down"}"#),
+        );
+        let client = line.client("token", Default::default());
+        let notifier = notifier(Some("Unotify")).await;
+        notifier.notify(&client, "r1", &line_error(500)).await;
+        notifier.notify(&client, "r2", &line_error(500)).await;
+
+        // Every push attempted is the first notification, retries included
+        let pushes = line.to("/v2/bot/message/push");
+        assert!(!pushes.is_empty());
+        for push in &pushes {
+            let body = push.json();
+            assert_eq!(body["to"], "Unotify");
+            let text = body["messages"][0]["text"].as_str().unwrap();
+            assert!(text.ends_with("request id: r1"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn long_errors_are_cut_at_a_character_boundary() {
+        let long = "あ".repeat(MAX_ERROR_CHARS + 10);
+        let cut = truncate(&long);
+        assert_eq!(cut.chars().count(), MAX_ERROR_CHARS + 1);
+        assert!(cut.ends_with('…'));
+        assert_eq!(truncate("short"), "short");
+    }
+}
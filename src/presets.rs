@@ -0,0 +1,1580 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use icu_normalizer::ComposingNormalizerBorrowed;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    Preset, PresetKind, env_list, find_preset,
+    line::{self, Sender},
+    schedule::{self, Variant},
+    storage::{self, Storage},
+};
+
+/// Image and text presets admins can edit without a redeploy, as a JSON
+/// object mapping trigger messages to object paths or to entries like
+/// `{"type": "text", "body": ..., "aliases": [...]}`. Takes the place of
+/// PRESETS when present.
+pub const PRESETS_OBJECT: &str = "presets.json";
+
+/// Longest trigger message a preset may have. Names end up in admin
+/// prompts, the broadcast confirmation and message actions, which LINE
+/// limits in length.
+pub const MAX_NAME_CHARS: usize = 100;
+
+/// LINE's limit on a message's altText.
+const MAX_ALT_TEXT_CHARS: usize = 400;
+
+/// Compiled size a preset's pattern may reach, so a pattern that would
+/// blow up into a huge automaton fails at load instead.
+const MAX_PATTERN_BYTES: usize = 1 << 20;
+
+/// `export` writes snapshots under here as `presets-<UTC timestamp>.json`.
+const EXPORTS_DIR: &str = "exports";
+
+/// Edits retried when another instance rewrote presets.json between our
+/// read and write.
+const MAX_EDIT_ATTEMPTS: u32 = 3;
+
+/// Image presets used when neither presets.json nor PRESETS exists.
+const DEFAULT_IMAGE_PRESETS: [(&str, &str); 4] = [
+    ("食べ物メニュー", "images/food1.jpg"),
+    ("ナイトランチメニュー", "images/food2.jpg"),
+    ("飲み物1メニュー", "images/drink1.jpg"),
+    ("飲み物2メニュー", "images/drink2.jpg"),
+];
+
+/// Trigger message -> preset.
+pub type PresetMap = HashMap<String, Preset>;
+
+/// A preset as configured, before it becomes a `Preset`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "serde_json::Value", into = "RawEntry")]
+struct PresetEntry {
+    kind: PresetKind,
+    aliases: Vec<String>,
+    alt_text: Option<String>,
+    caption: Option<String>,
+    /// Regex tried against the normalized text when nothing matches exactly.
+    pattern: Option<String>,
+    /// Images sent instead of an image preset's own at certain times.
+    variants: Vec<Variant>,
+}
+
+impl PresetEntry {
+    fn new(kind: PresetKind) -> Self {
+        Self {
+            kind,
+            aliases: Vec::new(),
+            alt_text: None,
+            caption: None,
+            pattern: None,
+            variants: Vec::new(),
+        }
+    }
+
+    fn image(object: &str) -> Self {
+        Self::new(PresetKind::Image {
+            object: object.to_string(),
+        })
+    }
+
+    /// Whether this is a bare message, with nothing a list entry can't have.
+    fn is_plain(&self) -> bool {
+        self.aliases.is_empty()
+            && self.alt_text.is_none()
+            && self.caption.is_none()
+            && self.pattern.is_none()
+            && self.variants.is_empty()
+    }
+}
+
+/// How a preset is written: just an image's object path, an object whose
+/// `type` (`image` when absent) says which other fields apply, or a list
+/// of either to send together.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RawEntry {
+    Object(String),
+    Full(Box<FullEntry>),
+    Messages(Vec<RawEntry>),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FullEntry {
+    #[serde(rename = "type", default)]
+    kind: EntryType,
+    #[serde(alias = "default", default, skip_serializing_if = "Option::is_none")]
+    object: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preview: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    package_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sticker_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alt_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    caption: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<Variant>,
+}
+
+impl FullEntry {
+    fn new(kind: EntryType, entry: &PresetEntry) -> Self {
+        Self {
+            kind,
+            object: None,
+            preview: None,
+            body: None,
+            package_id: None,
+            sticker_id: None,
+            aliases: entry.aliases.clone(),
+            alt_text: entry.alt_text.clone(),
+            caption: entry.caption.clone(),
+            pattern: entry.pattern.clone(),
+            variants: entry.variants.clone(),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryType {
+    #[default]
+    Image,
+    Text,
+    Sticker,
+    Video,
+}
+
+// Read through a Value rather than an untagged enum, so a malformed entry
+// is reported as what's wrong with it instead of "matched no variant".
+impl TryFrom<serde_json::Value> for PresetEntry {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, String> {
+        match value {
+            serde_json::Value::String(object) => Ok(Self::image(&object)),
+            serde_json::Value::Array(items) => {
+                let parts = items
+                    .into_iter()
+                    .map(|item| {
+                        let part = Self::try_from(item)?;
+                        if !part.is_plain() {
+                            return Err(
+                                "aliases, altText, caption, pattern and variants go on the whole list, not one of its messages"
+                                    .to_string(),
+                            );
+                        }
+                        if let PresetKind::Messages { .. } = part.kind {
+                            return Err("message lists can't be nested".to_string());
+                        }
+                        Ok(part.kind)
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Self::new(PresetKind::Messages { parts }))
+            }
+            value => {
+                let entry: FullEntry = serde_json::from_value(value).map_err(|e| e.to_string())?;
+                if !entry.variants.is_empty() && !matches!(entry.kind, EntryType::Image) {
+                    return Err("only image presets can have variants".to_string());
+                }
+                let kind = match entry.kind {
+                    EntryType::Image => PresetKind::Image {
+                        object: entry.object.ok_or("an image preset needs an object")?,
+                    },
+                    EntryType::Text => PresetKind::Text {
+                        body: entry.body.ok_or("a text preset needs a body")?,
+                    },
+                    EntryType::Sticker => match (entry.package_id, entry.sticker_id) {
+                        (Some(package_id), Some(sticker_id)) => PresetKind::Sticker {
+                            package_id,
+                            sticker_id,
+                        },
+                        _ => {
+                            return Err("a sticker preset needs a packageId and a stickerId".into());
+                        }
+                    },
+                    EntryType::Video => match (entry.object, entry.preview) {
+                        (Some(object), Some(preview)) => PresetKind::Video { object, preview },
+                        _ => return Err("a video preset needs an object and a preview".into()),
+                    },
+                };
+                Ok(Self {
+                    kind,
+                    aliases: entry.aliases,
+                    alt_text: entry.alt_text,
+                    caption: entry.caption,
+                    pattern: entry.pattern,
+                    variants: entry.variants,
+                })
+            }
+        }
+    }
+}
+
+impl From<PresetEntry> for RawEntry {
+    fn from(entry: PresetEntry) -> Self {
+        match &entry.kind {
+            PresetKind::Image { object } if entry.is_plain() => Self::Object(object.clone()),
+            PresetKind::Image { object } => Self::Full(Box::new(FullEntry {
+                object: Some(object.clone()),
+                ..FullEntry::new(EntryType::Image, &entry)
+            })),
+            PresetKind::Text { body } => Self::Full(Box::new(FullEntry {
+                body: Some(body.clone()),
+                ..FullEntry::new(EntryType::Text, &entry)
+            })),
+            PresetKind::Sticker {
+                package_id,
+                sticker_id,
+            } => Self::Full(Box::new(FullEntry {
+                package_id: Some(package_id.clone()),
+                sticker_id: Some(sticker_id.clone()),
+                ..FullEntry::new(EntryType::Sticker, &entry)
+            })),
+            PresetKind::Video { object, preview } => Self::Full(Box::new(FullEntry {
+                object: Some(object.clone()),
+                preview: Some(preview.clone()),
+                ..FullEntry::new(EntryType::Video, &entry)
+            })),
+            // Lists can't carry aliases, altText, caption, pattern or variants
+            // in this form
+            PresetKind::Messages { parts } => Self::Messages(
+                parts
+                    .iter()
+                    .map(|kind| PresetEntry::new(kind.clone()).into())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Every preset by trigger message, plus the aliases that also trigger
+/// them. Derefs to the map of canonical trigger messages, which is what
+/// listings and postback data use.
+pub(crate) struct Presets {
+    map: PresetMap,
+    /// Trigger message or alias, as matched -> canonical trigger message.
+    index: HashMap<String, String>,
+    /// Patterns in config order, with the trigger message each stands for.
+    patterns: Vec<(Regex, String)>,
+    /// Match texts exactly rather than normalized.
+    strict: bool,
+    /// Presets only a group or room has, by its id, tried before these.
+    overlays: HashMap<String, Presets>,
+}
+
+impl Presets {
+    /// The preset `text` triggers, by its trigger message or an alias or
+    /// else by the first pattern it matches, with the canonical trigger
+    /// message.
+    pub fn lookup(&self, text: &str) -> Option<(&String, &Preset)> {
+        let name = self.owner(text).or_else(|| {
+            let key = match_key(text, self.strict);
+            self.patterns
+                .iter()
+                .find(|(pattern, _)| pattern.is_match(&key))
+                .map(|(_, name)| name.as_str())
+        })?;
+        self.map.get_key_value(name)
+    }
+
+    /// Like `lookup`, but a group or room's own presets come first.
+    pub fn lookup_in(&self, chat_id: Option<&str>, text: &str) -> Option<(&String, &Preset)> {
+        chat_id
+            .and_then(|chat_id| self.overlays.get(chat_id))
+            .and_then(|overlay| overlay.lookup(text))
+            .or_else(|| self.lookup(text))
+    }
+
+    /// The canonical trigger message `text` would match, if any.
+    pub fn owner(&self, text: &str) -> Option<&str> {
+        self.index
+            .get(&match_key(text, self.strict))
+            .map(String::as_str)
+    }
+}
+
+/// How many near misses a "did you mean" reply offers.
+const MAX_SUGGESTIONS: usize = 3;
+
+impl Presets {
+    /// Canonical trigger messages close to `text`, nearest first: those
+    /// whose trigger message or an alias is within `threshold` edits per
+    /// character of it.
+    pub fn suggest(&self, text: &str, threshold: f64) -> Vec<&str> {
+        let key = match_key(text, self.strict);
+        if key.is_empty() {
+            return Vec::new();
+        }
+        let mut best: HashMap<&str, f64> = HashMap::new();
+        for (candidate, owner) in &self.index {
+            let distance = edit_distance(&key, candidate) as f64
+                / key.chars().count().max(candidate.chars().count()) as f64;
+            if distance > threshold {
+                continue;
+            }
+            let entry = best.entry(owner.as_str()).or_insert(distance);
+            *entry = entry.min(distance);
+        }
+        let mut ranked: Vec<(&str, f64)> = best.into_iter().collect();
+        ranked.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(b.0)));
+        ranked
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+/// Levenshtein distance in characters, so one kana counts as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Folds the differences users don't mean to make: NFKC (so full-width
+/// ASCII and half-width katakana become their usual forms), case, and
+/// surrounding or repeated whitespace.
+pub fn normalize(text: &str) -> String {
+    let text = ComposingNormalizerBorrowed::new_nfkc()
+        .normalize(text)
+        .to_lowercase();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn match_key(text: &str, strict: bool) -> String {
+    if strict {
+        text.to_string()
+    } else {
+        normalize(text)
+    }
+}
+
+impl std::ops::Deref for Presets {
+    type Target = PresetMap;
+
+    fn deref(&self) -> &PresetMap {
+        &self.map
+    }
+}
+
+/// The current presets, replaced as a whole on reload so a reader never
+/// sees half of an update.
+pub struct PresetStore {
+    storage: Arc<dyn Storage>,
+    current: RwLock<Arc<Presets>>,
+}
+
+impl PresetStore {
+    /// Loads the presets, failing when presets.json exists but is invalid.
+    pub async fn load(storage: Arc<dyn Storage>) -> anyhow::Result<Self> {
+        let pairs = read_entries(&*storage).await?;
+        let presets = build(pairs)?;
+        Ok(Self {
+            storage,
+            current: RwLock::new(Arc::new(presets)),
+        })
+    }
+
+    /// The presets as of now; a later reload doesn't change the returned map.
+    pub fn snapshot(&self) -> Arc<Presets> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replaces the group overlays as if GROUP_PRESETS were `json`.
+    #[cfg(test)]
+    pub async fn set_group_presets(&self, json: &str) -> anyhow::Result<()> {
+        let pairs = read_entries(&*self.storage).await?;
+        let mut presets = build_map(pairs, true)?;
+        presets.overlays = parse_group_presets(json)?;
+        *self.current.write().unwrap() = Arc::new(presets);
+        Ok(())
+    }
+
+    /// Reads presets.json again and makes it current. On error the
+    /// previous presets stay in place.
+    pub async fn reload(&self) -> anyhow::Result<Diff> {
+        let pairs = read_entries(&*self.storage).await?;
+        let presets = build(pairs)?;
+        let diff = Diff::between(&self.snapshot(), &presets);
+        *self.current.write().unwrap() = Arc::new(presets);
+        info!(
+            added = diff.added.len(),
+            removed = diff.removed.len(),
+            changed = diff.changed.len(),
+            "presets reloaded"
+        );
+        Ok(diff)
+    }
+
+    /// Makes `name` an image preset showing `object`, adding it if needed
+    /// and keeping its aliases if not. Returns whether it already existed.
+    /// `caption` replaces the caption when given, an empty one clearing
+    /// it; `None` keeps whatever caption the preset had.
+    pub async fn set_image(
+        &self,
+        name: &str,
+        object: &str,
+        caption: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let caption = caption.map(|caption| Some(caption.to_string()).filter(|c| !c.is_empty()));
+        self.edit(|pairs| match pairs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, preset)) => {
+                preset.kind = PresetKind::Image {
+                    object: object.to_string(),
+                };
+                if let Some(caption) = &caption {
+                    preset.caption = caption.clone();
+                }
+                true
+            }
+            None => {
+                let mut preset = PresetEntry::image(object);
+                preset.caption = caption.clone().flatten();
+                pairs.push((name.to_string(), preset));
+                false
+            }
+        })
+        .await
+    }
+
+    /// Drops the image or text preset `name`. Returns false when there was
+    /// none.
+    pub async fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        self.edit(|pairs| {
+            let before = pairs.len();
+            pairs.retain(|(n, _)| n != name);
+            pairs.len() != before
+        })
+        .await
+    }
+
+    /// Writes the configured presets, in presets.json's format, to a new
+    /// object under exports/ and returns its path.
+    pub async fn export(&self) -> anyhow::Result<String> {
+        let pairs = read_entries(&*self.storage).await?;
+        let object = format!(
+            "{}/presets-{}.json",
+            EXPORTS_DIR,
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        self.storage
+            .upload(&object, to_json(&pairs)?, "application/json")
+            .await?;
+        info!(object = %object, presets = pairs.len(), "presets exported");
+        Ok(object)
+    }
+
+    /// Replaces presets.json with an exported file, after checking it the
+    /// same way presets.json is checked at startup. Nothing changes when
+    /// it fails.
+    pub async fn import(&self, data: &[u8], source: &str) -> anyhow::Result<Diff> {
+        let pairs = serde_json::from_slice::<JsonPairs>(data)
+            .with_context(|| {
+                format!(
+                    "{} must be a JSON object mapping messages to presets",
+                    source
+                )
+            })?
+            .0;
+        let pairs = validate(pairs, source)?;
+        let before = self.snapshot();
+        self.edit(|current| *current = pairs.clone()).await?;
+        let diff = Diff::between(&before, &self.snapshot());
+        info!(
+            source,
+            added = diff.added.len(),
+            removed = diff.removed.len(),
+            changed = diff.changed.len(),
+            "presets imported"
+        );
+        Ok(diff)
+    }
+
+    /// Applies `change` to the image presets and writes them to
+    /// presets.json, which from then on replaces PRESETS and the built-in
+    /// presets. The write only goes through if the object is still the
+    /// one read, so a concurrent edit is retried rather than lost.
+    async fn edit<T>(
+        &self,
+        change: impl Fn(&mut Vec<(String, PresetEntry)>) -> T,
+    ) -> anyhow::Result<T> {
+        let mut edited = None;
+        storage::update_conditionally(
+            &*self.storage,
+            PRESETS_OBJECT,
+            "application/json",
+            MAX_EDIT_ATTEMPTS,
+            |data| {
+                let mut pairs = parse_entries(data.as_deref())?;
+                let result = change(&mut pairs);
+                let presets = build(pairs.clone())?;
+                let data = to_json(&pairs)?;
+                edited = Some((result, presets));
+                Ok(Some(data))
+            },
+        )
+        .await?;
+        let (result, presets) = edited.expect("an edit that was written ran its change");
+        *self.current.write().unwrap() = Arc::new(presets);
+        Ok(result)
+    }
+}
+
+/// Presets as presets.json holds them, sorted by trigger message.
+fn to_json(pairs: &[(String, PresetEntry)]) -> anyhow::Result<Vec<u8>> {
+    let object: BTreeMap<&str, &PresetEntry> = pairs
+        .iter()
+        .map(|(name, preset)| (name.as_str(), preset))
+        .collect();
+    Ok(serde_json::to_vec_pretty(&object)?)
+}
+
+/// Trigger messages that differ between two preset maps, each list sorted.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl Diff {
+    fn between(old: &PresetMap, new: &PresetMap) -> Self {
+        let mut diff = Self::default();
+        for (name, preset) in new {
+            match old.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(old) if old != preset => diff.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|name| !new.contains_key(*name))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Presets from presets.json, else PRESETS, else the built-in ones.
+async fn read_entries(storage: &dyn Storage) -> anyhow::Result<Vec<(String, PresetEntry)>> {
+    let data = storage.download_revision(PRESETS_OBJECT).await?;
+    parse_entries(data.map(|(data, _)| data).as_deref())
+}
+
+/// `read_entries` given presets.json's content, if it exists.
+fn parse_entries(data: Option<&[u8]>) -> anyhow::Result<Vec<(String, PresetEntry)>> {
+    Ok(match data {
+        Some(data) => {
+            let pairs = serde_json::from_slice::<JsonPairs>(data)
+                .with_context(|| {
+                    format!(
+                        "{} must be a JSON object mapping messages to presets",
+                        PRESETS_OBJECT
+                    )
+                })?
+                .0;
+            validate(pairs, PRESETS_OBJECT)?
+        }
+        None => match env::var("PRESETS") {
+            Ok(value) => parse_presets(&value)?,
+            Err(_) => DEFAULT_IMAGE_PRESETS
+                .iter()
+                .map(|(name, path)| (name.to_string(), PresetEntry::image(path)))
+                .collect(),
+        },
+    })
+}
+
+/// The configured presets plus the sticker and video presets, senders
+/// and group overlays from the environment.
+fn build(entries: Vec<(String, PresetEntry)>) -> anyhow::Result<Presets> {
+    let mut presets = build_map(entries, true)?;
+    presets.overlays = load_group_presets()?;
+    Ok(presets)
+}
+
+/// GROUP_PRESETS: `{"<groupId or roomId>": {<presets, as in PRESETS>}}`.
+/// A chat's presets are matched before the global ones, so they can
+/// shadow a global preset or add ones only that chat has.
+fn load_group_presets() -> anyhow::Result<HashMap<String, Presets>> {
+    match env::var("GROUP_PRESETS") {
+        Ok(json) => parse_group_presets(&json),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+fn parse_group_presets(json: &str) -> anyhow::Result<HashMap<String, Presets>> {
+    let chats: BTreeMap<String, serde_json::Value> = serde_json::from_str(json)
+        .context("GROUP_PRESETS must be a JSON object mapping group ids to presets")?;
+    chats
+        .into_iter()
+        .map(|(chat, value)| {
+            let source = format!("GROUP_PRESETS[{}]", chat);
+            let pairs = serde_json::from_value::<JsonPairs>(value)
+                .with_context(|| {
+                    format!(
+                        "{} must be a JSON object mapping messages to presets",
+                        source
+                    )
+                })?
+                .0;
+            let presets = build_map(validate(pairs, &source)?, false)
+                .with_context(|| format!("{} is invalid", source))?;
+            Ok((chat, presets))
+        })
+        .collect()
+}
+
+/// Builds presets from configured entries, adding the ones from the
+/// environment when `with_env` is set. Fails when an alias is taken
+/// twice, or shadows a trigger message.
+fn build_map(entries: Vec<(String, PresetEntry)>, with_env: bool) -> anyhow::Result<Presets> {
+    let patterns = entries
+        .iter()
+        .filter_map(|(name, entry)| Some((name, entry.pattern.as_deref()?)))
+        .map(|(name, pattern)| {
+            let regex = compile_pattern(pattern)
+                .with_context(|| format!("pattern of {:?} is invalid", name))?;
+            Ok((regex, name.clone()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    // 固定メッセージ -> 返信する画像・文章など
+    let mut presets: PresetMap = entries
+        .into_iter()
+        .map(|(name, entry)| {
+            let key = match &entry.kind {
+                PresetKind::Image { object } => object_stem(object).to_string(),
+                _ => name.clone(),
+            };
+            let preset = Preset {
+                key,
+                kind: entry.kind,
+                aliases: entry.aliases,
+                alt_text: entry.alt_text,
+                caption: entry.caption,
+                variants: entry.variants,
+                sender: None,
+            };
+            (name, preset)
+        })
+        .collect();
+
+    if with_env {
+        // 固定メッセージ=packageId:stickerId
+        for entry in env_list("STICKER_PRESETS") {
+            let parsed = entry.split_once('=').and_then(|(name, ids)| {
+                let (package_id, sticker_id) = ids.split_once(':')?;
+                Some((name.trim(), package_id.trim(), sticker_id.trim()))
+            });
+            let parsed = parsed.filter(|(name, ..)| name.chars().count() <= MAX_NAME_CHARS);
+            let Some((name, package_id, sticker_id)) = parsed else {
+                warn!("ignoring malformed STICKER_PRESETS entry: {}", entry);
+                continue;
+            };
+            let preset = Preset {
+                key: name.to_string(),
+                kind: PresetKind::Sticker {
+                    package_id: package_id.to_string(),
+                    sticker_id: sticker_id.to_string(),
+                },
+                aliases: Vec::new(),
+                alt_text: None,
+                caption: None,
+                variants: Vec::new(),
+                sender: None,
+            };
+            presets.insert(name.to_string(), preset);
+        }
+
+        // 固定メッセージ=動画のオブジェクトパス:プレビュー画像のオブジェクトパス
+        for entry in env_list("VIDEO_PRESETS") {
+            let parsed = entry.split_once('=').and_then(|(name, objects)| {
+                let (object, preview) = objects.split_once(':')?;
+                Some((name.trim(), object.trim(), preview.trim()))
+            });
+            let parsed = parsed.filter(|(name, ..)| name.chars().count() <= MAX_NAME_CHARS);
+            let Some((name, object, preview)) = parsed else {
+                warn!("ignoring malformed VIDEO_PRESETS entry: {}", entry);
+                continue;
+            };
+            let preset = Preset {
+                key: name.to_string(),
+                kind: PresetKind::Video {
+                    object: object.to_string(),
+                    preview: preview.to_string(),
+                },
+                aliases: Vec::new(),
+                alt_text: None,
+                caption: None,
+                variants: Vec::new(),
+                sender: None,
+            };
+            presets.insert(name.to_string(), preset);
+        }
+
+        // {"固定メッセージまたはキー": {"name": ..., "iconUrl": ...}}
+        if let Ok(json) = env::var("PRESET_SENDERS") {
+            match serde_json::from_str::<HashMap<String, Sender>>(&json) {
+                Ok(senders) => {
+                    for (name_or_key, sender) in senders {
+                        let name =
+                            find_preset(&presets, &name_or_key).map(|(name, _)| name.clone());
+                        match name.and_then(|name| presets.get_mut(&name)) {
+                            Some(preset) => preset.sender = Some(sender.validated()),
+                            None => {
+                                warn!("PRESET_SENDERS names an unknown preset: {}", name_or_key)
+                            }
+                        }
+                    }
+                }
+                Err(e) => warn!("ignoring malformed PRESET_SENDERS: {}", e),
+            }
+        }
+    }
+
+    let mut aliases: HashMap<&String, &String> = HashMap::new();
+    let mut names: Vec<&String> = presets.keys().collect();
+    names.sort();
+    for &name in &names {
+        for alias in &presets[name].aliases {
+            if presets.contains_key(alias) {
+                anyhow::bail!(
+                    "alias {:?} of {:?} is already the trigger message of {:?}",
+                    alias,
+                    name,
+                    alias
+                );
+            }
+            if let Some(owner) = aliases.insert(alias, name)
+                && owner != name
+            {
+                anyhow::bail!(
+                    "alias {:?} is used by both {:?} and {:?}",
+                    alias,
+                    owner,
+                    name
+                );
+            }
+        }
+    }
+
+    let strict = matches!(
+        env::var("PRESET_MATCH_STRICT").as_deref(),
+        Ok("1") | Ok("true") | Ok("on")
+    );
+    let mut index: HashMap<String, String> = HashMap::new();
+    for &name in &names {
+        for text in std::iter::once(name).chain(&presets[name].aliases) {
+            let key = match_key(text, strict);
+            if let Some(owner) = index.insert(key.clone(), name.clone())
+                && owner != *name
+            {
+                anyhow::bail!(
+                    "{:?} and {:?} both match {:?} once normalized; rename one or set PRESET_MATCH_STRICT",
+                    owner,
+                    name,
+                    key
+                );
+            }
+        }
+    }
+    Ok(Presets {
+        map: presets,
+        index,
+        patterns,
+        strict,
+        overlays: HashMap::new(),
+    })
+}
+
+/// Regexes run in linear time, so only their size needs bounding.
+fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    regex::RegexBuilder::new(pattern)
+        .size_limit(MAX_PATTERN_BYTES)
+        .build()
+}
+
+/// Parses PRESETS, either a JSON object (`{"ランチ": "images/lunch.jpg"}`)
+/// or a comma-separated `message=path` list, into trimmed pairs. Empty or
+/// repeated messages and malformed entries are errors naming the entry.
+fn parse_presets(value: &str) -> anyhow::Result<Vec<(String, PresetEntry)>> {
+    let pairs = if value.trim_start().starts_with('{') {
+        serde_json::from_str::<JsonPairs>(value)
+            .context("PRESETS must be a JSON object mapping messages to presets")?
+            .0
+    } else {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .map(|(name, path)| (name.to_string(), PresetEntry::image(path)))
+                    .with_context(|| {
+                        format!(
+                            "PRESETS entry {:?} must look like message=path",
+                            entry.trim()
+                        )
+                    })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+
+    validate(pairs, "PRESETS")
+}
+
+/// Trims `pairs` read from `source`, rejecting empty and repeated messages
+/// and malformed presets. Empty aliases are dropped.
+fn validate(
+    pairs: Vec<(String, PresetEntry)>,
+    source: &str,
+) -> anyhow::Result<Vec<(String, PresetEntry)>> {
+    let mut seen = HashSet::new();
+    let mut presets = Vec::with_capacity(pairs.len());
+    for (name, preset) in pairs {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            anyhow::bail!("{} has an entry with an empty message", source);
+        }
+        if name.chars().count() > MAX_NAME_CHARS {
+            anyhow::bail!(
+                "{} entry {:?} is longer than {} characters",
+                source,
+                name,
+                MAX_NAME_CHARS
+            );
+        }
+        let kind = match preset.kind {
+            PresetKind::Messages { parts } => {
+                if parts.is_empty() || parts.len() > line::MAX_MESSAGES_PER_REQUEST {
+                    anyhow::bail!(
+                        "{} entry {:?} lists {} messages; a reply takes 1 to {}",
+                        source,
+                        name,
+                        parts.len(),
+                        line::MAX_MESSAGES_PER_REQUEST
+                    );
+                }
+                let parts = parts
+                    .into_iter()
+                    .map(|part| {
+                        if let PresetKind::Video { .. } = part {
+                            anyhow::bail!(
+                                "{} entry {:?} lists a video; only images, text and stickers can be combined",
+                                source,
+                                name
+                            );
+                        }
+                        if let PresetKind::Text { body } = &part
+                            && body.chars().count() > line::MAX_TEXT_CHARS
+                        {
+                            anyhow::bail!(
+                                "{} entry {:?} lists a text longer than {} characters",
+                                source,
+                                name,
+                                line::MAX_TEXT_CHARS
+                            );
+                        }
+                        validate_kind(part, source, &name)
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                PresetKind::Messages { parts }
+            }
+            kind => validate_kind(kind, source, &name)?,
+        };
+        if !seen.insert(name.clone()) {
+            anyhow::bail!("{} lists {:?} more than once", source, name);
+        }
+        let aliases = preset
+            .aliases
+            .iter()
+            .map(|alias| alias.trim().to_string())
+            .filter(|alias| !alias.is_empty())
+            .collect();
+        let non_empty = |text: Option<String>| text.filter(|text| !text.trim().is_empty());
+        let alt_text = non_empty(preset.alt_text);
+        if let Some(alt_text) = &alt_text
+            && alt_text.chars().count() > MAX_ALT_TEXT_CHARS
+        {
+            anyhow::bail!(
+                "{} entry {:?} has an altText longer than {} characters",
+                source,
+                name,
+                MAX_ALT_TEXT_CHARS
+            );
+        }
+        let caption = non_empty(preset.caption);
+        if let Some(caption) = &caption
+            && caption.chars().count() > line::MAX_TEXT_CHARS
+        {
+            anyhow::bail!(
+                "{} entry {:?} has a caption longer than {} characters",
+                source,
+                name,
+                line::MAX_TEXT_CHARS
+            );
+        }
+        for (earlier, later) in schedule::overlaps(&preset.variants) {
+            warn!(
+                "{} entry {:?}: variant {} overlaps variant {}, which wins where both apply",
+                source,
+                name,
+                later + 1,
+                earlier + 1
+            );
+        }
+        let pattern = preset.pattern.filter(|pattern| !pattern.is_empty());
+        if let Some(pattern) = &pattern
+            && let Err(e) = compile_pattern(pattern)
+        {
+            anyhow::bail!("{} entry {:?} has an invalid pattern: {}", source, name, e);
+        }
+        presets.push((
+            name,
+            PresetEntry {
+                kind,
+                aliases,
+                alt_text,
+                caption,
+                pattern,
+                variants: preset.variants,
+            },
+        ));
+    }
+    Ok(presets)
+}
+
+/// Trims and checks one image, text, sticker or video.
+fn validate_kind(kind: PresetKind, source: &str, name: &str) -> anyhow::Result<PresetKind> {
+    let empty = |what: &str| anyhow::anyhow!("{} entry {:?} has an empty {}", source, name, what);
+    Ok(match kind {
+        PresetKind::Image { object } => {
+            let object = object.trim().to_string();
+            if object.is_empty() {
+                return Err(empty("object path"));
+            }
+            PresetKind::Image { object }
+        }
+        PresetKind::Text { body } => {
+            if body.trim().is_empty() {
+                return Err(empty("body"));
+            }
+            PresetKind::Text { body }
+        }
+        PresetKind::Sticker {
+            package_id,
+            sticker_id,
+        } => {
+            let (package_id, sticker_id) = (package_id.trim(), sticker_id.trim());
+            if package_id.is_empty() || sticker_id.is_empty() {
+                return Err(empty("sticker id"));
+            }
+            PresetKind::Sticker {
+                package_id: package_id.to_string(),
+                sticker_id: sticker_id.to_string(),
+            }
+        }
+        PresetKind::Video { object, preview } => {
+            let (object, preview) = (object.trim(), preview.trim());
+            if object.is_empty() || preview.is_empty() {
+                return Err(empty("object path"));
+            }
+            PresetKind::Video {
+                object: object.to_string(),
+                preview: preview.to_string(),
+            }
+        }
+        kind @ PresetKind::Messages { .. } => kind,
+    })
+}
+
+/// A JSON object's entries in order, repeats included, so a repeated
+/// message can be reported rather than silently overwritten.
+struct JsonPairs(Vec<(String, PresetEntry)>);
+
+impl<'de> Deserialize<'de> for JsonPairs {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = JsonPairs;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object of presets")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<JsonPairs, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some(pair) = map.next_entry()? {
+                    pairs.push(pair);
+                }
+                Ok(JsonPairs(pairs))
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// `images/food1.jpg` -> `food1`, the key an image preset goes by.
+fn object_stem(object: &str) -> &str {
+    let name = object.rsplit('/').next().unwrap_or(object);
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn images(pairs: &[(String, PresetEntry)]) -> Vec<(&str, PresetKind)> {
+        pairs
+            .iter()
+            .map(|(name, preset)| (name.as_str(), preset.kind.clone()))
+            .collect()
+    }
+
+    /// Presets as presets.json would configure them, leaving out the
+    /// environment.
+    fn configured(json: &str) -> anyhow::Result<Presets> {
+        let pairs = serde_json::from_str::<JsonPairs>(json)?.0;
+        build_map(validate(pairs, PRESETS_OBJECT)?, false)
+    }
+
+    fn image(object: &str) -> PresetKind {
+        PresetKind::Image {
+            object: object.to_string(),
+        }
+    }
+
+    #[test]
+    fn presets_parse_from_json() {
+        let pairs =
+            parse_presets(r#"{"menu1": "images/menu1.jpg", " ランチ ": " images/lunch.jpg "}"#)
+                .unwrap();
+        let mut parsed = images(&pairs);
+        parsed.sort_by(|a, b| a.0.cmp(b.0));
+        assert_eq!(
+            parsed,
+            [
+                ("menu1", image("images/menu1.jpg")),
+                ("ランチ", image("images/lunch.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn presets_parse_from_a_comma_separated_list() {
+        let pairs = parse_presets("menu1=images/menu1.jpg, ランチ = images/lunch.jpg,").unwrap();
+        assert_eq!(
+            images(&pairs),
+            [
+                ("menu1", image("images/menu1.jpg")),
+                ("ランチ", image("images/lunch.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_repeated_after_trimming_are_rejected() {
+        for value in [
+            "ランチ=images/a.jpg, ランチ =images/b.jpg",
+            r#"{"ランチ": "images/a.jpg", " ランチ": "images/b.jpg"}"#,
+        ] {
+            let error = parse_presets(value).unwrap_err();
+            assert_eq!(error.to_string(), "PRESETS lists \"ランチ\" more than once");
+        }
+    }
+
+    #[test]
+    fn empty_keys_and_paths_are_rejected() {
+        let error = parse_presets(" =images/a.jpg").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "PRESETS has an entry with an empty message"
+        );
+        let error = parse_presets("ランチ= ").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "PRESETS entry \"ランチ\" has an empty object path"
+        );
+    }
+
+    #[test]
+    fn long_keys_are_rejected() {
+        let name = "ラ".repeat(MAX_NAME_CHARS);
+        assert!(parse_presets(&format!("{}=images/a.jpg", name)).is_ok());
+        let name = "ラ".repeat(MAX_NAME_CHARS + 1);
+        let error = parse_presets(&format!("{}=images/a.jpg", name)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("PRESETS entry {:?} is longer than 100 characters", name)
+        );
+    }
+
+    #[test]
+    fn malformed_entries_are_named() {
+        let error = parse_presets("menu1=images/menu1.jpg, ランチ").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "PRESETS entry \"ランチ\" must look like message=path"
+        );
+        let error = parse_presets(r#"{"menu1": }"#).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("PRESETS must be a JSON object"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn presets_json_takes_the_place_of_the_defaults() {
+        let storage = Arc::new(MemoryStorage::default());
+        let store = PresetStore::load(storage.clone()).await.unwrap();
+        assert_eq!(store.snapshot().len(), DEFAULT_IMAGE_PRESETS.len());
+
+        storage.put(
+            PRESETS_OBJECT,
+            r#"{"ランチ": "images/lunch.jpg", "お知らせ": {"type": "text", "body": "本日休業"}}"#
+                .as_bytes()
+                .to_vec(),
+        );
+        let store = PresetStore::load(storage).await.unwrap();
+        let presets = store.snapshot();
+        assert_eq!(presets.len(), 2);
+        assert_eq!(presets["ランチ"].kind, image("images/lunch.jpg"));
+        assert_eq!(
+            presets["お知らせ"].kind,
+            PresetKind::Text {
+                body: "本日休業".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_presets_json_fails_the_reload_and_keeps_the_map() {
+        let storage = Arc::new(MemoryStorage::default());
+        let store = PresetStore::load(storage.clone()).await.unwrap();
+        storage.put(PRESETS_OBJECT, b"{not json".to_vec());
+        assert!(store.reload().await.is_err());
+        assert_eq!(store.snapshot().len(), DEFAULT_IMAGE_PRESETS.len());
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_canonical_trigger_message() {
+        let presets = configured(
+            r#"{
+                "食べ物メニュー": {"object": "images/food1.jpg", "aliases": ["メニュー", "menu"]},
+                "ランチ": "images/lunch.jpg"
+            }"#,
+        )
+        .unwrap();
+        for text in ["食べ物メニュー", "メニュー", "menu"] {
+            let (name, preset) = presets.lookup(text).unwrap();
+            assert_eq!(name, "食べ物メニュー");
+            assert_eq!(preset.key, "food1");
+        }
+        assert_eq!(presets.owner("ランチ"), Some("ランチ"));
+        // Listings only see canonical trigger messages
+        let mut names: Vec<&String> = presets.keys().collect();
+        names.sort();
+        assert_eq!(names, ["ランチ", "食べ物メニュー"]);
+    }
+
+    #[test]
+    fn an_alias_taken_twice_names_both_owners() {
+        let error = configured(
+            r#"{
+                "食べ物メニュー": {"object": "images/food1.jpg", "aliases": ["menu"]},
+                "ランチ": {"object": "images/lunch.jpg", "aliases": ["menu"]}
+            }"#,
+        )
+        .err()
+        .expect("the configuration should be refused");
+        assert_eq!(
+            error.to_string(),
+            "alias \"menu\" is used by both \"ランチ\" and \"食べ物メニュー\""
+        );
+    }
+
+    #[test]
+    fn an_alias_may_not_shadow_a_trigger_message() {
+        let error = configured(
+            r#"{
+                "食べ物メニュー": {"object": "images/food1.jpg", "aliases": ["ランチ"]},
+                "ランチ": "images/lunch.jpg"
+            }"#,
+        )
+        .err()
+        .expect("the configuration should be refused");
+        assert_eq!(
+            error.to_string(),
+            "alias \"ランチ\" of \"食べ物メニュー\" is already the trigger message of \"ランチ\""
+        );
+    }
+
+    #[test]
+    fn normalization_folds_width_case_and_spacing() {
+        assert_eq!(normalize("ｍｅｎｕ１"), "menu1");
+        assert_eq!(normalize(" MENU1 "), "menu1");
+        assert_eq!(normalize("ﾒﾆｭｰ"), "メニュー");
+        assert_eq!(normalize("メニュー\u{3000}"), "メニュー");
+        assert_eq!(normalize("食べ物\u{3000} \tメニュー"), "食べ物 メニュー");
+    }
+
+    #[test]
+    fn lookups_match_normalized_text() {
+        let presets =
+            configured(r#"{"menu1": "images/menu1.jpg", "メニュー": "images/m.jpg"}"#).unwrap();
+        for text in ["ｍｅｎｕ１", "MENU1 ", "menu1\u{3000}"] {
+            assert_eq!(presets.lookup(text).unwrap().0, "menu1", "{:?}", text);
+        }
+        assert_eq!(presets.lookup("ﾒﾆｭｰ").unwrap().0, "メニュー");
+    }
+
+    #[test]
+    fn triggers_equal_once_normalized_are_refused() {
+        let error = configured(r#"{"menu1": "images/a.jpg", "ＭＥＮＵ１": "images/b.jpg"}"#)
+            .err()
+            .expect("the configuration should be refused");
+        assert_eq!(
+            error.to_string(),
+            "\"menu1\" and \"ＭＥＮＵ１\" both match \"menu1\" once normalized; rename one or set PRESET_MATCH_STRICT"
+        );
+    }
+
+    fn entry(json: &str) -> Result<PresetEntry, String> {
+        PresetEntry::try_from(serde_json::from_str::<serde_json::Value>(json).unwrap())
+    }
+
+    #[test]
+    fn entries_read_as_plain_paths_or_typed_objects() {
+        assert_eq!(
+            entry(r#""images/a.jpg""#).unwrap().kind,
+            image("images/a.jpg")
+        );
+        assert_eq!(
+            entry(r#"{"object": "images/a.jpg"}"#).unwrap().kind,
+            image("images/a.jpg")
+        );
+        assert_eq!(
+            entry(r#"{"type": "text", "body": "11:00〜15:00"}"#)
+                .unwrap()
+                .kind,
+            PresetKind::Text {
+                body: "11:00〜15:00".to_string()
+            }
+        );
+        assert_eq!(
+            entry(r#"{"type": "text"}"#).err().unwrap(),
+            "a text preset needs a body"
+        );
+        assert!(entry(r#"{"type": "audio", "object": "a.m4a"}"#).is_err());
+    }
+
+    #[test]
+    fn plain_image_presets_are_written_back_as_paths() {
+        let pairs = vec![
+            ("ランチ".to_string(), PresetEntry::image("images/lunch.jpg")),
+            (
+                "営業時間".to_string(),
+                PresetEntry::new(PresetKind::Text {
+                    body: "11:00〜15:00".to_string(),
+                }),
+            ),
+        ];
+        let json: serde_json::Value = serde_json::from_slice(&to_json(&pairs).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "ランチ": "images/lunch.jpg",
+                "営業時間": {"type": "text", "body": "11:00〜15:00"},
+            })
+        );
+    }
+
+    #[test]
+    fn message_lists_take_one_to_five_messages() {
+        let six = serde_json::json!({ "おすすめ": vec!["images/a.jpg"; 6] }).to_string();
+        let error = configured(&six)
+            .err()
+            .expect("six messages should be refused");
+        assert_eq!(
+            error.to_string(),
+            "presets.json entry \"おすすめ\" lists 6 messages; a reply takes 1 to 5"
+        );
+        let error = configured(r#"{"おすすめ": []}"#)
+            .err()
+            .expect("no messages should be refused");
+        assert!(error.to_string().contains("lists 0 messages"), "{}", error);
+    }
+
+    #[test]
+    fn message_lists_hold_neither_videos_nor_lists() {
+        let error = configured(
+            r#"{"おすすめ": [{"type": "video", "object": "v.mp4", "preview": "v.jpg"}]}"#,
+        )
+        .err()
+        .expect("a video should be refused");
+        assert!(error.to_string().contains("lists a video"), "{}", error);
+        assert_eq!(
+            entry(r#"[["images/a.jpg"]]"#).err().unwrap(),
+            "message lists can't be nested"
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_characters_not_bytes() {
+        assert_eq!(edit_distance("メニュ", "メニュー"), 1);
+        assert_eq!(edit_distance("ランチ", "ランチ"), 0);
+        assert_eq!(edit_distance("飲み物", "のみもの"), 3);
+        assert_eq!(edit_distance("", "メニュー"), 4);
+    }
+
+    #[test]
+    fn suggestions_rank_nearest_first_within_the_threshold() {
+        let presets = configured(
+            r#"{
+                "メニュー": "images/menu.jpg",
+                "メニュー表": "images/list.jpg",
+                "ランチメニュー": {"object": "images/lunch.jpg", "aliases": ["menus"]},
+                "飲み物": "images/drink.jpg"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(presets.suggest("メニュ", 0.5), ["メニュー", "メニュー表"]);
+        assert_eq!(presets.suggest("メニュ", 0.3), ["メニュー"]);
+        assert!(presets.suggest("お会計", 0.5).is_empty());
+        // An alias suggests its canonical trigger message
+        assert_eq!(presets.suggest("menu", 0.3), ["ランチメニュー"]);
+    }
+
+    #[test]
+    fn suggestions_stop_at_three() {
+        let presets = configured(
+            r#"{"メニュー1": "a.jpg", "メニュー2": "b.jpg", "メニュー3": "c.jpg", "メニュー4": "d.jpg"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            presets.suggest("メニュー", 0.5),
+            ["メニュー1", "メニュー2", "メニュー3"]
+        );
+    }
+
+    #[test]
+    fn the_first_matching_pattern_in_config_order_wins() {
+        let presets = configured(
+            r#"{
+                "営業時間": {"type": "text", "body": "11:00〜22:00", "pattern": "営業時間|時間"},
+                "ランチ時間": {"type": "text", "body": "11:00〜14:00", "pattern": "ランチ"},
+                "ランチ": "images/lunch.jpg"
+            }"#,
+        )
+        .unwrap();
+        // Both patterns match; the earlier entry wins
+        assert_eq!(presets.lookup("ランチの時間は？").unwrap().0, "営業時間");
+        assert_eq!(
+            presets.lookup("ランチやってますか").unwrap().0,
+            "ランチ時間"
+        );
+        assert!(presets.lookup("こんにちは").is_none());
+    }
+
+    #[test]
+    fn exact_matches_come_before_patterns() {
+        let presets = configured(
+            r#"{
+                "営業時間": {"type": "text", "body": "11:00〜22:00", "pattern": "時間"},
+                "ランチ時間": "images/lunch.jpg"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(presets.lookup("ランチ時間").unwrap().0, "ランチ時間");
+        assert_eq!(presets.lookup("ディナーの時間").unwrap().0, "営業時間");
+    }
+
+    #[test]
+    fn patterns_run_against_normalized_text() {
+        let presets =
+            configured(r#"{"メニュー": {"object": "images/m.jpg", "pattern": "^menu\\d$"}}"#)
+                .unwrap();
+        assert_eq!(presets.lookup("ＭＥＮＵ１").unwrap().0, "メニュー");
+    }
+
+    #[test]
+    fn invalid_and_oversized_patterns_are_refused() {
+        let error = configured(r#"{"営業時間": {"object": "images/h.jpg", "pattern": "(時間"}}"#)
+            .err()
+            .expect("an unclosed group should be refused");
+        assert!(
+            error
+                .to_string()
+                .starts_with("presets.json entry \"営業時間\" has an invalid pattern"),
+            "{}",
+            error
+        );
+        assert!(compile_pattern(r"\w{1000}{1000}").is_err());
+    }
+
+    const EXPORTED: &str = r#"{
+        "食べ物メニュー": {"object": "images/food1.jpg", "aliases": ["メニュー"], "caption": "本日のおすすめです", "altText": "食べ物"},
+        "営業時間": {"type": "text", "body": "11:00〜22:00"},
+        "ありがとう": {"type": "sticker", "packageId": "446", "stickerId": "1989"},
+        "ランチ": {"default": "images/menu.jpg", "variants": [{"days": ["mon"], "from": "11:00", "to": "14:00", "object": "images/lunch.jpg"}]}
+    }"#;
+
+    #[tokio::test]
+    async fn an_export_imports_back_to_the_same_presets() {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.put(PRESETS_OBJECT, EXPORTED.as_bytes().to_vec());
+        let store = PresetStore::load(storage.clone()).await.unwrap();
+        let original = store.snapshot();
+
+        let object = store.export().await.unwrap();
+        assert!(object.starts_with("exports/presets-"), "{}", object);
+        let exported = storage.get(&object).unwrap();
+
+        assert!(store.remove("営業時間").await.unwrap());
+        store
+            .set_image("食べ物メニュー", "images/other.jpg", None)
+            .await
+            .unwrap();
+        let diff = store.import(&exported, &object).await.unwrap();
+        assert_eq!(diff.added, ["営業時間"]);
+        assert_eq!(diff.changed, ["食べ物メニュー"]);
+        assert!(diff.removed.is_empty());
+
+        let restored = store.snapshot();
+        assert_eq!(restored.len(), original.len());
+        for (name, preset) in original.iter() {
+            assert!(restored.get(name) == Some(preset), "{} differs", name);
+        }
+        assert_eq!(restored.owner("メニュー"), Some("食べ物メニュー"));
+        assert_eq!(storage.get(PRESETS_OBJECT).unwrap(), exported);
+        // Importing the same file again changes nothing
+        assert!(store.import(&exported, &object).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_import_with_a_duplicate_alias_changes_nothing() {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.put(PRESETS_OBJECT, EXPORTED.as_bytes().to_vec());
+        let store = PresetStore::load(storage.clone()).await.unwrap();
+        let bad = r#"{
+            "ランチ": {"object": "images/lunch.jpg", "aliases": ["menu"]},
+            "ディナー": {"object": "images/dinner.jpg", "aliases": ["menu"]}
+        }"#;
+        let error = store
+            .import(bad.as_bytes(), "exports/bad.json")
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("alias \"menu\" is used by both"),
+            "{:#}",
+            error
+        );
+        assert_eq!(store.snapshot().len(), 4);
+        assert_eq!(storage.get(PRESETS_OBJECT).unwrap(), EXPORTED.as_bytes());
+
+        assert!(store.import(b"[1, 2]", "exports/list.json").await.is_err());
+        assert_eq!(store.snapshot().len(), 4);
+    }
+
+    #[test]
+    fn a_chat_overlay_is_tried_before_the_global_presets() {
+        let mut presets =
+            configured(r#"{"ランチ": "images/lunch.jpg", "ディナー": "images/dinner.jpg"}"#)
+                .unwrap();
+        presets.overlays = parse_group_presets(
+            r#"{"Cstaff": {"ランチ": "images/staff-lunch.jpg", "まかない": "images/meal.jpg"}}"#,
+        )
+        .unwrap();
+        let object = |chat_id, text| match presets.lookup_in(chat_id, text) {
+            Some((_, preset)) => match &preset.kind {
+                PresetKind::Image { object } => Some(object.clone()),
+                _ => None,
+            },
+            None => None,
+        };
+        assert_eq!(
+            object(Some("Cstaff"), "ランチ").unwrap(),
+            "images/staff-lunch.jpg"
+        );
+        assert_eq!(
+            object(Some("Cstaff"), "ディナー").unwrap(),
+            "images/dinner.jpg"
+        );
+        assert_eq!(
+            object(Some("Cstaff"), "まかない").unwrap(),
+            "images/meal.jpg"
+        );
+        assert_eq!(
+            object(Some("Cother"), "ランチ").unwrap(),
+            "images/lunch.jpg"
+        );
+        assert_eq!(object(None, "ランチ").unwrap(), "images/lunch.jpg");
+        assert!(object(None, "まかない").is_none());
+    }
+
+    #[test]
+    fn group_presets_are_checked_like_presets_json() {
+        for (json, error) in [
+            (
+                "[]",
+                "GROUP_PRESETS must be a JSON object mapping group ids to presets",
+            ),
+            (
+                r#"{"C1": ["images/a.jpg"]}"#,
+                "GROUP_PRESETS[C1] must be a JSON object mapping messages to presets",
+            ),
+            (
+                r#"{"C1": {"ランチ": ""}}"#,
+                "GROUP_PRESETS[C1] entry \"ランチ\" has an empty object path",
+            ),
+        ] {
+            let message = parse_group_presets(json).err().expect(json).to_string();
+            assert_eq!(message, error);
+        }
+    }
+}
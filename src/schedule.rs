@@ -0,0 +1,321 @@
+use std::{env, str::FromStr};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, NaiveTime, Timelike, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_TIMEZONE: Tz = chrono_tz::Asia::Tokyo;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+/// An image a preset sends instead of its usual one between two times on
+/// some weekdays. A window whose `to` is not after its `from` runs past
+/// midnight into the next day.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "RawVariant", into = "RawVariant")]
+pub struct Variant {
+    /// Days the window starts on; empty means every day.
+    pub days: Vec<Weekday>,
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    pub object: String,
+}
+
+/// A variant as written: `{"days": ["mon", "fri"], "from": "11:00", "to":
+/// "14:00", "object": "images/lunch.jpg"}`.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawVariant {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    days: Vec<String>,
+    from: String,
+    to: String,
+    object: String,
+}
+
+impl TryFrom<RawVariant> for Variant {
+    type Error = String;
+
+    fn try_from(raw: RawVariant) -> Result<Self, String> {
+        let days = raw
+            .days
+            .iter()
+            .map(|day| Weekday::from_str(day.trim()).map_err(|_| format!("unknown day {:?}", day)))
+            .collect::<Result<_, _>>()?;
+        let time = |text: &str| {
+            NaiveTime::parse_from_str(text.trim(), "%H:%M")
+                .map_err(|_| format!("time {:?} must look like 11:00", text))
+        };
+        let (from, to) = (time(&raw.from)?, time(&raw.to)?);
+        if from == to {
+            return Err(format!("variant from {} to {} is empty", raw.from, raw.to));
+        }
+        let object = raw.object.trim().to_string();
+        if object.is_empty() {
+            return Err("a variant needs an object".to_string());
+        }
+        Ok(Self {
+            days,
+            from,
+            to,
+            object,
+        })
+    }
+}
+
+impl From<Variant> for RawVariant {
+    fn from(variant: Variant) -> Self {
+        Self {
+            days: variant
+                .days
+                .iter()
+                .map(|day| day.to_string().to_lowercase())
+                .collect(),
+            from: variant.from.format("%H:%M").to_string(),
+            to: variant.to.format("%H:%M").to_string(),
+            object: variant.object,
+        }
+    }
+}
+
+impl Variant {
+    /// Whether `now` falls inside this window, including the part of a
+    /// window that began the day before and runs past midnight.
+    pub fn covers(&self, now: DateTime<Tz>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+        if self.from < self.to {
+            self.runs_on(today) && self.from <= time && time < self.to
+        } else {
+            (self.runs_on(today) && self.from <= time)
+                || (self.runs_on(today.pred()) && time < self.to)
+        }
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// The window as half-open ranges of minutes since Monday 00:00.
+    fn week_minutes(&self) -> Vec<(u32, u32)> {
+        let minute = |time: NaiveTime| time.hour() * 60 + time.minute();
+        let (from, to) = (minute(self.from), minute(self.to));
+        let length = if from < to {
+            to - from
+        } else {
+            MINUTES_PER_DAY - from + to
+        };
+        let days: Vec<Weekday> = if self.days.is_empty() {
+            (0..7).map(|n| Weekday::try_from(n).unwrap()).collect()
+        } else {
+            self.days.clone()
+        };
+        let mut ranges = Vec::new();
+        for day in days {
+            let start = day.num_days_from_monday() * MINUTES_PER_DAY + from;
+            let end = start + length;
+            if end > MINUTES_PER_WEEK {
+                ranges.push((start, MINUTES_PER_WEEK));
+                ranges.push((0, end - MINUTES_PER_WEEK));
+            } else {
+                ranges.push((start, end));
+            }
+        }
+        ranges
+    }
+}
+
+/// The object of the first variant covering `now`.
+pub fn pick(variants: &[Variant], now: DateTime<Tz>) -> Option<&str> {
+    variants
+        .iter()
+        .find(|variant| variant.covers(now))
+        .map(|variant| variant.object.as_str())
+}
+
+/// Index pairs of variants whose windows share some minute of the week;
+/// the earlier of each pair is the one that gets sent.
+pub fn overlaps(variants: &[Variant]) -> Vec<(usize, usize)> {
+    let ranges: Vec<_> = variants.iter().map(Variant::week_minutes).collect();
+    let mut pairs = Vec::new();
+    for (i, a) in ranges.iter().enumerate() {
+        for (j, b) in ranges.iter().enumerate().skip(i + 1) {
+            let overlap = a
+                .iter()
+                .any(|(a0, a1)| b.iter().any(|(b0, b1)| a0 < b1 && b0 < a1));
+            if overlap {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// Reads PRESET_TIMEZONE, the zone schedule variants are written in: an
+/// IANA name such as `America/New_York`, `Asia/Tokyo` (the default) or
+/// `UTC`.
+pub fn load_timezone() -> anyhow::Result<Tz> {
+    let value = env::var("PRESET_TIMEZONE").unwrap_or_default();
+    match value.trim() {
+        "" => Ok(DEFAULT_TIMEZONE),
+        name => Tz::from_str(name).map_err(|_| {
+            anyhow!(
+                "PRESET_TIMEZONE {:?} must name a time zone like Asia/Tokyo",
+                name
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDateTime, TimeZone, Utc};
+    use chrono_tz::{America::New_York, Asia::Tokyo};
+
+    use super::*;
+
+    /// The clock reading `local` (e.g. "2024-03-08 13:59") in `zone`.
+    fn clock(zone: &Tz, local: &str) -> DateTime<Tz> {
+        let local = NaiveDateTime::parse_from_str(local, "%Y-%m-%d %H:%M").unwrap();
+        zone.from_local_datetime(&local).unwrap()
+    }
+
+    fn variant(json: serde_json::Value) -> Variant {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn lunch() -> Variant {
+        variant(serde_json::json!({
+            "days": ["mon", "tue", "wed", "thu", "fri"],
+            "from": "11:00",
+            "to": "14:00",
+            "object": "images/lunch.jpg"
+        }))
+    }
+
+    #[test]
+    fn weekday_windows_include_from_and_exclude_to() {
+        let zone = Tokyo;
+        let lunch = lunch();
+        // 2024-03-08 is a Friday
+        assert!(lunch.covers(clock(&zone, "2024-03-08 13:59")));
+        assert!(!lunch.covers(clock(&zone, "2024-03-08 14:00")));
+        assert!(!lunch.covers(clock(&zone, "2024-03-08 10:59")));
+        assert!(!lunch.covers(clock(&zone, "2024-03-09 12:00")));
+        assert!(lunch.covers(clock(&zone, "2024-03-11 11:00")));
+    }
+
+    #[test]
+    fn windows_past_midnight_belong_to_the_day_they_start() {
+        let zone = Tokyo;
+        let late = variant(serde_json::json!({
+            "days": ["fri"],
+            "from": "22:00",
+            "to": "02:00",
+            "object": "images/bar.jpg"
+        }));
+        assert!(!late.covers(clock(&zone, "2024-03-08 21:59")));
+        assert!(late.covers(clock(&zone, "2024-03-08 22:00")));
+        assert!(late.covers(clock(&zone, "2024-03-09 01:59")));
+        assert!(!late.covers(clock(&zone, "2024-03-09 02:00")));
+        // Monday 01:00 follows Sunday, which has no window
+        assert!(!late.covers(clock(&zone, "2024-03-11 01:00")));
+        // Friday 01:00 follows Thursday, which has none either
+        assert!(!late.covers(clock(&zone, "2024-03-08 01:00")));
+    }
+
+    #[test]
+    fn the_first_covering_variant_is_picked() {
+        let zone = Tokyo;
+        let all_day = variant(serde_json::json!({
+            "from": "10:00",
+            "to": "15:00",
+            "object": "images/all_day.jpg"
+        }));
+        let variants = vec![lunch(), all_day.clone()];
+        assert_eq!(
+            pick(&variants, clock(&zone, "2024-03-08 12:00")),
+            Some("images/lunch.jpg")
+        );
+        assert_eq!(
+            pick(&variants, clock(&zone, "2024-03-09 12:00")),
+            Some("images/all_day.jpg")
+        );
+        assert_eq!(pick(&variants, clock(&zone, "2024-03-09 16:00")), None);
+        assert_eq!(overlaps(&variants), vec![(0, 1)]);
+        assert!(
+            overlaps(&[
+                lunch(),
+                variant(serde_json::json!({
+                    "days": ["sat"],
+                    "from": "11:00",
+                    "to": "14:00",
+                    "object": "images/weekend.jpg"
+                }))
+            ])
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn overlaps_see_windows_wrapping_into_monday() {
+        let sunday_night = variant(serde_json::json!({
+            "days": ["sun"],
+            "from": "23:00",
+            "to": "01:00",
+            "object": "images/a.jpg"
+        }));
+        let monday_early = variant(serde_json::json!({
+            "days": ["mon"],
+            "from": "00:30",
+            "to": "02:00",
+            "object": "images/b.jpg"
+        }));
+        assert_eq!(overlaps(&[sunday_night, monday_early]), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn windows_follow_daylight_saving_in_the_zone() {
+        let daily = variant(serde_json::json!({
+            "from": "11:00",
+            "to": "14:00",
+            "object": "images/lunch.jpg"
+        }));
+        // 16:30 UTC is 11:30 before the switch on 2024-03-10 and 12:30 after
+        let before = Utc
+            .with_ymd_and_hms(2024, 3, 9, 16, 30, 0)
+            .unwrap()
+            .with_timezone(&New_York);
+        assert_eq!(before.format("%H:%M").to_string(), "11:30");
+        assert!(daily.covers(before));
+        // 15:30 UTC is 10:30 in winter but 11:30 once clocks go forward
+        let after = Utc
+            .with_ymd_and_hms(2024, 3, 11, 15, 30, 0)
+            .unwrap()
+            .with_timezone(&New_York);
+        assert_eq!(after.format("%H:%M").to_string(), "11:30");
+        assert!(daily.covers(after));
+        let winter = Utc
+            .with_ymd_and_hms(2024, 3, 8, 15, 30, 0)
+            .unwrap()
+            .with_timezone(&New_York);
+        assert!(!daily.covers(winter));
+    }
+
+    #[test]
+    fn malformed_variants_are_refused() {
+        for json in [
+            serde_json::json!({"days": ["funday"], "from": "11:00", "to": "14:00", "object": "a"}),
+            serde_json::json!({"from": "11am", "to": "14:00", "object": "a"}),
+            serde_json::json!({"from": "11:00", "to": "11:00", "object": "a"}),
+            serde_json::json!({"from": "11:00", "to": "14:00", "object": " "}),
+        ] {
+            assert!(
+                serde_json::from_value::<Variant>(json.clone()).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+}
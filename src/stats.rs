@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{
+    Channel,
+    storage::{self, Storage},
+};
+
+/// Daily stats objects live under here as `YYYY-MM-DD.json`.
+const STATS_DIR: &str = "stats";
+
+/// Counts are written out at least this often...
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// ...and sooner once this many events are waiting.
+const FLUSH_EVENTS: u64 = 100;
+
+/// Merges retried when another instance wrote the same day's object
+/// between our read and write.
+const MAX_MERGE_ATTEMPTS: u32 = 5;
+
+/// One day's preset usage, as stored.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DayStats {
+    /// Canonical trigger message -> replies sent for it.
+    #[serde(default)]
+    pub hits: BTreeMap<String, u64>,
+    /// Messages that matched no preset.
+    #[serde(default)]
+    pub misses: u64,
+}
+
+impl DayStats {
+    fn merge(&mut self, other: &DayStats) {
+        for (name, count) in &other.hits {
+            *self.hits.entry(name.clone()).or_insert(0) += count;
+        }
+        self.misses += other.misses;
+    }
+
+    fn events(&self) -> u64 {
+        self.hits.values().sum::<u64>() + self.misses
+    }
+
+    /// The `limit` most used presets, most used first.
+    pub fn top(&self, limit: usize) -> Vec<(&str, u64)> {
+        let mut hits: Vec<(&str, u64)> = self
+            .hits
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Preset hits and misses not yet written to storage, by the stats
+/// object they belong in.
+pub struct PresetStats {
+    storage: Arc<dyn Storage>,
+    /// Days roll over at midnight in this zone.
+    timezone: Tz,
+    pending: Mutex<HashMap<String, DayStats>>,
+    flush_now: Notify,
+    /// Set while the last flush left counts unwritten, so a pile of
+    /// pending events waits for the interval instead of retrying on
+    /// every record.
+    backing_off: AtomicBool,
+}
+
+impl PresetStats {
+    pub fn new(storage: Arc<dyn Storage>, timezone: Tz) -> Self {
+        Self {
+            storage,
+            timezone,
+            pending: Mutex::new(HashMap::new()),
+            flush_now: Notify::new(),
+            backing_off: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts a reply from the preset `name`.
+    pub fn hit(&self, channel: &Channel, name: &str) {
+        self.record(channel, |day| {
+            *day.hits.entry(name.to_string()).or_insert(0) += 1
+        });
+    }
+
+    /// Counts a message that matched no preset.
+    pub fn miss(&self, channel: &Channel) {
+        self.record(channel, |day| day.misses += 1);
+    }
+
+    fn record(&self, channel: &Channel, update: impl FnOnce(&mut DayStats)) {
+        let object = self.today_object(channel);
+        let mut pending = self.pending.lock().unwrap();
+        update(pending.entry(object).or_default());
+        if pending.values().map(DayStats::events).sum::<u64>() >= FLUSH_EVENTS
+            && !self.backing_off.load(Ordering::Relaxed)
+        {
+            self.flush_now.notify_one();
+        }
+    }
+
+    fn today_object(&self, channel: &Channel) -> String {
+        let today = Utc::now().with_timezone(&self.timezone);
+        channel.object_path(&format!("{}/{}.json", STATS_DIR, today.format("%Y-%m-%d")))
+    }
+
+    /// Today's stats for `channel`, stored and pending together.
+    pub async fn today(&self, channel: &Channel) -> anyhow::Result<(String, DayStats)> {
+        let object = self.today_object(channel);
+        let mut stats = match self.storage.download_revision(&object).await? {
+            Some((data, _)) => serde_json::from_slice(&data)?,
+            None => DayStats::default(),
+        };
+        if let Some(pending) = self.pending.lock().unwrap().get(&object) {
+            stats.merge(pending);
+        }
+        let day = object
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.strip_suffix(".json"))
+            .unwrap_or_default()
+            .to_string();
+        Ok((day, stats))
+    }
+
+    /// Adds the pending counts to the stored objects. Counts that fail to
+    /// write are kept for the next flush, which waits for the interval.
+    async fn flush(&self) {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        let mut failed = false;
+        for (object, counts) in pending {
+            if let Err(e) = self.merge_into(&object, &counts).await {
+                warn!(object = %object, "failed to write preset stats: {:#}", e);
+                failed = true;
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .entry(object)
+                    .or_default()
+                    .merge(&counts);
+            }
+        }
+        self.backing_off.store(failed, Ordering::Relaxed);
+    }
+
+    /// Adds `counts` to `object`, only writing if it's still the version
+    /// read so concurrent flushes from other instances aren't lost.
+    async fn merge_into(&self, object: &str, counts: &DayStats) -> anyhow::Result<()> {
+        let aside = format!("{}.unreadable", object);
+        let mut unreadable = None;
+        storage::update_conditionally(
+            &*self.storage,
+            object,
+            "application/json",
+            MAX_MERGE_ATTEMPTS,
+            |data| {
+                unreadable = None;
+                let mut stats = match data {
+                    Some(data) => match serde_json::from_slice::<DayStats>(&data) {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            warn!(object = %object, "moving unreadable preset stats to {}: {}", aside, e);
+                            unreadable = Some(data);
+                            DayStats::default()
+                        }
+                    },
+                    None => DayStats::default(),
+                };
+                stats.merge(counts);
+                Ok(Some(serde_json::to_vec_pretty(&stats)?))
+            },
+        )
+        .await?;
+        // Kept for a person to look at; the day starts over without them
+        if let Some(data) = unreadable {
+            self.storage
+                .upload(&aside, data, "application/json")
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes pending counts every minute, or sooner when enough pile up.
+pub async fn run_flusher(stats: Arc<PresetStats>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stats.flush_now.notified() => info!("flushing preset stats early"),
+        }
+        stats.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::PreconditionFailed, test_support::TestApp};
+
+    async fn setup() -> (TestApp, Arc<Channel>, PresetStats) {
+        let app = TestApp::new().await;
+        let channel = app.channel();
+        let stats = PresetStats::new(app.state.storage.clone(), Tz::UTC);
+        (app, channel, stats)
+    }
+
+    fn stored(app: &TestApp, object: &str) -> DayStats {
+        serde_json::from_slice(&app.storage.get(object).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hits_and_misses_add_up_per_preset() {
+        let (_app, channel, stats) = setup().await;
+        for name in ["ランチ", "ランチ", "ディナー", "ランチ"] {
+            stats.hit(&channel, name);
+        }
+        stats.miss(&channel);
+        stats.miss(&channel);
+        let (day, today) = stats.today(&channel).await.unwrap();
+        assert_eq!(day, chrono::Utc::now().format("%Y-%m-%d").to_string());
+        assert_eq!(today.top(10), vec![("ランチ", 3), ("ディナー", 1)]);
+        assert_eq!(today.misses, 2);
+        assert_eq!(today.events(), 6);
+    }
+
+    #[tokio::test]
+    async fn enough_events_ask_for_an_early_flush() {
+        let (_app, channel, stats) = setup().await;
+        for _ in 0..FLUSH_EVENTS - 1 {
+            stats.miss(&channel);
+        }
+        let early = Duration::from_millis(20);
+        assert!(
+            tokio::time::timeout(early, stats.flush_now.notified())
+                .await
+                .is_err()
+        );
+        stats.hit(&channel, "ランチ");
+        assert!(
+            tokio::time::timeout(early, stats.flush_now.notified())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn top_breaks_ties_by_name_and_stops_at_the_limit() {
+        let day = DayStats {
+            hits: [("b", 2), ("a", 2), ("c", 5), ("d", 1)]
+                .into_iter()
+                .map(|(name, count)| (name.to_string(), count))
+                .collect(),
+            misses: 0,
+        };
+        assert_eq!(day.top(3), vec![("c", 5), ("a", 2), ("b", 2)]);
+    }
+
+    #[tokio::test]
+    async fn a_flush_adds_to_the_stored_day() {
+        let (app, channel, stats) = setup().await;
+        let object = stats.today_object(&channel);
+        app.storage.put(
+            &object,
+            r#"{"hits": {"ランチ": 2, "ディナー": 1}, "misses": 4}"#
+                .as_bytes()
+                .to_vec(),
+        );
+        stats.hit(&channel, "ランチ");
+        stats.hit(&channel, "おすすめ");
+        stats.miss(&channel);
+        stats.flush().await;
+
+        let day = stored(&app, &object);
+        assert_eq!(
+            day.top(10),
+            vec![("ランチ", 3), ("おすすめ", 1), ("ディナー", 1)]
+        );
+        assert_eq!(day.misses, 5);
+        assert!(stats.pending.lock().unwrap().is_empty());
+        // Nothing pending means today() is just what's stored
+        let (_, today) = stats.today(&channel).await.unwrap();
+        assert_eq!(today.events(), day.events());
+    }
+
+    #[tokio::test]
+    async fn a_conflicting_write_is_merged_again() {
+        let (app, channel, stats) = setup().await;
+        let object = stats.today_object(&channel);
+        app.storage
+            .put(&object, r#"{"hits": {"ランチ": 1}}"#.as_bytes().to_vec());
+        app.storage.fail_next(
+            "upload_if",
+            &object,
+            PreconditionFailed {
+                object: object.clone(),
+            },
+        );
+        stats.hit(&channel, "ランチ");
+        stats.flush().await;
+        assert_eq!(stored(&app, &object).top(10), vec![("ランチ", 2)]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_flush_holds_off_early_flushes() {
+        let (app, channel, stats) = setup().await;
+        let object = stats.today_object(&channel);
+        app.storage
+            .fail_next("upload_if", &object, anyhow::anyhow!("storage is down"));
+        stats.hit(&channel, "ランチ");
+        stats.flush().await;
+        for _ in 0..FLUSH_EVENTS {
+            stats.miss(&channel);
+        }
+        let early = Duration::from_millis(20);
+        assert!(
+            tokio::time::timeout(early, stats.flush_now.notified())
+                .await
+                .is_err()
+        );
+
+        // Once a flush gets through, early flushes are back
+        stats.flush().await;
+        for _ in 0..FLUSH_EVENTS {
+            stats.miss(&channel);
+        }
+        assert!(
+            tokio::time::timeout(early, stats.flush_now.notified())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_day_is_moved_aside() {
+        let (app, channel, stats) = setup().await;
+        let object = stats.today_object(&channel);
+        app.storage.put(&object, b"{not json".to_vec());
+        stats.hit(&channel, "ランチ");
+        stats.flush().await;
+        assert_eq!(stored(&app, &object).top(10), vec![("ランチ", 1)]);
+        assert_eq!(
+            app.storage.get(&format!("{}.unreadable", object)).unwrap(),
+            b"{not json"
+        );
+        assert!(stats.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_that_fail_to_write_wait_for_the_next_flush() {
+        let (app, channel, stats) = setup().await;
+        let object = stats.today_object(&channel);
+        app.storage
+            .fail_next("upload_if", &object, anyhow::anyhow!("storage is down"));
+        stats.hit(&channel, "ランチ");
+        stats.flush().await;
+        assert!(app.storage.get(&object).is_none());
+
+        stats.hit(&channel, "ランチ");
+        stats.miss(&channel);
+        stats.flush().await;
+        let day = stored(&app, &object);
+        assert_eq!(day.top(10), vec![("ランチ", 2)]);
+        assert_eq!(day.misses, 1);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::Rng;
+use tracing::warn;
+
+mod gcs;
+mod local;
+#[cfg(test)]
+mod memory;
+mod prefixed;
+mod s3;
+
+pub use gcs::{GcsStorage, Resumable};
+pub use local::LocalStorage;
+#[cfg(test)]
+pub use memory::MemoryStorage;
+pub use prefixed::{PrefixedStorage, join_path};
+pub use s3::S3Storage;
+
+/// Where presets, uploads and everything else the bot keeps are stored.
+/// Object names are `/`-separated paths such as `images/food1.jpg`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// A URL LINE can fetch the object from.
+    async fn url(&self, object: &str) -> anyhow::Result<String>;
+
+    async fn upload(&self, object: &str, data: Vec<u8>, content_type: &str) -> anyhow::Result<()>;
+
+    /// Pipes an HTTP response body into a new object chunk by chunk, so a
+    /// large upload never sits in memory as a whole. `prefix` holds bytes
+    /// already read off the body, which count towards `max_bytes`. Fails
+    /// with `TooLarge`, leaving no object behind, once the body grows past
+    /// it. Returns how many bytes were stored.
+    async fn upload_stream(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        body: reqwest::Response,
+        content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64>;
+
+    /// Merges `entries` into the object's custom metadata.
+    async fn set_metadata(&self, object: &str, entries: &[(&str, String)]) -> anyhow::Result<()>;
+
+    /// The object's custom metadata, empty when it has none.
+    async fn metadata(&self, object: &str) -> anyhow::Result<HashMap<String, String>>;
+
+    /// Every object whose name starts with `prefix`.
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectInfo>>;
+
+    async fn download(&self, object: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// The object's content along with its current revision, or None when
+    /// it doesn't exist. Pairs with `upload_if` for read-modify-write.
+    async fn download_revision(&self, object: &str) -> anyhow::Result<Option<(Vec<u8>, String)>>;
+
+    /// Writes the object only if it is still at `revision`, or, given None,
+    /// only if it doesn't exist yet. Fails with `PreconditionFailed` when
+    /// another writer got there first.
+    async fn upload_if(
+        &self,
+        object: &str,
+        data: Vec<u8>,
+        content_type: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    async fn exists(&self, object: &str) -> anyhow::Result<bool>;
+
+    /// Size and times of the object, or None when it doesn't exist.
+    async fn stat(&self, object: &str) -> anyhow::Result<Option<ObjectInfo>>;
+
+    /// Removes the object. One that is already gone counts as deleted, so
+    /// a retried webhook or a racing cleanup doesn't turn into an error.
+    async fn delete(&self, object: &str) -> anyhow::Result<()>;
+
+    async fn copy(&self, source: &str, dest: &str) -> anyhow::Result<()>;
+}
+
+/// A failed storage call, classified by whether trying again later could help.
+#[derive(Debug)]
+pub struct StorageError {
+    pub what: &'static str,
+    /// Throttling, a 5xx or a dropped connection, as opposed to something
+    /// like missing permissions or a wrong bucket name.
+    pub transient: bool,
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl StorageError {
+    pub fn new(
+        what: &'static str,
+        transient: bool,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            what,
+            transient,
+            source: source.into(),
+        }
+    }
+
+    /// The backend's own error, when it is an `E`.
+    pub fn source_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        self.source.downcast_ref()
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "storage {} failed: {}",
+            self.what,
+            self.source.to_string().trim_end()
+        )
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Attempts made at each storage call before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Runs `op` until it succeeds, fails permanently or runs out of attempts,
+/// backing off exponentially with jitter in between.
+async fn retry<T, Fut>(what: &'static str, op: impl Fn() -> Fut) -> Result<T, StorageError>
+where
+    Fut: Future<Output = Result<T, StorageError>>,
+{
+    let mut attempt = 1;
+    loop {
+        let e = match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if !e.transient || attempt >= MAX_ATTEMPTS {
+            return Err(e);
+        }
+        let base = BASE_RETRY_DELAY * 2u32.pow(attempt - 1);
+        let jitter = rand::rng().random_range(0..=base.as_millis() as u64 / 2);
+        let delay = base + Duration::from_millis(jitter);
+        warn!(what, attempt, error = %e, ?delay, "retrying");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so object names
+/// with spaces or Japanese text make valid URLs. Slashes are kept in paths
+/// and encoded in query values.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// `https://img.example.com` + `images/ランチ.jpg` ->
+/// `https://img.example.com/images/%E3%83%A9...jpg`
+fn cdn_url(base: &str, object: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        uri_encode(object.trim_start_matches('/'), false)
+    )
+}
+
+/// An upload refused for exceeding its size limit.
+#[derive(Debug)]
+pub struct TooLarge {
+    /// The full size, when the sender declared it up front.
+    pub size: Option<u64>,
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            Some(size) => write!(
+                f,
+                "content is {} bytes, over the {} byte limit",
+                size, self.limit
+            ),
+            None => write!(f, "content exceeded the {} byte limit", self.limit),
+        }
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// A conditional write refused because the object changed since it was read.
+#[derive(Debug)]
+pub struct PreconditionFailed {
+    pub object: String,
+}
+
+impl fmt::Display for PreconditionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} was changed by another writer", self.object)
+    }
+}
+
+impl std::error::Error for PreconditionFailed {}
+
+/// Rewrites `object` with what `update` makes of its current content
+/// (None when it doesn't exist yet), only writing if no one else wrote it
+/// since it was read. After a conflicting write `update` runs again on the
+/// newer content, up to `max_attempts` times in all. `update` returning
+/// None leaves the object as it is.
+pub async fn update_conditionally(
+    storage: &dyn Storage,
+    object: &str,
+    content_type: &str,
+    max_attempts: u32,
+    mut update: impl FnMut(Option<Vec<u8>>) -> anyhow::Result<Option<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    for attempt in 1..=max_attempts {
+        let (data, revision) = match storage.download_revision(object).await? {
+            Some((data, revision)) => (Some(data), Some(revision)),
+            None => (None, None),
+        };
+        let Some(data) = update(data)? else {
+            return Ok(());
+        };
+        match storage
+            .upload_if(object, data, content_type, revision.as_deref())
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is::<PreconditionFailed>() => {
+                warn!(
+                    object,
+                    attempt, "{} changed underneath us; retrying", object
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    anyhow::bail!(
+        "gave up writing {} after {} conflicting writes",
+        object,
+        max_attempts
+    )
+}
+
+/// An object as returned by `Storage::list` and `Storage::stat`.
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub created: SystemTime,
+    pub updated: SystemTime,
+    pub size: u64,
+    /// What `upload_if` compares against: a GCS generation or an S3 ETag.
+    pub revision: Option<String>,
+}
+
+/// How URLs handed to LINE point at objects.
+#[derive(Clone, Debug)]
+pub enum UrlMode {
+    /// Plain object URLs; the bucket must be publicly readable.
+    Public,
+    /// Plain URLs under PUBLIC_URL_BASE, such as a CDN on our own domain
+    /// in front of the bucket. Kept without a trailing slash.
+    Cdn { base: String },
+    /// V4 signed URLs valid for this many seconds.
+    Signed { expires_in_secs: u32 },
+}
+
+/// LINE fetches media when it delivers the message, which can lag well
+/// behind the send, so signed URLs never expire sooner than this.
+pub const MIN_SIGNED_URL_EXPIRY_SECS: u32 = 600;
+
+impl UrlMode {
+    /// Reads STORAGE_URL_MODE, SIGNED_URL_EXPIRY_SECS and PUBLIC_URL_BASE.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_vars(
+            env::var("STORAGE_URL_MODE").ok().as_deref(),
+            env::var("SIGNED_URL_EXPIRY_SECS").ok().as_deref(),
+            env::var("PUBLIC_URL_BASE").ok().as_deref(),
+        )
+    }
+
+    fn from_vars(
+        mode: Option<&str>,
+        expiry_secs: Option<&str>,
+        public_base: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let public_base = public_base
+            .map(|v| v.trim().trim_end_matches('/').to_string())
+            .filter(|v| !v.is_empty());
+        if let Some(base) = public_base {
+            if mode == Some("signed") {
+                anyhow::bail!(
+                    "PUBLIC_URL_BASE serves plain URLs and can't be combined with STORAGE_URL_MODE=signed; unset one of them"
+                );
+            }
+            let url = url::Url::parse(&base)
+                .with_context(|| format!("PUBLIC_URL_BASE is not a valid URL: {}", base))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                anyhow::bail!("PUBLIC_URL_BASE must be an http(s) URL, got {}", base);
+            }
+            return Ok(Self::Cdn { base });
+        }
+        match mode {
+            Some("public") => Ok(Self::Public),
+            Some("signed") | None => {
+                let expires_in_secs = expiry_secs.and_then(|v| v.parse().ok()).unwrap_or(3600);
+                if expires_in_secs < MIN_SIGNED_URL_EXPIRY_SECS {
+                    warn!(
+                        expires_in_secs,
+                        "SIGNED_URL_EXPIRY_SECS is too short for LINE delivery; using {}",
+                        MIN_SIGNED_URL_EXPIRY_SECS
+                    );
+                }
+                Ok(Self::Signed {
+                    expires_in_secs: expires_in_secs.max(MIN_SIGNED_URL_EXPIRY_SECS),
+                })
+            }
+            Some(other) => {
+                anyhow::bail!("STORAGE_URL_MODE must be public or signed, got {}", other)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// An operation failing with `errors` in turn, then succeeding.
+    fn scripted(
+        calls: &AtomicU32,
+        errors: &[bool],
+    ) -> impl Future<Output = Result<u32, StorageError>> {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+        let result = match errors.get(call as usize) {
+            Some(&transient) => Err(StorageError::new(
+                "upload",
+                transient,
+                std::io::Error::other("503 Service Unavailable"),
+            )),
+            None => Ok(call + 1),
+        };
+        async move { result }
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_until_success() {
+        let calls = AtomicU32::new(0);
+        let result = retry("upload", || scripted(&calls, &[true, true])).await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let e = retry("upload", || scripted(&calls, &[false]))
+            .await
+            .unwrap_err();
+        assert!(!e.transient);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_last_attempt() {
+        let calls = AtomicU32::new(0);
+        let e = retry("upload", || scripted(&calls, &[true; 4]))
+            .await
+            .unwrap_err();
+        assert!(e.transient);
+        assert_eq!(calls.load(Ordering::SeqCst), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn a_conflicting_write_reruns_the_update_on_the_newer_content() {
+        let storage = MemoryStorage::default();
+        storage.put("log", "a");
+        let mut seen = Vec::new();
+        update_conditionally(&storage, "log", "text/plain", 3, |data| {
+            let data = data.unwrap();
+            if seen.is_empty() {
+                // Another writer gets in between the read and the write
+                storage.put("log", "ab");
+            }
+            seen.push(String::from_utf8(data.clone()).unwrap());
+            Ok(Some([data, b"c".to_vec()].concat()))
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen, ["a", "ab"]);
+        assert_eq!(storage.get("log").unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn conditional_updates_give_up_after_the_last_attempt() {
+        let storage = MemoryStorage::default();
+        for _ in 0..2 {
+            storage.fail_next(
+                "upload_if",
+                "log",
+                PreconditionFailed {
+                    object: "log".to_string(),
+                },
+            );
+        }
+        let e = update_conditionally(&storage, "log", "text/plain", 2, |_| {
+            Ok(Some(b"a".to_vec()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "gave up writing log after 2 conflicting writes"
+        );
+        assert!(storage.get("log").is_none());
+
+        // Nothing to change writes nothing
+        update_conditionally(&storage, "log", "text/plain", 2, |_| Ok(None))
+            .await
+            .unwrap();
+        assert!(storage.get("log").is_none());
+    }
+
+    #[test]
+    fn cdn_urls_join_with_exactly_one_slash() {
+        for base in ["https://img.example.com", "https://img.example.com/"] {
+            for object in ["images/food1.jpg", "/images/food1.jpg"] {
+                assert_eq!(
+                    cdn_url(base, object),
+                    "https://img.example.com/images/food1.jpg"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cdn_urls_encode_japanese_names_and_spaces() {
+        assert_eq!(
+            cdn_url("https://img.example.com/menu", "images/夜 ランチ.jpg"),
+            "https://img.example.com/menu/images/%E5%A4%9C%20%E3%83%A9%E3%83%B3%E3%83%81.jpg"
+        );
+    }
+
+    #[test]
+    fn a_public_base_selects_cdn_urls() {
+        let mode = UrlMode::from_vars(None, None, Some(" https://img.example.com/ ")).unwrap();
+        assert!(
+            matches!(mode, UrlMode::Cdn { ref base } if base == "https://img.example.com"),
+            "{:?}",
+            mode
+        );
+        // An empty base is as good as none
+        let mode = UrlMode::from_vars(Some("public"), None, Some("")).unwrap();
+        assert!(matches!(mode, UrlMode::Public), "{:?}", mode);
+    }
+
+    #[test]
+    fn signed_urls_and_a_public_base_are_exclusive() {
+        let error =
+            UrlMode::from_vars(Some("signed"), None, Some("https://img.example.com")).unwrap_err();
+        assert!(error.to_string().contains("PUBLIC_URL_BASE"), "{}", error);
+    }
+
+    #[test]
+    fn a_public_base_must_be_an_http_url() {
+        for base in ["img.example.com", "ftp://img.example.com"] {
+            assert!(
+                UrlMode::from_vars(None, None, Some(base)).is_err(),
+                "{}",
+                base
+            );
+        }
+    }
+
+    #[test]
+    fn signed_url_expiry_has_a_floor() {
+        let mode = UrlMode::from_vars(None, Some("60"), None).unwrap();
+        assert!(
+            matches!(mode, UrlMode::Signed { expires_in_secs } if expires_in_secs == MIN_SIGNED_URL_EXPIRY_SECS),
+            "{:?}",
+            mode
+        );
+    }
+}
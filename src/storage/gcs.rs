@@ -0,0 +1,965 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use cloud_storage::{Token, TokenCache};
+use reqwest::{StatusCode, header};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::{
+    ObjectInfo, PreconditionFailed, Storage, StorageError, TooLarge, UrlMode, cdn_url, retry,
+    uri_encode,
+};
+
+mod resumable;
+mod signing;
+
+pub use resumable::Resumable;
+use signing::UrlSigner;
+
+const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// The GCS bucket everything lives in, spoken to over the JSON API with
+/// one shared client so credentials and connections are set up once
+/// rather than per call.
+pub struct GcsStorage {
+    http: reqwest::Client,
+    bucket: String,
+    url_mode: UrlMode,
+    /// `https://storage.googleapis.com`, or an emulator's address.
+    endpoint: String,
+    /// None against an emulator, which takes no credentials.
+    auth: Option<Auth>,
+    /// Set when URLs are signed.
+    signer: Option<UrlSigner>,
+    resumable: Resumable,
+}
+
+/// The service account's access token, cached until it nears expiry.
+struct Auth {
+    token: Token,
+    /// What `token` refreshes itself with, on the reqwest cloud-storage uses.
+    http: reqwest_011::Client,
+}
+
+/// A non-success response from GCS.
+#[derive(Debug)]
+pub struct GcsError {
+    pub status: StatusCode,
+    /// `error.message` from the JSON body, if there was one.
+    pub message: Option<String>,
+}
+
+impl fmt::Display for GcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{} ({})", self.status, message),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+impl std::error::Error for GcsError {}
+
+/// The parts of an object resource the backend uses.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Resource {
+    name: String,
+    /// int64 fields come as strings.
+    #[serde(default)]
+    size: String,
+    time_created: Option<String>,
+    updated: Option<String>,
+    generation: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListPage {
+    #[serde(default)]
+    items: Vec<Resource>,
+    next_page_token: Option<String>,
+}
+
+fn object_info(object: Resource) -> ObjectInfo {
+    let time = |at: Option<&str>| {
+        at.and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .map_or(UNIX_EPOCH, |at| {
+                UNIX_EPOCH + Duration::from_secs(at.timestamp().max(0) as u64)
+            })
+    };
+    let updated = time(object.updated.as_deref());
+    ObjectInfo {
+        created: object
+            .time_created
+            .as_deref()
+            .map_or(updated, |at| time(Some(at))),
+        updated,
+        size: object.size.parse().unwrap_or(0),
+        revision: object.generation,
+        name: object.name,
+    }
+}
+
+fn is_not_found(e: &StorageError) -> bool {
+    e.source_ref::<GcsError>()
+        .is_some_and(|e| e.status == StatusCode::NOT_FOUND)
+}
+
+impl GcsStorage {
+    /// Talks to GCS over `http`, the bot's configured client. Loads the
+    /// service account key up front when URLs are signed, so a missing or
+    /// broken key fails startup rather than the first reply.
+    pub fn new(
+        http: reqwest::Client,
+        bucket: String,
+        url_mode: UrlMode,
+        resumable: Resumable,
+    ) -> anyhow::Result<Self> {
+        let signer = match url_mode {
+            UrlMode::Signed { .. } => Some(UrlSigner::from_env()?),
+            _ => None,
+        };
+        Ok(Self {
+            http,
+            bucket,
+            url_mode,
+            endpoint: GCS_ENDPOINT.to_string(),
+            auth: Some(Auth {
+                token: Token::default(),
+                http: reqwest_011::Client::new(),
+            }),
+            signer,
+            resumable,
+        })
+    }
+
+    /// Talks to a GCS emulator such as fake-gcs-server at `host` (as in
+    /// STORAGE_EMULATOR_HOST, with or without a scheme) instead of GCS.
+    /// No credentials are loaded, and URLs are always plain object URLs
+    /// on the emulator, since there is no key to sign them with.
+    pub fn emulator(
+        http: reqwest::Client,
+        bucket: String,
+        host: &str,
+        resumable: Resumable,
+    ) -> Self {
+        let host = host.trim_end_matches('/');
+        let endpoint = if host.contains("://") {
+            host.to_string()
+        } else {
+            format!("http://{}", host)
+        };
+        Self {
+            http,
+            bucket,
+            url_mode: UrlMode::Public,
+            endpoint,
+            auth: None,
+            signer: None,
+            resumable,
+        }
+    }
+
+    /// `{endpoint}/{api}/b/{bucket}/o`, followed by `object` as a single
+    /// path segment when given.
+    fn api_url(&self, api: &str, object: Option<&str>) -> url::Url {
+        let mut url = url::Url::parse(&self.endpoint).expect("GCS endpoint is a valid URL");
+        {
+            let mut segments = url.path_segments_mut().expect("GCS endpoint has a path");
+            segments.pop_if_empty();
+            segments.extend(api.split('/'));
+            segments.extend(["b", &self.bucket, "o"]);
+            if let Some(object) = object {
+                segments.push(object);
+            }
+        }
+        url
+    }
+
+    fn object_url(&self, object: &str) -> url::Url {
+        self.api_url("storage/v1", Some(object))
+    }
+
+    fn upload_url(&self) -> url::Url {
+        self.api_url("upload/storage/v1", None)
+    }
+
+    /// Adds the access token, when there are credentials to add.
+    async fn authorize(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, StorageError> {
+        let Some(auth) = &self.auth else {
+            return Ok(request);
+        };
+        let token = auth.token.get(&auth.http).await.map_err(|e| {
+            let transient =
+                matches!(&e, cloud_storage::Error::Reqwest(e) if e.is_connect() || e.is_timeout());
+            StorageError::new("authenticate", transient, e)
+        })?;
+        Ok(request.bearer_auth(token))
+    }
+
+    /// Sends a request built afresh for every attempt, turning non-success
+    /// statuses into errors.
+    async fn send(
+        &self,
+        what: &'static str,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, StorageError> {
+        retry(what, || async {
+            let resp = self
+                .authorize(build())
+                .await?
+                .send()
+                .await
+                .map_err(|e| StorageError::new(what, e.is_connect() || e.is_timeout(), e))?;
+            check(what, resp).await
+        })
+        .await
+    }
+
+    async fn read(&self, object: &str) -> Result<Resource, StorageError> {
+        self.send("read", || self.http.get(self.object_url(object)))
+            .await?
+            .json()
+            .await
+            .map_err(|e| StorageError::new("read", true, e))
+    }
+
+    /// Sends a large or unsized body through a resumable session, so a
+    /// dropped connection costs one chunk rather than the whole upload.
+    async fn upload_resumable(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        body: reqwest::Response,
+        content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64> {
+        let started = Instant::now();
+        let session = resumable::Session::start(self, object, content_type).await?;
+        let sent = session
+            .send(object, prefix, body, max_bytes, self.resumable.chunk_size)
+            .await;
+        let size = match sent {
+            Ok((size, _)) => size,
+            Err(e) => {
+                session.cancel().await;
+                // A failed integrity check leaves a finished object behind
+                if let Err(e) = self.delete(object).await {
+                    warn!(object, "failed to remove partial upload: {:#}", e);
+                }
+                return Err(e);
+            }
+        };
+        info!(
+            object,
+            size,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "uploaded object to GCS in resumable chunks"
+        );
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl Storage for GcsStorage {
+    /// A URL LINE can fetch the object from, per the configured mode.
+    async fn url(&self, object: &str) -> anyhow::Result<String> {
+        match &self.url_mode {
+            UrlMode::Public => Ok(format!(
+                "{}/{}/{}",
+                self.endpoint,
+                self.bucket,
+                uri_encode(object, false)
+            )),
+            UrlMode::Cdn { base } => Ok(cdn_url(base, object)),
+            UrlMode::Signed { expires_in_secs } => {
+                let signer = self
+                    .signer
+                    .as_ref()
+                    .expect("signed mode always loads a signer");
+                signer.sign(&self.bucket, object, Utc::now(), *expires_in_secs)
+            }
+        }
+    }
+
+    async fn upload(&self, object: &str, data: Vec<u8>, content_type: &str) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let size = data.len();
+        let data = Bytes::from(data);
+        self.send("upload", || {
+            self.http
+                .post(self.upload_url())
+                .query(&[("uploadType", "media"), ("name", object)])
+                .header(header::CONTENT_TYPE, content_type)
+                .body(data.clone())
+        })
+        .await?;
+        info!(
+            object,
+            size,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "uploaded object to GCS"
+        );
+        Ok(())
+    }
+
+    /// Pipes an HTTP response body into a new object chunk by chunk, so a
+    /// large upload never sits in memory as a whole. `prefix` holds bytes
+    /// already read off the body. The body can only be read once, so unlike
+    /// other calls this one is never retried; bodies over the resumable
+    /// threshold go through a session that resumes instead. Fails, leaving
+    /// no object behind, once the body grows past `max_bytes`. Returns the
+    /// number of bytes stored.
+    async fn upload_stream(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        mut body: reqwest::Response,
+        content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64> {
+        let length = body
+            .content_length()
+            .map(|remaining| remaining + prefix.len() as u64);
+        // What was already read counts even when the rest is undeclared
+        if length.unwrap_or(prefix.len() as u64) > max_bytes {
+            return Err(TooLarge {
+                size: length,
+                limit: max_bytes,
+            }
+            .into());
+        }
+        if self.resumable.applies(length) {
+            return self
+                .upload_resumable(object, prefix, body, content_type, max_bytes)
+                .await;
+        }
+
+        let started = Instant::now();
+        let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(4);
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        });
+        let mut request = self
+            .http
+            .post(self.upload_url())
+            .query(&[("uploadType", "media"), ("name", object)])
+            .header(header::CONTENT_TYPE, content_type)
+            .body(reqwest::Body::wrap_stream(stream));
+        if let Some(length) = length {
+            request = request.header(header::CONTENT_LENGTH, length);
+        }
+        let request = self.authorize(request).await?;
+        let upload = async {
+            let resp = request
+                .send()
+                .await
+                .map_err(|e| StorageError::new("upload", e.is_connect() || e.is_timeout(), e))?;
+            check("upload", resp).await
+        };
+        let forward = async move {
+            let mut total = prefix.len() as u64;
+            if !prefix.is_empty() && tx.send(Ok(prefix)).await.is_err() {
+                return Ok(total);
+            }
+            loop {
+                let chunk = match body.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => return Ok(total),
+                    Err(e) => {
+                        let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+                        return Err(anyhow::Error::new(e).context("content download failed"));
+                    }
+                };
+                total += chunk.len() as u64;
+                if total > max_bytes {
+                    let _ = tx.send(Err(io::Error::other("content too large"))).await;
+                    return Err(TooLarge {
+                        size: None,
+                        limit: max_bytes,
+                    }
+                    .into());
+                }
+                if tx.send(Ok(chunk)).await.is_err() {
+                    // The upload gave up; its own error is reported below.
+                    return Ok(total);
+                }
+            }
+        };
+        let (uploaded, forwarded) = tokio::join!(upload, forward);
+        let size = forwarded?;
+        uploaded?;
+        info!(
+            object,
+            size,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "streamed object to GCS"
+        );
+        Ok(size)
+    }
+
+    /// Merges `entries` into the object's custom metadata.
+    async fn set_metadata(&self, object: &str, entries: &[(&str, String)]) -> anyhow::Result<()> {
+        let metadata: HashMap<_, _> = entries.iter().cloned().collect();
+        let patch = serde_json::json!({ "metadata": metadata });
+        self.send("update", || {
+            self.http.patch(self.object_url(object)).json(&patch)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn metadata(&self, object: &str) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self.read(object).await?.metadata.unwrap_or_default())
+    }
+
+    /// Every object whose name starts with `prefix`.
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let page: ListPage = self
+                .send("list", || {
+                    let mut request = self
+                        .http
+                        .get(self.api_url("storage/v1", None))
+                        .query(&[("prefix", prefix)]);
+                    if let Some(token) = &page_token {
+                        request = request.query(&[("pageToken", token)]);
+                    }
+                    request
+                })
+                .await?
+                .json()
+                .await
+                .map_err(|e| StorageError::new("list", true, e))?;
+            objects.extend(page.items.into_iter().map(object_info));
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => return Ok(objects),
+            }
+        }
+    }
+
+    async fn download(&self, object: &str) -> anyhow::Result<Vec<u8>> {
+        let resp = self
+            .send("download", || {
+                self.http
+                    .get(self.object_url(object))
+                    .query(&[("alt", "media")])
+            })
+            .await?;
+        let data = resp
+            .bytes()
+            .await
+            .map_err(|e| StorageError::new("download", true, e))?;
+        Ok(data.to_vec())
+    }
+
+    async fn download_revision(&self, object: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        let download = self.send("download", || {
+            self.http
+                .get(self.object_url(object))
+                .query(&[("alt", "media")])
+        });
+        let resp = match download.await {
+            Ok(resp) => resp,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let generation = resp
+            .headers()
+            .get("x-goog-generation")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("GCS sent {} without a generation", object))?;
+        let data = resp
+            .bytes()
+            .await
+            .map_err(|e| StorageError::new("download", true, e))?;
+        Ok(Some((data.to_vec(), generation)))
+    }
+
+    async fn upload_if(
+        &self,
+        object: &str,
+        data: Vec<u8>,
+        content_type: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<()> {
+        // Generation 0 matches only an object that doesn't exist
+        let generation = revision.unwrap_or("0");
+        let data = Bytes::from(data);
+        let upload = self.send("upload", || {
+            self.http
+                .post(self.upload_url())
+                .query(&[
+                    ("uploadType", "media"),
+                    ("name", object),
+                    ("ifGenerationMatch", generation),
+                ])
+                .header(header::CONTENT_TYPE, content_type)
+                .body(data.clone())
+        });
+        match upload.await {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.source_ref::<GcsError>()
+                    .is_some_and(|e| e.status == StatusCode::PRECONDITION_FAILED) =>
+            {
+                Err(PreconditionFailed {
+                    object: object.to_string(),
+                }
+                .into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, object: &str) -> anyhow::Result<bool> {
+        Ok(self.stat(object).await?.is_some())
+    }
+
+    async fn stat(&self, object: &str) -> anyhow::Result<Option<ObjectInfo>> {
+        match self.read(object).await {
+            Ok(obj) => Ok(Some(object_info(obj))),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, object: &str) -> anyhow::Result<()> {
+        let delete = self.send("delete", || self.http.delete(self.object_url(object)));
+        match delete.await {
+            Ok(_) => Ok(()),
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn copy(&self, source: &str, dest: &str) -> anyhow::Result<()> {
+        let mut url = self.object_url(source);
+        url.path_segments_mut()
+            .expect("GCS endpoint has a path")
+            .extend(["copyTo", "b", &self.bucket, "o", dest]);
+        self.send("copy", || {
+            self.http.post(url.clone()).json(&serde_json::json!({}))
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Passes successful responses through and turns the rest into errors,
+/// with 429 and 5xx marked as worth retrying.
+async fn check(
+    what: &'static str,
+    resp: reqwest::Response,
+) -> Result<reqwest::Response, StorageError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body: serde_json::Value = resp.json().await.unwrap_or_default();
+    let message = body["error"]["message"].as_str().map(str::to_string);
+    let transient = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+    Err(StorageError::new(
+        what,
+        transient,
+        GcsError { status, message },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        Router,
+        body::Body,
+        extract::{ConnectInfo, Query, State},
+        http::{HeaderMap, Method, Uri},
+        response::{IntoResponse, Response},
+        routing::get,
+    };
+    use futures_util::StreamExt as _;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::test_support::serve;
+
+    /// Just enough of the GCS JSON API to store objects through simple
+    /// and resumable uploads and read them back.
+    #[derive(Clone, Default)]
+    struct FakeGcs {
+        objects: Arc<Mutex<HashMap<String, Stored>>>,
+        /// Signalled whenever part of an upload body arrives.
+        receiving: Arc<Notify>,
+        /// Where each request came from.
+        peers: Arc<Mutex<Vec<SocketAddr>>>,
+        /// Resumable session id -> the upload it holds.
+        sessions: Arc<Mutex<HashMap<String, OpenSession>>>,
+        /// The Content-Range of every request to a session, in order.
+        ranges: Arc<Mutex<Vec<String>>>,
+        /// The session request (counting from 1) that only stores half its
+        /// chunk before the connection drops.
+        drop_request: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl FakeGcs {
+        async fn start() -> (Self, GcsStorage) {
+            Self::start_with(resumable()).await
+        }
+
+        async fn start_with(resumable: Resumable) -> (Self, GcsStorage) {
+            let fake = Self::default();
+            let app = Router::new().fallback(handle).with_state(fake.clone());
+            let base = serve(app).await;
+            let storage = GcsStorage::emulator(
+                reqwest::Client::new(),
+                "bucket".to_string(),
+                &base,
+                resumable,
+            );
+            (fake, storage)
+        }
+
+        fn object(&self, name: &str) -> Option<Stored> {
+            self.objects.lock().unwrap().get(name).cloned()
+        }
+
+        fn peers(&self) -> Vec<SocketAddr> {
+            self.peers.lock().unwrap().clone()
+        }
+    }
+
+    struct OpenSession {
+        name: String,
+        /// Bytes stored so far.
+        stored: Vec<u8>,
+    }
+
+    #[derive(Clone)]
+    struct Stored {
+        data: Bytes,
+        content_type: String,
+        /// The Content-Length the upload declared, if any.
+        length: Option<String>,
+    }
+
+    fn resumable() -> Resumable {
+        Resumable {
+            threshold: u64::MAX,
+            chunk_size: 256 * 1024,
+        }
+    }
+
+    async fn handle(
+        State(fake): State<FakeGcs>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        method: Method,
+        uri: Uri,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Body,
+    ) -> Response {
+        let path = uri.path().to_string();
+        fake.peers.lock().unwrap().push(peer);
+        if let Some(session) = path.strip_prefix("/session/") {
+            return session_put(&fake, session, &headers, body).await;
+        }
+        match (method, path.as_str()) {
+            (Method::POST, "/upload/storage/v1/b/bucket/o")
+                if query.get("uploadType").map(String::as_str) == Some("resumable") =>
+            {
+                let id = uuid::Uuid::new_v4().to_string();
+                fake.sessions.lock().unwrap().insert(
+                    id.clone(),
+                    OpenSession {
+                        name: query["name"].clone(),
+                        stored: Vec::new(),
+                    },
+                );
+                let host = headers[header::HOST].to_str().unwrap();
+                let location = format!("http://{}/session/{}", host, id);
+                ([(header::LOCATION, location)], "").into_response()
+            }
+            (Method::POST, "/upload/storage/v1/b/bucket/o") => {
+                let name = query["name"].clone();
+                let header = |name| {
+                    headers
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string)
+                };
+                let mut data = Vec::new();
+                let mut body = body.into_data_stream();
+                while let Some(chunk) = body.next().await {
+                    let Ok(chunk) = chunk else {
+                        return StatusCode::BAD_REQUEST.into_response();
+                    };
+                    data.extend_from_slice(&chunk);
+                    fake.receiving.notify_one();
+                }
+                let stored = Stored {
+                    data: data.into(),
+                    content_type: header(header::CONTENT_TYPE).unwrap_or_default(),
+                    length: header(header::CONTENT_LENGTH),
+                };
+                fake.objects.lock().unwrap().insert(name.clone(), stored);
+                axum::Json(serde_json::json!({ "name": name })).into_response()
+            }
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    /// One request to a resumable session: stores the chunk if it starts
+    /// where the stored bytes end, then reports progress with a 308 or
+    /// finishes the object once the declared total is in.
+    async fn session_put(
+        fake: &FakeGcs,
+        session: &str,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Response {
+        let range = headers[header::CONTENT_RANGE].to_str().unwrap().to_string();
+        let request = {
+            let mut ranges = fake.ranges.lock().unwrap();
+            ranges.push(range.clone());
+            ranges.len()
+        };
+        let data = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let spec = range.strip_prefix("bytes ").unwrap();
+        let (span, total) = spec.split_once('/').unwrap();
+        let mut sessions = fake.sessions.lock().unwrap();
+        let OpenSession { name, stored } = sessions.get_mut(session).unwrap();
+        if span != "*" {
+            let start: usize = span.split_once('-').unwrap().0.parse().unwrap();
+            if start != stored.len() {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            if *fake.drop_request.lock().unwrap() == Some(request) {
+                stored.extend_from_slice(&data[..data.len() / 2]);
+                let dropped = futures_util::stream::once(async {
+                    Err::<Bytes, _>(io::Error::other("connection dropped"))
+                });
+                return Body::from_stream(dropped).into_response();
+            }
+            stored.extend_from_slice(&data);
+        }
+        if total.parse() == Ok(stored.len()) {
+            let object = Stored {
+                data: Bytes::from(stored.clone()),
+                content_type: String::new(),
+                length: None,
+            };
+            let resource = serde_json::json!({
+                "name": name,
+                "size": stored.len().to_string(),
+            });
+            fake.objects.lock().unwrap().insert(name.clone(), object);
+            return axum::Json(resource).into_response();
+        }
+        let mut resp = StatusCode::PERMANENT_REDIRECT.into_response();
+        if !stored.is_empty() {
+            let acked = format!("bytes=0-{}", stored.len() - 1);
+            resp.headers_mut()
+                .insert(header::RANGE, acked.parse().unwrap());
+        }
+        resp
+    }
+
+    #[tokio::test]
+    async fn uploads_share_one_client_and_connection() {
+        let (fake, storage) = FakeGcs::start().await;
+        for i in 0..5 {
+            storage
+                .upload(&format!("uploads/{}.jpg", i), vec![i; 16], "image/jpeg")
+                .await
+                .unwrap();
+        }
+
+        let stored = fake.object("uploads/3.jpg").unwrap();
+        assert_eq!(&stored.data[..], &[3; 16]);
+        assert_eq!(stored.content_type, "image/jpeg");
+        let peers = fake.peers();
+        assert_eq!(peers.len(), 5);
+        // A client per call would open a fresh connection, from a new port,
+        // every time.
+        assert!(peers.iter().all(|peer| *peer == peers[0]), "{:?}", peers);
+    }
+
+    /// A response whose body arrives as `first`, then — only once `go` is
+    /// signalled — `rest`, declaring their combined length.
+    async fn content(
+        first: &'static [u8],
+        rest: &'static [u8],
+        go: Arc<Notify>,
+    ) -> reqwest::Response {
+        let app = Router::new().route(
+            "/content",
+            get(move || async move {
+                let chunks = futures_util::stream::iter([first])
+                    .chain(futures_util::stream::once(async move {
+                        go.notified().await;
+                        rest
+                    }))
+                    .map(|chunk| Ok::<_, io::Error>(Bytes::from_static(chunk)));
+                (
+                    [(
+                        header::CONTENT_LENGTH,
+                        (first.len() + rest.len()).to_string(),
+                    )],
+                    Body::from_stream(chunks),
+                )
+            }),
+        );
+        let base = serve(app).await;
+        reqwest::get(format!("{}/content", base)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn content_is_forwarded_to_gcs_as_it_arrives() {
+        let (fake, storage) = FakeGcs::start().await;
+        // The rest of the content is held back until GCS has seen the
+        // start of it, which a buffering upload would never let happen.
+        let body = content(b"first chunk, ", b"second chunk", fake.receiving.clone()).await;
+        let size = storage
+            .upload_stream(
+                "uploads/v.mp4",
+                Bytes::from_static(b"prefix, "),
+                body,
+                "video/mp4",
+                1024,
+            )
+            .await
+            .unwrap();
+
+        let stored = fake.object("uploads/v.mp4").unwrap();
+        assert_eq!(&stored.data[..], b"prefix, first chunk, second chunk");
+        assert_eq!(size, stored.data.len() as u64);
+        assert_eq!(stored.length, Some(stored.data.len().to_string()));
+        assert_eq!(stored.content_type, "video/mp4");
+    }
+
+    #[tokio::test]
+    async fn declared_oversized_content_is_refused_before_uploading() {
+        let (fake, storage) = FakeGcs::start().await;
+        let go = Arc::new(Notify::new());
+        go.notify_one();
+        let body = content(b"0123456789", b"0123456789", go).await;
+        let error = storage
+            .upload_stream("uploads/v.mp4", Bytes::new(), body, "video/mp4", 15)
+            .await
+            .unwrap_err();
+        let too_large = error.downcast_ref::<TooLarge>().unwrap();
+        assert_eq!(too_large.size, Some(20));
+        assert!(fake.object("uploads/v.mp4").is_none());
+    }
+
+    /// Serves `data` with its length declared, as LINE's content API does.
+    async fn served(data: Vec<u8>) -> reqwest::Response {
+        let app = Router::new().route("/content", get(move || async move { data }));
+        let base = serve(app).await;
+        reqwest::get(format!("{}/content", base)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_dropped_chunk_resumes_the_session_rather_than_restarting() {
+        let (fake, storage) = FakeGcs::start_with(Resumable {
+            threshold: 0,
+            chunk_size: 256 * 1024,
+        })
+        .await;
+        *fake.drop_request.lock().unwrap() = Some(2);
+        let data: Vec<u8> = (0..600 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let size = storage
+            .upload_stream(
+                "uploads/v.mp4",
+                Bytes::new(),
+                served(data.clone()).await,
+                "video/mp4",
+                u64::MAX,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(&fake.object("uploads/v.mp4").unwrap().data[..], &data[..]);
+        assert_eq!(fake.sessions.lock().unwrap().len(), 1);
+        assert_eq!(
+            *fake.ranges.lock().unwrap(),
+            [
+                "bytes 0-262143/*",
+                "bytes 262144-524287/*",
+                // Asks how far the session got, then sends on from there
+                "bytes */*",
+                "bytes 393216-614399/614400",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn small_declared_uploads_skip_the_session() {
+        let (fake, storage) = FakeGcs::start_with(Resumable {
+            threshold: 1024,
+            chunk_size: 256 * 1024,
+        })
+        .await;
+        storage
+            .upload_stream(
+                "uploads/a.jpg",
+                Bytes::new(),
+                served(vec![7; 1024]).await,
+                "image/jpeg",
+                u64::MAX,
+            )
+            .await
+            .unwrap();
+        assert_eq!(fake.object("uploads/a.jpg").unwrap().data.len(), 1024);
+        assert!(fake.sessions.lock().unwrap().is_empty());
+    }
+
+    /// Uploads, copies, lists and deletes through a real emulator such as
+    /// fake-gcs-server when STORAGE_EMULATOR_HOST is set (with the bucket
+    /// in GCS_TEST_BUCKET, "test" by default). Skipped otherwise.
+    #[tokio::test]
+    async fn round_trips_against_the_emulator() {
+        let Ok(host) = std::env::var("STORAGE_EMULATOR_HOST") else {
+            return;
+        };
+        let bucket = std::env::var("GCS_TEST_BUCKET").unwrap_or_else(|_| "test".to_string());
+        let gcs = GcsStorage::emulator(reqwest::Client::new(), bucket, &host, resumable());
+
+        let object = format!("tests/{}/夜 ランチ.jpg", uuid::Uuid::new_v4());
+        let copy = format!("{}.copy", object);
+        gcs.upload(&object, b"jpeg".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        gcs.copy(&object, &copy).await.unwrap();
+        assert_eq!(gcs.download(&copy).await.unwrap(), b"jpeg");
+        let prefix = object.rsplit_once('/').unwrap().0;
+        assert_eq!(gcs.list(prefix).await.unwrap().len(), 2);
+        for name in [&object, &copy] {
+            gcs.delete(name).await.unwrap();
+        }
+        assert!(!gcs.exists(&object).await.unwrap());
+    }
+}
@@ -0,0 +1,284 @@
+use std::{env, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::{Buf, Bytes, BytesMut};
+use reqwest::{StatusCode, header};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use super::{GcsStorage, check};
+use crate::storage::{StorageError, TooLarge, retry};
+
+/// GCS wants every chunk but the last to be a multiple of this.
+const CHUNK_ALIGN: usize = 256 * 1024;
+
+/// How many times one upload may pick up again after a failed chunk.
+const MAX_RESUMES: u32 = 5;
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// Progress is logged once per this many chunks.
+const LOG_EVERY_CHUNKS: u64 = 8;
+
+/// When `upload_stream` switches to a resumable session, and how much it
+/// sends per request once it has.
+#[derive(Clone, Copy, Debug)]
+pub struct Resumable {
+    pub threshold: u64,
+    pub chunk_size: usize,
+}
+
+impl Resumable {
+    /// Reads GCS_RESUMABLE_THRESHOLD_BYTES and GCS_RESUMABLE_CHUNK_BYTES.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let threshold = env::var("GCS_RESUMABLE_THRESHOLD_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16 * 1024 * 1024);
+        let chunk_size: usize = env::var("GCS_RESUMABLE_CHUNK_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(8 * 1024 * 1024);
+        if chunk_size == 0 || !chunk_size.is_multiple_of(CHUNK_ALIGN) {
+            anyhow::bail!(
+                "GCS_RESUMABLE_CHUNK_BYTES must be a multiple of {}, got {}",
+                CHUNK_ALIGN,
+                chunk_size
+            );
+        }
+        Ok(Self {
+            threshold,
+            chunk_size,
+        })
+    }
+
+    /// Whether content of this length (None when undeclared) goes through
+    /// a resumable session rather than a single request.
+    pub fn applies(&self, length: Option<u64>) -> bool {
+        length.is_none_or(|length| length > self.threshold)
+    }
+}
+
+/// Where a chunk left the session.
+enum Progress {
+    /// Bytes before this offset are stored; send the rest from here.
+    Acked(u64),
+    /// The object is complete; this is its resource.
+    Done(Value),
+}
+
+/// An open resumable upload. The session URI is its own credential, so
+/// only starting one needs a token.
+pub struct Session {
+    http: reqwest::Client,
+    uri: String,
+}
+
+impl Session {
+    pub async fn start(
+        storage: &GcsStorage,
+        object: &str,
+        content_type: &str,
+    ) -> Result<Self, StorageError> {
+        let resp = storage
+            .send("upload", || {
+                storage
+                    .http
+                    .post(storage.upload_url())
+                    .query(&[("uploadType", "resumable"), ("name", object)])
+                    .header("X-Upload-Content-Type", content_type)
+                    .json(&serde_json::json!({ "contentType": content_type }))
+            })
+            .await?;
+        let uri = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| StorageError::new("upload", false, "no resumable session URI"))?;
+        Ok(Self {
+            http: storage.http.clone(),
+            uri: uri.to_string(),
+        })
+    }
+
+    /// Streams `prefix` and then the rest of `body` through the session in
+    /// `chunk_size` pieces, resuming from the last stored byte when a
+    /// chunk fails. Returns the stored size and the finished object.
+    pub async fn send(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        mut body: reqwest::Response,
+        max_bytes: u64,
+        chunk_size: usize,
+    ) -> anyhow::Result<(u64, Value)> {
+        let mut received = prefix.len() as u64;
+        let mut crc = crc32c(0, &prefix);
+        // Bytes read off the body that GCS hasn't acknowledged yet
+        let mut pending = BytesMut::from(prefix);
+        let mut eof = false;
+        let mut offset = 0u64;
+        let mut chunks = 0u64;
+        let mut resumes = 0;
+        loop {
+            // Reading one byte past a full chunk tells whether it's the last
+            while !eof && pending.len() <= chunk_size {
+                let Some(chunk) = body
+                    .chunk()
+                    .await
+                    .map_err(|e| anyhow::Error::new(e).context("content download failed"))?
+                else {
+                    eof = true;
+                    break;
+                };
+                received += chunk.len() as u64;
+                if received > max_bytes {
+                    return Err(TooLarge {
+                        size: None,
+                        limit: max_bytes,
+                    }
+                    .into());
+                }
+                crc = crc32c(crc, &chunk);
+                pending.extend_from_slice(&chunk);
+            }
+            let last = eof && pending.len() <= chunk_size;
+            let len = if last { pending.len() } else { chunk_size };
+            let total = last.then_some(offset + len as u64);
+            let chunk = Bytes::copy_from_slice(&pending[..len]);
+
+            let progress = match self.put(offset, chunk, total).await {
+                Ok(progress) => progress,
+                Err(e) if e.transient && resumes < MAX_RESUMES => {
+                    resumes += 1;
+                    warn!(object, offset, resumes, error = %e, "upload chunk failed; resuming");
+                    tokio::time::sleep(RESUME_DELAY * resumes).await;
+                    retry("upload", || self.put(offset, Bytes::new(), None)).await?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match progress {
+                Progress::Done(resource) => {
+                    verify(&resource, received, crc)?;
+                    return Ok((received, resource));
+                }
+                Progress::Acked(next) => {
+                    if next < offset || next > offset + pending.len() as u64 {
+                        anyhow::bail!(
+                            "GCS acknowledged offset {} outside the unsent range at {}",
+                            next,
+                            offset
+                        );
+                    }
+                    pending.advance((next - offset) as usize);
+                    offset = next;
+                }
+            }
+            chunks += 1;
+            if chunks.is_multiple_of(LOG_EVERY_CHUNKS) {
+                info!(object, uploaded = offset, "resumable upload in progress");
+            }
+        }
+    }
+
+    /// Sends `chunk` as the bytes from `offset`; with `total` set this
+    /// finishes the object. An empty chunk without a total just asks how
+    /// far the session got.
+    async fn put(
+        &self,
+        offset: u64,
+        chunk: Bytes,
+        total: Option<u64>,
+    ) -> Result<Progress, StorageError> {
+        let total = total.map_or("*".to_string(), |total| total.to_string());
+        let range = if chunk.is_empty() {
+            format!("bytes */{}", total)
+        } else {
+            format!(
+                "bytes {}-{}/{}",
+                offset,
+                offset + chunk.len() as u64 - 1,
+                total
+            )
+        };
+        let resp = self
+            .http
+            .put(&self.uri)
+            .header(header::CONTENT_RANGE, range)
+            .body(chunk)
+            .send()
+            .await
+            // Any dropped connection is worth asking the session about
+            .map_err(|e| StorageError::new("upload", true, e))?;
+        if resp.status() == StatusCode::PERMANENT_REDIRECT {
+            // "Resume Incomplete", with the stored range if anything is
+            let next = resp
+                .headers()
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('-').next()?.parse::<u64>().ok())
+                .map_or(0, |end| end + 1);
+            return Ok(Progress::Acked(next));
+        }
+        let resp = check("upload", resp).await?;
+        let resource = resp
+            .json()
+            .await
+            .map_err(|e| StorageError::new("upload", true, e))?;
+        Ok(Progress::Done(resource))
+    }
+
+    /// Abandons the session so GCS drops what it has stored so far.
+    pub async fn cancel(&self) {
+        if let Err(e) = self.http.delete(&self.uri).send().await {
+            warn!("failed to cancel resumable upload: {:#}", e);
+        }
+    }
+}
+
+/// Compares the finished object's size and CRC32C with what was read off
+/// the body, so a resume that skipped or repeated bytes doesn't go unseen.
+fn verify(resource: &Value, size: u64, crc: u32) -> anyhow::Result<()> {
+    let stored_size = resource["size"]
+        .as_str()
+        .and_then(|s| s.parse::<u64>().ok());
+    if stored_size != Some(size) {
+        anyhow::bail!(
+            "integrity check failed: sent {} bytes, GCS stored {:?}",
+            size,
+            stored_size
+        );
+    }
+    if let Some(stored_crc) = resource["crc32c"].as_str()
+        && stored_crc != BASE64.encode(crc.to_be_bytes())
+    {
+        anyhow::bail!("integrity check failed: CRC32C mismatch");
+    }
+    Ok(())
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Extends a CRC32C (Castagnoli) checksum, as GCS reports it, over `data`.
+fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::info;
+
+use super::{ObjectInfo, PreconditionFailed, Storage, StorageError, TooLarge};
+
+/// Objects kept as plain files under a directory, for running the bot
+/// without a bucket. The files are served on GET /files/{path}, which is
+/// what `url` points at.
+pub struct LocalStorage {
+    root: PathBuf,
+    /// Where this server can be reached, e.g. an ngrok tunnel.
+    base_url: String,
+    /// Makes `upload_if`'s check-then-write atomic within this process.
+    conditional_writes: Mutex<()>,
+}
+
+fn io_error(what: &'static str, source: io::Error) -> StorageError {
+    StorageError::new(what, false, source)
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf, base_url: String) -> Self {
+        Self {
+            root,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            conditional_writes: Mutex::new(()),
+        }
+    }
+
+    /// The file behind `object`. Names that could step outside the root
+    /// are refused, since they may come from a request path.
+    pub fn path(&self, object: &str) -> anyhow::Result<PathBuf> {
+        let relative = Path::new(object);
+        if object.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("invalid object name: {}", object);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// The file behind `object`, with its parent directories created.
+    async fn create_path(&self, object: &str) -> anyhow::Result<PathBuf> {
+        let path = self.path(object)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error("create directory", e))?;
+        }
+        Ok(path)
+    }
+
+    /// The file's modification time and size, which change on every write.
+    async fn revision(&self, object: &str) -> anyhow::Result<Option<String>> {
+        match tokio::fs::metadata(self.path(object)?).await {
+            Ok(metadata) => Ok(Some(revision(&metadata))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error("read", e).into()),
+        }
+    }
+}
+
+fn revision(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("{}-{}", modified.as_nanos(), metadata.len())
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn url(&self, object: &str) -> anyhow::Result<String> {
+        self.path(object)?;
+        Ok(format!("{}/files/{}", self.base_url, object))
+    }
+
+    async fn upload(&self, object: &str, data: Vec<u8>, _content_type: &str) -> anyhow::Result<()> {
+        let path = self.create_path(object).await?;
+        tokio::fs::write(&path, data)
+            .await
+            .map_err(|e| io_error("upload", e))?;
+        Ok(())
+    }
+
+    async fn upload_stream(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        mut body: reqwest::Response,
+        _content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64> {
+        let length = body
+            .content_length()
+            .map(|remaining| remaining + prefix.len() as u64);
+        // What was already read counts even when the rest is undeclared
+        if length.unwrap_or(prefix.len() as u64) > max_bytes {
+            return Err(TooLarge {
+                size: length,
+                limit: max_bytes,
+            }
+            .into());
+        }
+
+        let path = self.create_path(object).await?;
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| io_error("upload", e))?;
+        let written = async {
+            let mut total = prefix.len() as u64;
+            file.write_all(&prefix)
+                .await
+                .map_err(|e| io_error("upload", e))?;
+            while let Some(chunk) = body.chunk().await? {
+                total += chunk.len() as u64;
+                if total > max_bytes {
+                    return Err(TooLarge {
+                        size: None,
+                        limit: max_bytes,
+                    }
+                    .into());
+                }
+                file.write_all(&chunk)
+                    .await
+                    .map_err(|e| io_error("upload", e))?;
+            }
+            file.flush().await.map_err(|e| io_error("upload", e))?;
+            anyhow::Ok(total)
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+        let size = written?;
+        info!(object, size, "stored object locally");
+        Ok(size)
+    }
+
+    /// Local files carry no custom metadata, so this only checks the
+    /// object is there.
+    async fn set_metadata(&self, object: &str, _entries: &[(&str, String)]) -> anyhow::Result<()> {
+        tokio::fs::metadata(self.path(object)?)
+            .await
+            .map_err(|e| io_error("read", e))?;
+        Ok(())
+    }
+
+    async fn metadata(&self, object: &str) -> anyhow::Result<HashMap<String, String>> {
+        self.set_metadata(object, &[]).await?;
+        Ok(HashMap::new())
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectInfo>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        let objects = tokio::task::spawn_blocking(move || {
+            let mut objects = Vec::new();
+            list_dir(&root, "", &prefix, &mut objects)?;
+            io::Result::Ok(objects)
+        })
+        .await?
+        .map_err(|e| io_error("list", e))?;
+        Ok(objects)
+    }
+
+    async fn download(&self, object: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.path(object)?)
+            .await
+            .map_err(|e| io_error("download", e))?)
+    }
+
+    async fn download_revision(&self, object: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        let _guard = self.conditional_writes.lock().await;
+        let Some(revision) = self.revision(object).await? else {
+            return Ok(None);
+        };
+        let data = tokio::fs::read(self.path(object)?)
+            .await
+            .map_err(|e| io_error("download", e))?;
+        Ok(Some((data, revision)))
+    }
+
+    async fn upload_if(
+        &self,
+        object: &str,
+        data: Vec<u8>,
+        content_type: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let _guard = self.conditional_writes.lock().await;
+        if self.revision(object).await?.as_deref() != revision {
+            return Err(PreconditionFailed {
+                object: object.to_string(),
+            }
+            .into());
+        }
+        self.upload(object, data, content_type).await
+    }
+
+    async fn exists(&self, object: &str) -> anyhow::Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(object)?)
+            .await
+            .map_err(|e| io_error("read", e))?)
+    }
+
+    async fn stat(&self, object: &str) -> anyhow::Result<Option<ObjectInfo>> {
+        match tokio::fs::metadata(self.path(object)?).await {
+            Ok(metadata) if metadata.is_file() => {
+                Ok(Some(object_info(object.to_string(), &metadata)))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error("read", e).into()),
+        }
+    }
+
+    async fn delete(&self, object: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.path(object)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error("delete", e).into()),
+        }
+    }
+
+    async fn copy(&self, source: &str, dest: &str) -> anyhow::Result<()> {
+        let source = self.path(source)?;
+        let dest = self.create_path(dest).await?;
+        tokio::fs::copy(source, dest)
+            .await
+            .map_err(|e| io_error("copy", e))?;
+        Ok(())
+    }
+}
+
+fn object_info(name: String, metadata: &fs::Metadata) -> ObjectInfo {
+    let updated = metadata.modified().unwrap_or(UNIX_EPOCH);
+    ObjectInfo {
+        name,
+        created: metadata.created().unwrap_or(updated),
+        updated,
+        size: metadata.len(),
+        revision: Some(revision(metadata)),
+    }
+}
+
+/// Collects files under `dir` (named `name` relative to the root) whose
+/// object names start with `prefix`. A missing root lists as empty.
+fn list_dir(dir: &Path, name: &str, prefix: &str, out: &mut Vec<ObjectInfo>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let object = if name.is_empty() {
+            file_name
+        } else {
+            format!("{}/{}", name, file_name)
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            // Only descend where something could still match
+            if object.starts_with(prefix) || prefix.starts_with(&format!("{}/", object)) {
+                list_dir(&entry.path(), &object, prefix, out)?;
+            }
+        } else if object.starts_with(prefix) {
+            out.push(object_info(object, &metadata));
+        }
+    }
+    Ok(())
+}
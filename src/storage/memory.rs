@@ -0,0 +1,285 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{ObjectInfo, PreconditionFailed, Storage, StorageError, TooLarge};
+
+/// Objects kept in a map, for tests. Revisions count up on every write,
+/// and failures can be scripted per operation and object.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<BTreeMap<String, Object>>,
+    generation: Mutex<u64>,
+    /// (operation, object, error) to return instead of running the call.
+    failures: Mutex<Vec<(&'static str, String, anyhow::Error)>>,
+    /// (operation, object) to delete the object just before.
+    vanishing: Mutex<Vec<(&'static str, String)>>,
+}
+
+#[derive(Clone)]
+struct Object {
+    data: Vec<u8>,
+    content_type: String,
+    metadata: HashMap<String, String>,
+    created: SystemTime,
+    updated: SystemTime,
+    revision: String,
+}
+
+impl MemoryStorage {
+    pub fn put(&self, object: &str, data: impl Into<Vec<u8>>) {
+        self.write(object, data.into(), "application/octet-stream");
+    }
+
+    /// Makes the next `op` ("upload", "delete", ...) on `object` fail with
+    /// `error`. Scripting the same call twice fails it twice.
+    pub fn fail_next(&self, op: &'static str, object: &str, error: impl Into<anyhow::Error>) {
+        self.failures
+            .lock()
+            .unwrap()
+            .push((op, object.to_string(), error.into()));
+    }
+
+    /// Deletes `object` just before the next `op` on it runs, as if
+    /// another task removed it in between.
+    pub fn vanish_before(&self, op: &'static str, object: &str) {
+        self.vanishing
+            .lock()
+            .unwrap()
+            .push((op, object.to_string()));
+    }
+
+    /// Backdates an object, e.g. to make an upload look abandoned.
+    pub fn set_created(&self, object: &str, created: SystemTime) {
+        if let Some(o) = self.objects.lock().unwrap().get_mut(object) {
+            o.created = created;
+        }
+    }
+
+    pub fn content_type(&self, object: &str) -> Option<String> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .map(|o| o.content_type.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get(&self, object: &str) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .map(|o| o.data.clone())
+    }
+
+    fn scripted(&self, op: &'static str, object: &str) -> anyhow::Result<()> {
+        let mut vanishing = self.vanishing.lock().unwrap();
+        if let Some(i) = vanishing
+            .iter()
+            .position(|(o, name)| *o == op && name == object)
+        {
+            vanishing.remove(i);
+            self.objects.lock().unwrap().remove(object);
+        }
+        drop(vanishing);
+        let mut failures = self.failures.lock().unwrap();
+        match failures
+            .iter()
+            .position(|(o, name, _)| *o == op && name == object)
+        {
+            Some(i) => Err(failures.remove(i).2),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&self, object: &str, data: Vec<u8>, content_type: &str) {
+        let revision = {
+            let mut generation = self.generation.lock().unwrap();
+            *generation += 1;
+            generation.to_string()
+        };
+        let now = SystemTime::now();
+        let mut objects = self.objects.lock().unwrap();
+        let created = objects.get(object).map_or(now, |o| o.created);
+        objects.insert(
+            object.to_string(),
+            Object {
+                data,
+                content_type: content_type.to_string(),
+                metadata: HashMap::new(),
+                created,
+                updated: now,
+                revision,
+            },
+        );
+    }
+
+    fn read(&self, object: &str) -> anyhow::Result<Object> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .cloned()
+            .ok_or_else(|| not_found(object))
+    }
+}
+
+fn not_found(object: &str) -> anyhow::Error {
+    StorageError::new("read", false, format!("{} not found", object)).into()
+}
+
+fn info(name: &str, object: &Object) -> ObjectInfo {
+    ObjectInfo {
+        name: name.to_string(),
+        created: object.created,
+        updated: object.updated,
+        size: object.data.len() as u64,
+        revision: Some(object.revision.clone()),
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn url(&self, object: &str) -> anyhow::Result<String> {
+        Ok(format!("https://storage.test/{}", object))
+    }
+
+    async fn upload(&self, object: &str, data: Vec<u8>, content_type: &str) -> anyhow::Result<()> {
+        self.write(object, data, content_type);
+        Ok(())
+    }
+
+    async fn upload_stream(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        mut body: reqwest::Response,
+        content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64> {
+        let length = body.content_length().map(|n| n + prefix.len() as u64);
+        // What was already read counts even when the rest is undeclared
+        if length.unwrap_or(prefix.len() as u64) > max_bytes {
+            return Err(TooLarge {
+                size: length,
+                limit: max_bytes,
+            }
+            .into());
+        }
+        let mut data = prefix.to_vec();
+        while let Some(chunk) = body.chunk().await? {
+            data.extend_from_slice(&chunk);
+            if data.len() as u64 > max_bytes {
+                return Err(TooLarge {
+                    size: None,
+                    limit: max_bytes,
+                }
+                .into());
+            }
+        }
+        let size = data.len() as u64;
+        self.write(object, data, content_type);
+        Ok(size)
+    }
+
+    async fn set_metadata(&self, object: &str, entries: &[(&str, String)]) -> anyhow::Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        let o = objects.get_mut(object).ok_or_else(|| not_found(object))?;
+        for (key, value) in entries {
+            o.metadata.insert(key.to_string(), value.clone());
+        }
+        Ok(())
+    }
+
+    async fn metadata(&self, object: &str) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self.read(object)?.metadata)
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectInfo>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, o)| info(name, o))
+            .collect())
+    }
+
+    async fn download(&self, object: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(self.read(object)?.data)
+    }
+
+    async fn download_revision(&self, object: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .map(|o| (o.data.clone(), o.revision.clone())))
+    }
+
+    async fn upload_if(
+        &self,
+        object: &str,
+        data: Vec<u8>,
+        content_type: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.scripted("upload_if", object)?;
+        let current = self
+            .objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .map(|o| o.revision.clone());
+        if current.as_deref() != revision {
+            return Err(PreconditionFailed {
+                object: object.to_string(),
+            }
+            .into());
+        }
+        self.write(object, data, content_type);
+        Ok(())
+    }
+
+    async fn exists(&self, object: &str) -> anyhow::Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(object))
+    }
+
+    async fn stat(&self, object: &str) -> anyhow::Result<Option<ObjectInfo>> {
+        self.scripted("stat", object)?;
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(object)
+            .map(|o| info(object, o)))
+    }
+
+    async fn delete(&self, object: &str) -> anyhow::Result<()> {
+        self.scripted("delete", object)?;
+        self.objects.lock().unwrap().remove(object);
+        Ok(())
+    }
+
+    async fn copy(&self, source: &str, dest: &str) -> anyhow::Result<()> {
+        self.scripted("copy", source)?;
+        let source = self.read(source)?;
+        self.write(dest, source.data, &source.content_type);
+        let mut objects = self.objects.lock().unwrap();
+        if let Some(o) = objects.get_mut(dest) {
+            o.metadata = source.metadata;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{ObjectInfo, Storage};
+
+/// Puts every object of another backend under a fixed prefix, so several
+/// environments can share one bucket. Callers keep using prefix-free
+/// names, which is also what ends up in postback data and preset config.
+pub struct PrefixedStorage {
+    inner: Arc<dyn Storage>,
+    /// Normalized: no leading or trailing slashes, never empty.
+    prefix: String,
+}
+
+impl PrefixedStorage {
+    /// Wraps `inner` unless `prefix` is empty once slashes are trimmed.
+    pub fn wrap(inner: Arc<dyn Storage>, prefix: &str) -> Arc<dyn Storage> {
+        let prefix = prefix.trim_matches('/');
+        if prefix.is_empty() {
+            return inner;
+        }
+        Arc::new(Self {
+            inner,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn full(&self, object: &str) -> String {
+        join_path(&self.prefix, object)
+    }
+}
+
+/// `staging/` + `/images/a.jpg` -> `staging/images/a.jpg`, tolerating
+/// missing or doubled slashes on either side of the join.
+pub fn join_path(prefix: &str, object: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    let object = object.trim_start_matches('/');
+    if prefix.is_empty() {
+        object.to_string()
+    } else {
+        format!("{}/{}", prefix, object)
+    }
+}
+
+#[async_trait]
+impl Storage for PrefixedStorage {
+    async fn url(&self, object: &str) -> anyhow::Result<String> {
+        self.inner.url(&self.full(object)).await
+    }
+
+    async fn upload(&self, object: &str, data: Vec<u8>, content_type: &str) -> anyhow::Result<()> {
+        self.inner
+            .upload(&self.full(object), data, content_type)
+            .await
+    }
+
+    async fn upload_stream(
+        &self,
+        object: &str,
+        prefix: Bytes,
+        body: reqwest::Response,
+        content_type: &str,
+        max_bytes: u64,
+    ) -> anyhow::Result<u64> {
+        self.inner
+            .upload_stream(&self.full(object), prefix, body, content_type, max_bytes)
+            .await
+    }
+
+    async fn set_metadata(&self, object: &str, entries: &[(&str, String)]) -> anyhow::Result<()> {
+        self.inner.set_metadata(&self.full(object), entries).await
+    }
+
+    async fn metadata(&self, object: &str) -> anyhow::Result<HashMap<String, String>> {
+        self.inner.metadata(&self.full(object)).await
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectInfo>> {
+        let strip = format!("{}/", self.prefix);
+        let mut objects = self.inner.list(&self.full(prefix)).await?;
+        for object in &mut objects {
+            if let Some(name) = object.name.strip_prefix(&strip) {
+                object.name = name.to_string();
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn download(&self, object: &str) -> anyhow::Result<Vec<u8>> {
+        self.inner.download(&self.full(object)).await
+    }
+
+    async fn download_revision(&self, object: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        self.inner.download_revision(&self.full(object)).await
+    }
+
+    async fn upload_if(
+        &self,
+        object: &str,
+        data: Vec<u8>,
+        content_type: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .upload_if(&self.full(object), data, content_type, revision)
+            .await
+    }
+
+    async fn exists(&self, object: &str) -> anyhow::Result<bool> {
+        self.inner.exists(&self.full(object)).await
+    }
+
+    async fn stat(&self, object: &str) -> anyhow::Result<Option<ObjectInfo>> {
+        let mut stat = self.inner.stat(&self.full(object)).await?;
+        if let Some(stat) = &mut stat {
+            stat.name = object.to_string();
+        }
+        Ok(stat)
+    }
+
+    async fn delete(&self, object: &str) -> anyhow::Result<()> {
+        self.inner.delete(&self.full(object)).await
+    }
+
+    async fn copy(&self, source: &str, dest: &str) -> anyhow::Result<()> {
+        self.inner.copy(&self.full(source), &self.full(dest)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn joins_tolerate_missing_and_doubled_slashes() {
+        for prefix in ["staging", "staging/", "/staging/", "staging//"] {
+            for object in ["images/a.jpg", "/images/a.jpg", "//images/a.jpg"] {
+                assert_eq!(join_path(prefix, object), "staging/images/a.jpg");
+            }
+        }
+    }
+
+    #[test]
+    fn an_empty_prefix_leaves_names_alone() {
+        for prefix in ["", "/", "//"] {
+            assert_eq!(join_path(prefix, "images/a.jpg"), "images/a.jpg");
+            assert_eq!(join_path(prefix, "/images/a.jpg"), "images/a.jpg");
+        }
+    }
+
+    #[tokio::test]
+    async fn callers_only_ever_see_prefix_free_names() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = PrefixedStorage::wrap(inner.clone(), "staging/");
+        storage
+            .upload("uploads/a.jpg", b"jpeg".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        storage
+            .copy("uploads/a.jpg", "images/food1.jpg")
+            .await
+            .unwrap();
+
+        let mut names = inner.names();
+        names.sort();
+        assert_eq!(names, ["staging/images/food1.jpg", "staging/uploads/a.jpg"]);
+        let listed: Vec<_> = storage
+            .list("uploads/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| object.name)
+            .collect();
+        assert_eq!(listed, ["uploads/a.jpg"]);
+        let stat = storage.stat("images/food1.jpg").await.unwrap().unwrap();
+        assert_eq!(stat.name, "images/food1.jpg");
+        assert_eq!(storage.download("images/food1.jpg").await.unwrap(), b"jpeg");
+    }
+
+    #[tokio::test]
+    async fn an_empty_prefix_does_not_wrap() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = PrefixedStorage::wrap(inner.clone(), "/");
+        storage
+            .upload("images/a.jpg", b"jpeg".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        assert_eq!(inner.names(), ["images/a.jpg"]);
+    }
+}
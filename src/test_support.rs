@@ -0,0 +1,451 @@
+//! Fakes shared by the tests: a LINE API server that records what it is
+//! sent, and an AppState wired to it and to in-memory storage.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::{
+    AppState, Channel, ChannelConfig, FallbackMode, HmacSha256, PresenceCache, ProfileCache,
+    QueueOverflow, QueuedEvent, SeenEvents,
+    admins::AdminStore,
+    backups::PresetBackups,
+    conversations::Conversations,
+    health::ReadinessCache,
+    image_sets::ImageSets,
+    line::{LineClient, RetryPolicy},
+    metrics::Metrics,
+    notify::ErrorNotifier,
+    presets::PresetStore,
+    stats::PresetStats,
+    storage::{MemoryStorage, Storage},
+    versions::PresetVersions,
+};
+
+pub const ADMIN: &str = "Uadmin000000000000000000000000000";
+pub const USER: &str = "Uuser0000000000000000000000000000";
+pub const SECRET: &str = "test-channel-secret";
+
+/// A request the mock LINE server received.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Recorded {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// A canned response for one path.
+pub struct Scripted {
+    status: StatusCode,
+    body: Bytes,
+    delay: Duration,
+    /// Sends the body in chunks with no Content-Length.
+    chunked: bool,
+}
+
+impl Scripted {
+    pub fn new(status: u16, body: impl Into<Bytes>) -> Self {
+        Self {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.into(),
+            delay: Duration::ZERO,
+            chunked: false,
+        }
+    }
+
+    pub fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[derive(Clone, Default)]
+struct Shared {
+    requests: Arc<Mutex<Vec<Recorded>>>,
+    scripts: Arc<Mutex<Vec<(String, Scripted)>>>,
+}
+
+/// Stands in for both api.line.me and api-data.line.me. Anything not
+/// scripted gets `200 {}`.
+#[derive(Clone)]
+pub struct MockLine {
+    pub base: String,
+    shared: Shared,
+}
+
+impl MockLine {
+    pub async fn start() -> Self {
+        let shared = Shared::default();
+        let app = Router::new().fallback(record).with_state(shared.clone());
+        let base = serve(app).await;
+        Self { base, shared }
+    }
+
+    /// Answers the next request to `path` with `response`.
+    pub fn respond(&self, path: &str, response: Scripted) {
+        self.shared
+            .scripts
+            .lock()
+            .unwrap()
+            .push((path.to_string(), response));
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.shared.requests.lock().unwrap().clone()
+    }
+
+    /// Requests made to `path`, in order.
+    pub fn to(&self, path: &str) -> Vec<Recorded> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == path)
+            .collect()
+    }
+
+    /// A client for this server, sending `token` as the channel's.
+    pub fn client(&self, token: &str, metrics: Arc<Metrics>) -> LineClient {
+        LineClient::new(
+            reqwest::Client::new(),
+            token.to_string(),
+            Duration::from_secs(5),
+            metrics,
+        )
+        .with_base_urls(&self.base, &self.base)
+        .with_retry_policy(RetryPolicy::immediate())
+    }
+}
+
+async fn record(
+    State(shared): State<Shared>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path = uri.path().to_string();
+    shared.requests.lock().unwrap().push(Recorded {
+        path: path.clone(),
+        headers,
+        body,
+    });
+    let scripted = {
+        let mut scripts = shared.scripts.lock().unwrap();
+        scripts
+            .iter()
+            .position(|(p, _)| *p == path)
+            .map(|i| scripts.remove(i).1)
+    };
+    let Some(scripted) = scripted else {
+        return (StatusCode::OK, [("content-type", "application/json")], "{}").into_response();
+    };
+    tokio::time::sleep(scripted.delay).await;
+    if scripted.chunked {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = scripted
+            .body
+            .chunks(16)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        return (scripted.status, body).into_response();
+    }
+    (scripted.status, scripted.body).into_response()
+}
+
+/// Serves `app` on a free local port and returns its base URL.
+pub async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    format!("http://{}", addr)
+}
+
+/// Polls `done` until it holds, failing the test after a few seconds.
+pub async fn wait_for(done: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// An AppState over in-memory storage and a mock LINE server, configured
+/// like a fresh deployment with one admin. Tests adjust fields as needed.
+pub struct TestApp {
+    pub state: AppState,
+    pub storage: Arc<MemoryStorage>,
+    pub line: MockLine,
+    pub events: mpsc::Receiver<QueuedEvent>,
+}
+
+impl TestApp {
+    pub async fn new() -> Self {
+        let storage = Arc::new(MemoryStorage::default());
+        let dyn_storage: Arc<dyn Storage> = storage.clone();
+        let line = MockLine::start().await;
+        let config = channel_config("default", SECRET);
+        let metrics = Arc::new(Metrics::default());
+        let client = line.client(&config.token, metrics.clone());
+        let channel = Channel::new(config, client);
+        let admins = Arc::new(
+            AdminStore::load(dyn_storage.clone(), vec![ADMIN.to_string()])
+                .await
+                .unwrap(),
+        );
+        let (event_tx, events) = mpsc::channel(16);
+        let state = AppState {
+            channels: Arc::new(vec![Arc::new(channel)]),
+            storage: dyn_storage.clone(),
+            versions: Arc::new(PresetVersions::new(dyn_storage.clone(), 5)),
+            backups: Arc::new(PresetBackups::new(dyn_storage.clone(), 3)),
+            admins: admins.clone(),
+            admin_group_ids: Vec::new(),
+            announce_user_ids: Vec::new(),
+            presets: Arc::new(PresetStore::load(dyn_storage.clone()).await.unwrap()),
+            fallback_text: crate::DEFAULT_FALLBACK_TEXT.to_string(),
+            fallback_mode: FallbackMode::Menu,
+            group_fallback_mode: FallbackMode::Silent,
+            fuzzy_match_threshold: crate::DEFAULT_FUZZY_MATCH_THRESHOLD,
+            preset_timezone: chrono_tz::Asia::Tokyo,
+            clock: chrono::Utc::now,
+            greeting_text: crate::DEFAULT_GREETING_TEXT.to_string(),
+            menu_list_command: "メニュー一覧".to_string(),
+            sender: None,
+            venues: Vec::new(),
+            event_tx,
+            queue_overflow: QueueOverflow::Reject,
+            seen_events: Arc::new(SeenEvents::new(Duration::from_secs(600))),
+            profiles: Arc::new(ProfileCache::new(Duration::from_secs(3600))),
+            presence: Arc::new(PresenceCache::new(Duration::from_secs(60))),
+            missing_image_text: crate::DEFAULT_MISSING_IMAGE_TEXT.to_string(),
+            loading_seconds: None,
+            max_upload_bytes: 10 * 1024 * 1024,
+            rate_limiter: None,
+            upload_permits: Arc::new(Semaphore::new(2)),
+            upload_limiter: None,
+            event_max_age: Some(Duration::from_secs(300)),
+            require_json_content_type: true,
+            admin_silent_replies: true,
+            strip_image_metadata: true,
+            jpeg_quality: 90,
+            pending_ttl: Duration::from_secs(24 * 3600),
+            conversations: Arc::new(
+                Conversations::load(dyn_storage.clone(), Duration::from_secs(24 * 3600)).await,
+            ),
+            require_same_admin: true,
+            image_sets: Arc::new(ImageSets::default()),
+            image_set_timeout: Duration::from_secs(60),
+            group_replies: true,
+            group_mention_only: false,
+            metrics,
+            stats: Arc::new(PresetStats::new(
+                dyn_storage.clone(),
+                chrono_tz::Asia::Tokyo,
+            )),
+            started_at: Instant::now(),
+            storage_backend: "memory",
+            notifier: Arc::new(ErrorNotifier::from_env(admins)),
+            readiness: Arc::new(ReadinessCache::default()),
+        };
+        Self {
+            state,
+            storage,
+            line,
+            events,
+        }
+    }
+
+    /// Replaces the channels, all of them talking to the mock server.
+    pub fn set_channels(&mut self, configs: Vec<ChannelConfig>) {
+        let channels = configs
+            .into_iter()
+            .map(|config| {
+                let client = self.line.client(&config.token, self.state.metrics.clone());
+                Arc::new(Channel::new(config, client))
+            })
+            .collect();
+        self.state.channels = Arc::new(channels);
+    }
+
+    pub fn channel(&self) -> Arc<Channel> {
+        self.state.channels[0].clone()
+    }
+
+    /// Runs one event through handle_event on the first channel.
+    pub async fn handle(&self, event: Value) -> anyhow::Result<()> {
+        let event = serde_json::from_value(event).expect("valid event");
+        crate::handle_event(&self.state, &self.channel(), event).await
+    }
+
+    /// `event` as the webhook handler would queue it.
+    pub fn queued(&self, event: Value) -> QueuedEvent {
+        QueuedEvent {
+            channel: self.channel(),
+            request_id: "test-request".to_string(),
+            event: serde_json::from_value(event).expect("valid event"),
+        }
+    }
+}
+
+pub fn channel_config(name: &str, secret: &str) -> ChannelConfig {
+    ChannelConfig {
+        name: name.to_string(),
+        secret: secret.to_string(),
+        secondary_secret: None,
+        token: format!("{}-access-token", name),
+        bucket_prefix: String::new(),
+        bot_user_id: None,
+    }
+}
+
+/// The x-line-signature LINE would send for `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    use base64::Engine as _;
+    use hmac::Mac as _;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+pub fn user_source(user_id: &str) -> Value {
+    serde_json::json!({ "type": "user", "userId": user_id })
+}
+
+pub fn group_source(group_id: &str, user_id: &str) -> Value {
+    serde_json::json!({ "type": "group", "groupId": group_id, "userId": user_id })
+}
+
+pub fn text_event(source: Value, text: &str) -> Value {
+    serde_json::json!({
+        "type": "message",
+        "replyToken": "reply-token",
+        "source": source,
+        "message": { "id": "1", "type": "text", "text": text },
+    })
+}
+
+pub fn postback_event(source: Value, data: &str) -> Value {
+    serde_json::json!({
+        "type": "postback",
+        "replyToken": "reply-token",
+        "source": source,
+        "postback": { "data": data },
+    })
+}
+
+pub fn media_event(source: Value, kind: &str, message_id: &str) -> Value {
+    serde_json::json!({
+        "type": "message",
+        "replyToken": "reply-token",
+        "source": source,
+        "message": { "id": message_id, "type": kind },
+    })
+}
+
+pub fn follow_event(source: Value) -> Value {
+    serde_json::json!({
+        "type": "follow",
+        "replyToken": "reply-token",
+        "source": source,
+    })
+}
+
+/// A 2x2 RGB PNG.
+pub const PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x02, 0x00, 0x00, 0x00, 0xfd, 0xd4, 0x9a,
+    0x73, 0x00, 0x00, 0x00, 0x10, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0xf8, 0xcf, 0xc0, 0x00,
+    0x44, 0x0c, 0x10, 0x0a, 0x00, 0x1f, 0xee, 0x03, 0xfd, 0x8b, 0x5f, 0x14, 0xd4, 0x00, 0x00, 0x00,
+    0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+];
+
+/// A `width` x `height` gradient, so encoders have something to compress.
+fn gradient(width: u32, height: u32) -> image::RgbImage {
+    image::RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    })
+}
+
+/// A `width` x `height` baseline JPEG.
+pub fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90)
+        .encode_image(&gradient(width, height))
+        .unwrap();
+    jpeg
+}
+
+/// A `width` x `height` RGB PNG.
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = Vec::new();
+    gradient(width, height)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+/// A 4x2 JPEG whose EXIF, which also carries a GPS position, asks for it
+/// to be shown turned 90° clockwise (orientation 6).
+pub fn sideways_jpeg() -> Vec<u8> {
+    let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+    tiff.extend_from_slice(&2u16.to_le_bytes());
+    // Orientation, SHORT
+    tiff.extend_from_slice(&[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    tiff.extend_from_slice(&6u32.to_le_bytes());
+    // GPS IFD pointer, LONG
+    tiff.extend_from_slice(&[0x25, 0x88, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    tiff.extend_from_slice(&38u32.to_le_bytes());
+    tiff.extend_from_slice(&[0, 0, 0, 0]);
+    tiff.extend_from_slice(b"GPS 35.6812N 139.7671E");
+    let plain = jpeg(4, 2);
+    let mut jpeg = plain[..2].to_vec();
+    jpeg.extend_from_slice(&[0xFF, 0xE1]);
+    jpeg.extend_from_slice(&(2 + 6 + tiff.len() as u16).to_be_bytes());
+    jpeg.extend_from_slice(b"Exif\0\0");
+    jpeg.extend_from_slice(&tiff);
+    jpeg.extend_from_slice(&plain[2..]);
+    jpeg
+}
+
+/// The markers of a JPEG's header segments, up to the start of scan.
+pub fn jpeg_segments(jpeg: &[u8]) -> Vec<u8> {
+    let mut markers = Vec::new();
+    let mut at = 2;
+    while jpeg[at + 1] != 0xDA {
+        markers.push(jpeg[at + 1]);
+        at += 2 + usize::from(u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]));
+    }
+    markers
+}
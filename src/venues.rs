@@ -0,0 +1,97 @@
+use std::env;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Mean Earth radius used for great-circle distances.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// A shop location as written in VENUES.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Venue {
+    pub name: String,
+    pub lat: f64,
+    pub lng: f64,
+    /// Name or key of the preset sent along with the venue.
+    pub preset: String,
+}
+
+/// Reads VENUES, a JSON array of venues. Unset means no venues.
+pub fn load_venues() -> anyhow::Result<Vec<Venue>> {
+    match env::var("VENUES") {
+        Ok(json) if !json.trim().is_empty() => {
+            serde_json::from_str(&json).context("VENUES must be a JSON array of venues")
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The venue closest to the given point and its distance in kilometres.
+pub fn nearest(venues: &[Venue], lat: f64, lng: f64) -> Option<(&Venue, f64)> {
+    venues
+        .iter()
+        .map(|venue| (venue, haversine_km(lat, lng, venue.lat, venue.lng)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+/// Great-circle distance between two points given in degrees.
+fn haversine_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn venue(name: &str, lat: f64, lng: f64) -> Venue {
+        Venue {
+            name: name.to_string(),
+            lat,
+            lng,
+            preset: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn nothing_is_nearest_without_venues() {
+        assert!(nearest(&[], 35.0, 139.0).is_none());
+    }
+
+    #[test]
+    fn picks_the_closest_venue() {
+        let venues = [
+            venue("shibuya", 35.6580, 139.7016),
+            venue("shinjuku", 35.6896, 139.7006),
+            venue("osaka", 34.7025, 135.4959),
+        ];
+        // Harajuku, between Shibuya and Shinjuku but closer to Shibuya.
+        let (venue, km) = nearest(&venues, 35.6702, 139.7027).unwrap();
+        assert_eq!(venue.name, "shibuya");
+        assert!((1.2..1.5).contains(&km), "{}", km);
+
+        let (venue, km) = nearest(&venues, 34.6937, 135.5023).unwrap();
+        assert_eq!(venue.name, "osaka");
+        assert!(km < 2.0, "{}", km);
+    }
+
+    #[test]
+    fn measures_across_the_antimeridian() {
+        let venues = [venue("fiji", -17.7, 178.0), venue("east", -17.7, -170.0)];
+        // Just east of the antimeridian, a few degrees from Fiji.
+        let (venue, km) = nearest(&venues, -17.7, -179.5).unwrap();
+        assert_eq!(venue.name, "fiji");
+        assert!((250.0..300.0).contains(&km), "{}", km);
+    }
+
+    #[test]
+    fn distance_is_symmetric_and_zero_at_the_same_point() {
+        assert_eq!(haversine_km(35.0, 139.0, 35.0, 139.0), 0.0);
+        let there = haversine_km(35.0, 139.0, 34.0, 135.0);
+        let back = haversine_km(34.0, 135.0, 35.0, 139.0);
+        assert!((there - back).abs() < 1e-9);
+    }
+}
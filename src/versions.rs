@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    media,
+    storage::{self, Storage},
+};
+
+/// Where the current version of every preset object is recorded.
+pub const STATE_OBJECT: &str = "presets/versions.json";
+
+const MAX_EDIT_ATTEMPTS: u32 = 3;
+
+/// Tracks which versioned copy of each preset object is current. Every
+/// update is written to a fresh object name, so LINE's and browsers'
+/// caches can't keep serving the old bytes.
+pub struct PresetVersions {
+    storage: Arc<dyn Storage>,
+    /// How many versions of each object to keep around for rollback.
+    keep: usize,
+    current: RwLock<Recorded>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// Preset object path -> current versioned object path.
+    versions: HashMap<String, String>,
+    /// Counts this instance's writes, so a refresh that raced one can tell
+    /// its download is older and leave the written state alone.
+    edits: u64,
+}
+
+impl PresetVersions {
+    pub fn new(storage: Arc<dyn Storage>, keep: usize) -> Self {
+        Self {
+            storage,
+            keep: keep.max(1),
+            current: RwLock::new(Recorded::default()),
+        }
+    }
+
+    /// Reloads the recorded versions, picking up updates made by other
+    /// instances. A missing state object means nothing has been versioned.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let edits = self.current.read().unwrap().edits;
+        let Some((versions, _)) = self.read().await? else {
+            return Ok(());
+        };
+        let mut current = self.current.write().unwrap();
+        if current.edits == edits {
+            current.versions = versions;
+        }
+        Ok(())
+    }
+
+    /// The object currently holding `object`'s content.
+    pub fn resolve(&self, object: &str) -> String {
+        self.current
+            .read()
+            .unwrap()
+            .versions
+            .get(object)
+            .cloned()
+            .unwrap_or_else(|| object.to_string())
+    }
+
+    /// Copies `source` into a new version of `object` and makes it current.
+    /// Returns the new version's path.
+    pub async fn bind(&self, object: &str, source: &str) -> anyhow::Result<String> {
+        let version = versioned_path(object, source, SystemTime::now());
+        self.storage.copy(source, &version).await?;
+        self.edit(|versions| {
+            versions.insert(object.to_string(), version.clone());
+        })
+        .await?;
+        info!(object, version = %version, "preset object updated");
+
+        if let Err(e) = self.collect_garbage(object, &version).await {
+            warn!(object, "failed to delete old versions: {:#}", e);
+        }
+        Ok(version)
+    }
+
+    /// The recorded versions and their revision, or None before anything
+    /// has been versioned.
+    async fn read(&self) -> anyhow::Result<Option<(HashMap<String, String>, String)>> {
+        let Some((state, revision)) = self.storage.download_revision(STATE_OBJECT).await? else {
+            return Ok(None);
+        };
+        Ok(Some((serde_json::from_slice(&state)?, revision)))
+    }
+
+    /// Applies `change` to the recorded versions as stored, so entries
+    /// other instances wrote since our last refresh are kept. The write
+    /// only goes through if the state object is still the one read, and
+    /// is retried otherwise.
+    async fn edit<T>(
+        &self,
+        change: impl Fn(&mut HashMap<String, String>) -> T,
+    ) -> anyhow::Result<T> {
+        let mut edited = None;
+        storage::update_conditionally(
+            &*self.storage,
+            STATE_OBJECT,
+            "application/json",
+            MAX_EDIT_ATTEMPTS,
+            |data| {
+                let mut versions: HashMap<String, String> = match data {
+                    Some(data) => serde_json::from_slice(&data)?,
+                    None => HashMap::new(),
+                };
+                let before = versions.clone();
+                let result = change(&mut versions);
+                let data = if versions == before {
+                    None
+                } else {
+                    Some(serde_json::to_vec(&versions)?)
+                };
+                edited = Some((result, versions));
+                Ok(data)
+            },
+        )
+        .await?;
+        let (result, versions) = edited.expect("an edit that was written ran its change");
+        self.adopt(versions);
+        Ok(result)
+    }
+
+    fn adopt(&self, versions: HashMap<String, String>) {
+        let mut current = self.current.write().unwrap();
+        current.versions = versions;
+        current.edits += 1;
+    }
+
+    /// Deletes all but the newest `keep` versions of `object`. A version
+    /// that fails to delete is left for the next update to retry.
+    async fn collect_garbage(&self, object: &str, current: &str) -> anyhow::Result<()> {
+        let (previews, mut versions): (Vec<_>, Vec<_>) = self
+            .storage
+            .list(&versions_prefix(object))
+            .await?
+            .into_iter()
+            .partition(|listed| media::is_preview(&listed.name));
+        // Version names are millisecond timestamps of equal width, so name
+        // order is age order.
+        versions.sort_by(|a, b| b.name.cmp(&a.name));
+        for old in versions.iter().skip(self.keep) {
+            if old.name == current {
+                continue;
+            }
+            if let Err(e) = self.storage.delete(&old.name).await {
+                warn!(version = %old.name, "failed to delete old version: {:#}", e);
+                continue;
+            }
+            // A version's preview goes with it
+            let preview = media::preview_object(&old.name);
+            if previews.iter().any(|listed| listed.name == preview)
+                && let Err(e) = self.storage.delete(&preview).await
+            {
+                warn!(preview = %preview, "failed to delete old preview: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `images/menu1.jpg` -> `images/menu1/`
+fn versions_prefix(object: &str) -> String {
+    let stem = object.rsplit_once('.').map_or(object, |(stem, _)| stem);
+    format!("{}/", stem)
+}
+
+/// `images/menu1.jpg` + `uploads/x.png` -> `images/menu1/1700000000000.png`,
+/// keeping the source's extension since that's the format it really is.
+fn versioned_path(object: &str, source: &str, now: SystemTime) -> String {
+    let millis = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let extension = source
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.contains('/'))
+        .unwrap_or("jpg");
+    format!("{}{:013}.{}", versions_prefix(object), millis, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::{MemoryStorage, PreconditionFailed};
+
+    const OBJECT: &str = "images/food1.jpg";
+
+    fn storage() -> Arc<MemoryStorage> {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.put("uploads/new.jpg", b"new".to_vec());
+        storage
+    }
+
+    /// Binds `uploads/new.jpg` to `object`, a little later than the last
+    /// bind so version names differ.
+    async fn bind(versions: &PresetVersions, object: &str) -> String {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        versions.bind(object, "uploads/new.jpg").await.unwrap()
+    }
+
+    fn stored(storage: &MemoryStorage) -> HashMap<String, String> {
+        serde_json::from_slice(&storage.get(STATE_OBJECT).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn versions_are_named_after_the_time_and_the_source_format() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        assert_eq!(
+            versioned_path("images/menu1.jpg", "uploads/x.png", at),
+            "images/menu1/1700000000000.png"
+        );
+        assert_eq!(
+            versioned_path("images/menu1.jpg", "uploads.d/x", at),
+            "images/menu1/1700000000000.jpg"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_bind_makes_the_new_version_current_everywhere() {
+        let storage = storage();
+        let versions = PresetVersions::new(storage.clone(), 5);
+        assert_eq!(versions.resolve(OBJECT), OBJECT);
+
+        let version = bind(&versions, OBJECT).await;
+        assert!(version.starts_with("images/food1/"));
+        assert_eq!(versions.resolve(OBJECT), version);
+        assert_eq!(storage.get(&version).unwrap(), b"new");
+
+        let other = PresetVersions::new(storage.clone(), 5);
+        assert_eq!(other.resolve(OBJECT), OBJECT);
+        other.refresh().await.unwrap();
+        assert_eq!(other.resolve(OBJECT), version);
+    }
+
+    #[tokio::test]
+    async fn binds_on_two_instances_keep_each_others_versions() {
+        let storage = storage();
+        let one = PresetVersions::new(storage.clone(), 5);
+        let two = PresetVersions::new(storage.clone(), 5);
+        let food = bind(&one, OBJECT).await;
+        let drink = bind(&two, "images/drink1.jpg").await;
+
+        assert_eq!(two.resolve(OBJECT), food);
+        one.refresh().await.unwrap();
+        assert_eq!(one.resolve("images/drink1.jpg"), drink);
+        assert_eq!(stored(&storage).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_conflicting_write_is_retried() {
+        let storage = storage();
+        let versions = PresetVersions::new(storage.clone(), 5);
+        storage.fail_next(
+            "upload_if",
+            STATE_OBJECT,
+            PreconditionFailed {
+                object: STATE_OBJECT.to_string(),
+            },
+        );
+        let version = bind(&versions, OBJECT).await;
+        assert_eq!(stored(&storage)[OBJECT], version);
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_versions_are_kept() {
+        let storage = storage();
+        let versions = PresetVersions::new(storage.clone(), 2);
+        let mut bound = Vec::new();
+        for _ in 0..4 {
+            bound.push(bind(&versions, OBJECT).await);
+        }
+        let kept: Vec<_> = storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("images/food1/"))
+            .collect();
+        assert_eq!(kept, bound[2..]);
+        assert_eq!(versions.resolve(OBJECT), bound[3]);
+    }
+
+    #[tokio::test]
+    async fn previews_are_deleted_with_their_version_and_not_counted() {
+        let storage = storage();
+        let versions = PresetVersions::new(storage.clone(), 1);
+        let old = bind(&versions, OBJECT).await;
+        storage.put(&media::preview_object(&old), b"preview".to_vec());
+        let current = bind(&versions, OBJECT).await;
+        storage.put(&media::preview_object(&current), b"preview".to_vec());
+        let newest = bind(&versions, OBJECT).await;
+
+        let kept: Vec<_> = storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("images/food1/"))
+            .collect();
+        assert_eq!(kept, [newest]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_delete_does_not_stop_garbage_collection() {
+        let storage = storage();
+        for old in ["0000000000001", "0000000000002", "0000000000003"] {
+            storage.put(&format!("images/food1/{}.jpg", old), b"old".to_vec());
+        }
+        storage.fail_next(
+            "delete",
+            "images/food1/0000000000002.jpg",
+            anyhow::anyhow!("503"),
+        );
+        let versions = PresetVersions::new(storage.clone(), 1);
+        let version = bind(&versions, OBJECT).await;
+
+        let kept: Vec<_> = storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("images/food1/"))
+            .collect();
+        assert_eq!(
+            kept,
+            ["images/food1/0000000000002.jpg".to_string(), version]
+        );
+    }
+}